@@ -0,0 +1,22 @@
+use std::path::{Path, PathBuf};
+
+// Embeds nes20db.xml in the game database when NES20DB points at a copy, or
+// when one sits at data/nes20db.xml. Without it only src/game_db.txt is built
+// in and headers are fixed for the few dumps listed there.
+fn main() {
+    println!("cargo:rerun-if-env-changed=NES20DB");
+    println!("cargo:rerun-if-changed=data/nes20db.xml");
+
+    let source = std::env::var_os("NES20DB")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from("data/nes20db.xml")).filter(|path| path.exists()));
+    let out = Path::new(&std::env::var_os("OUT_DIR").unwrap()).join("nes20db.xml");
+
+    match source {
+        Some(source) => {
+            println!("cargo:rerun-if-changed={}", source.display());
+            std::fs::copy(&source, &out).unwrap_or_else(|e| panic!("{}: {}", source.display(), e));
+        }
+        None => std::fs::write(&out, "").unwrap(),
+    }
+}
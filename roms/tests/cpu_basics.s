@@ -0,0 +1,129 @@
+; A small self-checking CPU test using the $6000 status protocol (see
+; src/testrom.rs). Each check that fails stores its number as the result.
+; cpu_basics.nes is this assembled as NROM-128 (16K PRG, 8K CHR) with the
+; code at $C000 and the vectors at $FFFA.
+;
+; It was written for this emulator, so it checks the harness more than the
+; CPU. It isn't a substitute for blargg's or kevtris's ROMs: any of those
+; that use the $6000 protocol (instr_test-v5, cpu_interrupts_v2, ...) can be
+; dropped in this directory and cargo test runs them too.
+
+.segment "CODE"
+
+reset:
+        SEI
+        CLD
+        LDX #$FF
+        TXS
+        LDA #$80                ; running
+        STA $6000
+        LDA #$DE
+        STA $6001
+        LDA #$B0
+        STA $6002
+        LDA #$61
+        STA $6003
+
+; 1: ADC sets overflow and negative, clears carry
+        LDA #1
+        STA $00
+        CLC
+        LDA #$7F
+        ADC #$01
+        BVC fail
+        BPL fail
+        BCS fail
+        CMP #$80
+        BNE fail
+
+; 2: SBC borrows
+        LDA #2
+        STA $00
+        SEC
+        LDA #$00
+        SBC #$01
+        BCS fail
+        CMP #$FF
+        BNE fail
+
+; 3: PHA and PLA
+        LDA #3
+        STA $00
+        LDA #$42
+        PHA
+        LDA #$00
+        PLA
+        CMP #$42
+        BNE fail
+
+; 4: JSR and RTS
+        LDA #4
+        STA $00
+        LDX #$00
+        JSR load_x
+        CPX #$33
+        BNE fail
+
+; 5: STA ($zp),Y
+        LDA #5
+        STA $00
+        LDA #$00
+        STA $10
+        LDA #$02
+        STA $11
+        LDY #$05
+        LDA #$99
+        STA ($10),Y
+        LDA #$00
+        LDA $0205
+        CMP #$99
+        BNE fail
+
+; 6: ROL through carry
+        LDA #6
+        STA $00
+        SEC
+        LDA #$80
+        ROL A
+        BCC fail
+        CMP #$01
+        BNE fail
+
+        LDX #$00
+pass_message:
+        LDA passed,X
+        STA $6004,X
+        INX
+        CMP #$00
+        BNE pass_message
+        LDA #$00
+        STA $6000
+done:
+        JMP done
+
+fail:
+        LDX #$00
+fail_message:
+        LDA failed,X
+        STA $6004,X
+        INX
+        CMP #$00
+        BNE fail_message
+        LDA $00
+        STA $6000
+        JMP done
+
+load_x:
+        LDX #$33
+        RTS
+
+nmi:
+        RTI
+
+passed:
+        .byte "Passed", $0A, $00
+failed:
+        .byte "Failed", $0A, $00
+
+.segment "VECTORS"
+        .word nmi, reset, nmi
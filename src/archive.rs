@@ -0,0 +1,375 @@
+use crate::cartridge::MAX_ROM_SIZE;
+use crate::game_db::crc32;
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+use std::fmt;
+
+// ROMs inside .zip and .gz files. Anything else is passed through untouched,
+// so the archive is picked by its signature, not its extension.
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Compression methods
+const STORED: u16 = 0;
+const DEFLATE: u16 = 8;
+
+// Entries looked at in a zip, the rest (readmes, box art) are skipped
+const ROM_EXTENSIONS: [&str; 4] = ["nes", "unf", "unif", "fds"];
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveError {
+    Corrupt(String),
+    Unsupported(String),
+    NoRom,
+    NotFound(String),
+    // Unpacks to more than MAX_ROM_SIZE
+    TooLarge(String),
+    // Several ROMs and none was asked for
+    Ambiguous(Vec<String>),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Corrupt(err) => write!(f, "Corrupt archive: {}", err),
+            ArchiveError::Unsupported(err) => write!(f, "Unsupported archive: {}", err),
+            ArchiveError::NoRom => write!(f, "No ROM in the archive"),
+            ArchiveError::NotFound(name) => write!(f, "No ROM named {} in the archive", name),
+            ArchiveError::TooLarge(name) => write!(
+                f,
+                "{} unpacks to more than {} MiB",
+                name,
+                MAX_ROM_SIZE / 1024 / 1024
+            ),
+            ArchiveError::Ambiguous(names) => write!(
+                f,
+                "The archive holds several ROMs, pick one with --entry:\n  {}",
+                names.join("\n  ")
+            ),
+        }
+    }
+}
+
+fn corrupt(what: &str) -> ArchiveError {
+    ArchiveError::Corrupt(what.to_string())
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, ArchiveError> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| corrupt("truncated"))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, ArchiveError> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| corrupt("truncated"))
+}
+
+fn slice(data: &[u8], at: usize, len: usize) -> Result<&[u8], ArchiveError> {
+    data.get(at..at + len).ok_or_else(|| corrupt("truncated"))
+}
+
+fn is_zip(data: &[u8]) -> bool {
+    u32_at(data, 0) == Ok(ZIP_LOCAL_HEADER) || u32_at(data, 0) == Ok(ZIP_END_OF_DIRECTORY)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ROM_EXTENSIONS
+        .iter()
+        .any(|ext| name.ends_with(&format!(".{}", ext)))
+}
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    size: usize,
+    offset: usize,
+}
+
+// Entries from the zip's central directory
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, ArchiveError> {
+    // The end record is last, followed by a comment of up to 64K
+    let min_start = data.len().saturating_sub(22 + 0xffff);
+    let end = (min_start..=data.len().saturating_sub(22))
+        .rev()
+        .find(|at| u32_at(data, *at) == Ok(ZIP_END_OF_DIRECTORY))
+        .ok_or_else(|| corrupt("no end of central directory"))?;
+    let count = u16_at(data, end + 10)? as usize;
+    let mut at = u32_at(data, end + 16)? as usize;
+
+    let mut entries = vec![];
+    for _ in 0..count {
+        if u32_at(data, at)? != ZIP_CENTRAL_HEADER {
+            return Err(corrupt("bad central directory"));
+        }
+        let name_len = u16_at(data, at + 28)? as usize;
+        let extra_len = u16_at(data, at + 30)? as usize;
+        let comment_len = u16_at(data, at + 32)? as usize;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(slice(data, at + 46, name_len)?).into_owned(),
+            method: u16_at(data, at + 10)?,
+            crc32: u32_at(data, at + 16)?,
+            compressed_size: u32_at(data, at + 20)? as usize,
+            size: u32_at(data, at + 24)? as usize,
+            offset: u32_at(data, at + 42)? as usize,
+        });
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+// Stops at MAX_ROM_SIZE, so a small archive can't expand without bound
+fn inflate(data: &[u8], name: &str) -> Result<Vec<u8>, ArchiveError> {
+    decompress_to_vec_with_limit(data, MAX_ROM_SIZE).map_err(|err| match err.status {
+        TINFLStatus::HasMoreOutput => ArchiveError::TooLarge(name.to_string()),
+        status => ArchiveError::Corrupt(format!("{:?}", status)),
+    })
+}
+
+fn zip_extract(data: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, ArchiveError> {
+    if u32_at(data, entry.offset)? != ZIP_LOCAL_HEADER {
+        return Err(corrupt("bad local header"));
+    }
+    if entry.size > MAX_ROM_SIZE {
+        return Err(ArchiveError::TooLarge(entry.name.clone()));
+    }
+    let name_len = u16_at(data, entry.offset + 26)? as usize;
+    let extra_len = u16_at(data, entry.offset + 28)? as usize;
+    let compressed = slice(
+        data,
+        entry.offset + 30 + name_len + extra_len,
+        entry.compressed_size,
+    )?;
+    let out = match entry.method {
+        STORED => compressed.to_vec(),
+        DEFLATE => inflate(compressed, &entry.name)?,
+        method => {
+            return Err(ArchiveError::Unsupported(format!(
+                "{} uses compression method {}",
+                entry.name, method
+            )))
+        }
+    };
+    if out.len() != entry.size || crc32(&out) != entry.crc32 {
+        return Err(ArchiveError::Corrupt(format!(
+            "{} fails its CRC",
+            entry.name
+        )));
+    }
+    Ok(out)
+}
+
+// The ROM inside `data` if it's an archive, `data` itself otherwise. `entry`
+// picks a ROM by name (with or without its folder) when there are several.
+pub fn unpack(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        return gzip_extract(&data);
+    }
+    if !is_zip(&data) {
+        return Ok(data);
+    }
+    let roms: Vec<ZipEntry> = zip_entries(&data)?
+        .into_iter()
+        .filter(|entry| is_rom_name(&entry.name))
+        .collect();
+    let rom = match entry {
+        Some(name) => roms
+            .iter()
+            .find(|entry| entry.name == name || entry.name.rsplit('/').next() == Some(name))
+            .ok_or_else(|| ArchiveError::NotFound(name.to_string()))?,
+        None => match roms.as_slice() {
+            [] => return Err(ArchiveError::NoRom),
+            [rom] => rom,
+            _ => {
+                return Err(ArchiveError::Ambiguous(
+                    roms.iter().map(|entry| entry.name.clone()).collect(),
+                ))
+            }
+        },
+    };
+    zip_extract(&data, rom)
+}
+
+// gzip header flags
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+// Where the compressed data starts and the original file name, if stored
+fn gzip_header(data: &[u8]) -> Result<(usize, Option<String>), ArchiveError> {
+    if slice(data, 2, 1)? != [DEFLATE as u8] {
+        return Err(ArchiveError::Unsupported(
+            "gzip compression method".to_string(),
+        ));
+    }
+    let flags = slice(data, 3, 1)?[0];
+    let mut at = 10;
+    if flags & FEXTRA != 0 {
+        at += 2 + u16_at(data, at)? as usize;
+    }
+    let zero_terminated = |at: &mut usize| -> Result<String, ArchiveError> {
+        let len = data
+            .get(*at..)
+            .and_then(|rest| rest.iter().position(|b| *b == 0))
+            .ok_or_else(|| corrupt("truncated"))?;
+        let text = String::from_utf8_lossy(&data[*at..*at + len]).into_owned();
+        *at += len + 1;
+        Ok(text)
+    };
+    let name = match flags & FNAME {
+        0 => None,
+        _ => Some(zero_terminated(&mut at)?),
+    };
+    if flags & FCOMMENT != 0 {
+        zero_terminated(&mut at)?;
+    }
+    if flags & FHCRC != 0 {
+        at += 2;
+    }
+    Ok((at, name))
+}
+
+fn gzip_extract(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let (start, name) = gzip_header(data)?;
+    if data.len() < start + 8 {
+        return Err(corrupt("truncated"));
+    }
+    let trailer = data.len() - 8;
+    let name = name.unwrap_or_else(|| "The gzip file".to_string());
+    let out = inflate(&data[start..trailer], &name)?;
+    if crc32(&out) != u32_at(data, trailer)? || out.len() as u32 != u32_at(data, trailer + 4)? {
+        return Err(corrupt("CRC mismatch"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+
+    // A zip holding `files`, deflated when `deflate` is set
+    fn zip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+        let mut out = vec![];
+        let mut directory = vec![];
+        for (name, content) in files {
+            let (method, data) = match deflate {
+                true => (DEFLATE, compress_to_vec(content, 6)),
+                false => (STORED, content.to_vec()),
+            };
+            let mut common = vec![20, 0, 0, 0];
+            common.extend(method.to_le_bytes());
+            common.extend([0; 4]);
+            common.extend(crc32(content).to_le_bytes());
+            common.extend((data.len() as u32).to_le_bytes());
+            common.extend((content.len() as u32).to_le_bytes());
+            common.extend((name.len() as u16).to_le_bytes());
+            common.extend([0, 0]);
+
+            directory.extend(ZIP_CENTRAL_HEADER.to_le_bytes());
+            directory.extend([20, 0]);
+            directory.extend(&common);
+            directory.extend([0; 10]);
+            directory.extend((out.len() as u32).to_le_bytes());
+            directory.extend(name.as_bytes());
+
+            out.extend(ZIP_LOCAL_HEADER.to_le_bytes());
+            out.extend(&common);
+            out.extend(name.as_bytes());
+            out.extend(data);
+        }
+        let directory_offset = out.len() as u32;
+        out.extend(&directory);
+        out.extend(ZIP_END_OF_DIRECTORY.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend((files.len() as u16).to_le_bytes());
+        out.extend((files.len() as u16).to_le_bytes());
+        out.extend((directory.len() as u32).to_le_bytes());
+        out.extend(directory_offset.to_le_bytes());
+        out.extend([0, 0]);
+        out
+    }
+
+    #[test]
+    fn test_zip() {
+        let rom = b"NES\x1a and then some more bytes bytes bytes";
+        for deflate in [false, true] {
+            let data = zip(&[("readme.txt", b"hi"), ("Game (U).nes", rom)], deflate);
+            assert_eq!(unpack(data, None).unwrap(), rom);
+        }
+        assert_eq!(unpack(rom.to_vec(), None).unwrap(), rom);
+        assert_eq!(
+            unpack(zip(&[("readme.txt", b"hi")], false), None),
+            Err(ArchiveError::NoRom)
+        );
+
+        let mut bad = zip(&[("a.nes", rom)], false);
+        bad[40] ^= 1;
+        assert!(matches!(unpack(bad, None), Err(ArchiveError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_zip_pick_entry() {
+        let data = zip(&[("set/a.nes", b"A"), ("set/b.NES", b"B")], true);
+        assert_eq!(
+            unpack(data.clone(), None),
+            Err(ArchiveError::Ambiguous(vec![
+                "set/a.nes".to_string(),
+                "set/b.NES".to_string()
+            ]))
+        );
+        assert_eq!(unpack(data.clone(), Some("b.NES")).unwrap(), b"B");
+        assert_eq!(unpack(data.clone(), Some("set/a.nes")).unwrap(), b"A");
+        assert_eq!(
+            unpack(data, Some("c.nes")),
+            Err(ArchiveError::NotFound("c.nes".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_gzip() {
+        let rom = b"NES\x1a gzipped";
+        let mut data = vec![0x1f, 0x8b, 8, FNAME, 0, 0, 0, 0, 0, 3];
+        data.extend(b"game.nes\0");
+        data.extend(compress_to_vec(rom, 6));
+        data.extend(crc32(rom).to_le_bytes());
+        data.extend((rom.len() as u32).to_le_bytes());
+        assert_eq!(gzip_header(&data).unwrap().1.as_deref(), Some("game.nes"));
+        assert_eq!(unpack(data.clone(), None).unwrap(), rom);
+
+        let len = data.len();
+        data[len - 1] ^= 1;
+        assert_eq!(unpack(data, None), Err(corrupt("CRC mismatch")));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        // A few KiB that inflate to one byte over the limit
+        let zeros = vec![0; MAX_ROM_SIZE + 1];
+        let deflated = compress_to_vec(&zeros, 6);
+        assert!(deflated.len() < 0x10000);
+
+        let mut data = vec![0x1f, 0x8b, 8, FNAME, 0, 0, 0, 0, 0, 3];
+        data.extend(b"bomb.nes\0");
+        data.extend(&deflated);
+        data.extend(crc32(&zeros).to_le_bytes());
+        data.extend((zeros.len() as u32).to_le_bytes());
+        assert_eq!(
+            unpack(data, None),
+            Err(ArchiveError::TooLarge("bomb.nes".to_string()))
+        );
+
+        let zipped = zip(&[("bomb.nes", &zeros)], true);
+        assert_eq!(
+            unpack(zipped, None),
+            Err(ArchiveError::TooLarge("bomb.nes".to_string()))
+        );
+    }
+}
@@ -1,47 +1,249 @@
 use crate::cartridge::Rom;
-use crate::cpu::Mem;
-use crate::joypad::Joypad;
+use crate::cdl::{self, CodeDataLog};
+use crate::cheats::Cheats;
+use crate::cpu::{AddressingMode, Mem};
+use crate::fds::Fds;
+use crate::input::Controllers;
+use crate::opcodes::OpCode;
 use crate::ppu::{NesPPU, PPU};
+use std::collections::HashSet;
 
 const RAM: u16 = 0x0000;
 const RAM_MIRROR_END: u16 = 0x1FFF;
 const PPU_REGISTERS_MIRROR_START: u16 = 0x2008;
 const PPU_REGISTERS_MIRROR_END: u16 = 0x3FFF;
+const PRG_RAM_START: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 const PRG_ROM_START: u16 = 0x8000;
 const PRG_ROM_END: u16 = 0xFFFF;
+// With a Disk System RAM adapter, its registers and RAM up to the BIOS
+const FDS_START: u16 = 0x4020;
+const FDS_END: u16 = 0xDFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub access: Access,
+    pub value: u8,
+}
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     prg_rom: Vec<u8>,
+    prg_ram: [u8; 0x2000],
     ppu: NesPPU,
+    fds: Option<Fds>,
 
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    // PPU dots owed from a fraction of a CPU cycle (PAL runs 3.2 per cycle)
+    dot_remainder: u32,
+    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Controllers) + 'call>,
 
-    joypad1: Joypad,
+    controllers: Controllers,
+    cheats: Cheats,
+
+    read_watchpoints: HashSet<u16>,
+    write_watchpoints: HashSet<u16>,
+    watch_hit: Option<WatchHit>,
+
+    cdl: Option<CodeDataLog>,
+    // Bytes of the instruction being executed, their reads are fetches and not data
+    fetch: std::ops::Range<u16>,
+    data_flags: u8,
+    indirect_jump: bool,
 }
 
 impl<'a> Bus<'a> {
     pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+        F: FnMut(&NesPPU, &mut Controllers) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.mirroring);
+        let mut ppu = NesPPU::new(rom.chr_rom, rom.mirroring);
+        ppu.set_region(rom.region);
         Bus {
             cpu_vram: [0; 2048],
             prg_rom: rom.prg_rom,
+            prg_ram: [0; 0x2000],
             ppu,
+            fds: rom.disk.map(Fds::new),
             cycles: 0,
+            dot_remainder: 0,
             gameloop_callback: Box::from(gameloop_callback),
-            joypad1: Joypad::new(),
+            controllers: Controllers::new(),
+            cheats: Cheats::new(),
+            read_watchpoints: HashSet::new(),
+            write_watchpoints: HashSet::new(),
+            watch_hit: None,
+            cdl: None,
+            fetch: 0..0,
+            data_flags: cdl::PRG_DATA,
+            indirect_jump: false,
         }
     }
 
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
-        let new_frame = self.ppu.tick(cycles * 3);
+        if let Some(fds) = &mut self.fds {
+            fds.tick(cycles);
+        }
+        let (dots, per_cycles) = self.ppu.region().ppu_dots_per_cpu_cycle();
+        let total = cycles as u32 * dots + self.dot_remainder;
+        self.dot_remainder = total % per_cycles;
+        let new_frame = self.ppu.tick((total / per_cycles) as u8);
         if new_frame {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+            if let Some(cdl) = &mut self.cdl {
+                cdl.log_frame(&self.ppu);
+            }
+            self.controllers.next_frame();
+            for (addr, value) in self.cheats.ram_writes() {
+                self.poke(addr, value);
+            }
+            (self.gameloop_callback)(&self.ppu, &mut self.controllers);
+        }
+    }
+
+    pub fn controllers_mut(&mut self) -> &mut Controllers {
+        &mut self.controllers
+    }
+
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn fds(&self) -> Option<&Fds> {
+        self.fds.as_ref()
+    }
+
+    pub fn fds_mut(&mut self) -> Option<&mut Fds> {
+        self.fds.as_mut()
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.fds.as_ref().is_some_and(Fds::irq)
+    }
+
+    // Read memory without triggering side effects of I/O registers (used by tooling)
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRROR_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            FDS_START..=FDS_END if self.fds.is_some() => self.fds.as_ref().unwrap().peek(addr),
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM_START) as usize],
+            PRG_ROM_START..=PRG_ROM_END => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
+    // Write memory for tooling: RAM and cartridge memory are patched directly,
+    // anything else goes through the regular write path
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM_START..=FDS_END if self.fds.is_some() => {
+                self.fds.as_mut().unwrap().write(addr, data)
+            }
+            PRG_ROM_START..=PRG_ROM_END => {
+                let len = self.prg_rom.len();
+                self.prg_rom[(addr - PRG_ROM_START) as usize % len] = data;
+            }
+            _ => self.mem_write(addr, data),
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, addr: u16, access: Access) {
+        match access {
+            Access::Read => self.read_watchpoints.insert(addr),
+            Access::Write => self.write_watchpoints.insert(addr),
+        };
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.remove(&addr);
+        self.write_watchpoints.remove(&addr);
+    }
+
+    pub fn watchpoints(&self) -> Vec<(u16, Access)> {
+        let reads = self.read_watchpoints.iter().map(|a| (*a, Access::Read));
+        let writes = self.write_watchpoints.iter().map(|a| (*a, Access::Write));
+        let mut all: Vec<_> = reads.chain(writes).collect();
+        all.sort_by_key(|(addr, _)| *addr);
+        all
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn set_cdl(&mut self, cdl: CodeDataLog) {
+        self.cdl = Some(cdl);
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    // Read the opcode at `pc` as a code fetch rather than data
+    pub fn fetch_opcode(&mut self, pc: u16) -> u8 {
+        self.fetch = pc..pc.wrapping_add(1);
+        self.mem_read(pc)
+    }
+
+    // Called by the CPU before executing the instruction at `pc`
+    pub fn log_instruction(&mut self, pc: u16, op: &OpCode) {
+        if self.cdl.is_none() {
+            return;
+        }
+        self.fetch = pc..pc.wrapping_add(op.len as u16);
+        self.data_flags = match op.mode {
+            AddressingMode::IndirectX | AddressingMode::IndirectY => {
+                cdl::PRG_DATA | cdl::PRG_INDIRECT_DATA
+            }
+            _ => cdl::PRG_DATA,
+        };
+
+        let mut flags = cdl::PRG_CODE;
+        if self.indirect_jump {
+            flags |= cdl::PRG_INDIRECT_CODE;
+        }
+        // JMP ($nnnn)
+        self.indirect_jump = op.code == 0x6c;
+
+        for addr in self.fetch.clone() {
+            if addr >= PRG_ROM_START {
+                let offset = self.prg_rom_offset(addr);
+                self.cdl.as_mut().unwrap().log_prg(offset, addr, flags);
+            }
+        }
+    }
+
+    fn log_prg_read(&mut self, addr: u16) {
+        if self.fetch.contains(&addr) {
+            return;
+        }
+        let offset = self.prg_rom_offset(addr);
+        let flags = self.data_flags;
+        if let Some(cdl) = &mut self.cdl {
+            cdl.log_prg(offset, addr, flags);
         }
     }
 
@@ -49,17 +251,26 @@ impl<'a> Bus<'a> {
         self.ppu.nmi_interrupt.take()
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
-        }
-        self.prg_rom[addr as usize]
+    // 16K images and the 8K FDS BIOS are mirrored up to $FFFF
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        (addr - 0x8000) as usize % self.prg_rom.len()
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_rom_offset(addr)]
     }
 }
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        if self.watch_hit.is_none() && self.read_watchpoints.contains(&addr) {
+            self.watch_hit = Some(WatchHit {
+                addr,
+                access: Access::Read,
+                value: self.peek(addr),
+            });
+        }
+
         match addr {
             RAM..=RAM_MIRROR_END => {
                 let mirr_addr = addr & 0b00000111_11111111;
@@ -73,22 +284,47 @@ impl Mem for Bus<'_> {
 
             0x2002 => self.ppu.read_status(),   // STATUS
             0x2004 => self.ppu.read_oam_data(), // OAMDATA
-            0x2007 => self.ppu.read_data(),     // DATA
+            0x2007 => {
+                // DATA
+                let vram_addr = self.ppu.addr.get();
+                if let Some(cdl) = &mut self.cdl {
+                    if vram_addr < 0x2000 {
+                        cdl.log_chr(vram_addr as usize, cdl::CHR_READ);
+                    }
+                }
+                self.ppu.read_data()
+            }
 
             0x4000..=0x4015 => {
                 //ignore APU
                 0
             }
 
-            0x4016 => self.joypad1.read(), // JOYPAD 1
-            0x4017 => 0,                   // JOYPAD 2
+            0x4016 | 0x4017 => {
+                let port = (addr - 0x4016) as usize;
+                self.controllers.watch(port, &self.ppu);
+                self.controllers.read(port)
+            }
 
             PPU_REGISTERS_MIRROR_START..=PPU_REGISTERS_MIRROR_END => {
                 let mirr_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirr_addr)
             }
 
-            PRG_ROM_START..=PRG_ROM_END => self.read_prg_rom(addr),
+            FDS_START..=FDS_END if self.fds.is_some() => {
+                let value = self.fds.as_mut().unwrap().read(addr);
+                self.cheats.read_prg(addr, value)
+            }
+
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM_START) as usize],
+
+            PRG_ROM_START..=PRG_ROM_END => {
+                if self.cdl.is_some() {
+                    self.log_prg_read(addr);
+                }
+                let value = self.read_prg_rom(addr);
+                self.cheats.read_prg(addr, value)
+            }
 
             _ => {
                 println!("Ignoring memory read at address: {:#X}", addr);
@@ -98,6 +334,14 @@ impl Mem for Bus<'_> {
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if self.watch_hit.is_none() && self.write_watchpoints.contains(&addr) {
+            self.watch_hit = Some(WatchHit {
+                addr,
+                access: Access::Write,
+                value: data,
+            });
+        }
+
         match addr {
             RAM..=RAM_MIRROR_END => {
                 let mirr_addr = addr & 0b11111111111;
@@ -118,10 +362,10 @@ impl Mem for Bus<'_> {
                 //ignore APU
             }
 
-            0x4016 => self.joypad1.write(data),
+            0x4016 => self.controllers.write(data),
 
             0x4017 => {
-                // ignore joypad 2
+                // APU frame counter, the pads are strobed through $4016
             }
 
             // DMA
@@ -144,6 +388,16 @@ impl Mem for Bus<'_> {
                 self.mem_write(mirr_addr, data)
             }
 
+            FDS_START..=FDS_END if self.fds.is_some() => {
+                let fds = self.fds.as_mut().unwrap();
+                fds.write(addr, data);
+                self.ppu.mirroring = fds.mirroring();
+            }
+
+            PRG_RAM_START..=PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM_START) as usize] = data;
+            }
+
             PRG_ROM_START..=PRG_ROM_END => {
                 panic!("Attempting to write to Cartridge ROM: {:#X}", addr);
             }
@@ -159,11 +413,100 @@ impl Mem for Bus<'_> {
 mod test {
     use super::*;
     use crate::cartridge::test;
+    use crate::region::Region;
 
     #[test]
     fn test_mem_read_write_to_ram() {
-        let mut bus = Bus::new(test::test_rom(), |_| {});
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_mem_read_write_to_prg_ram() {
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        bus.mem_write(0x6004, 0x4f);
+        assert_eq!(bus.mem_read(0x6004), 0x4f);
+        assert_eq!(bus.peek(0x6004), 0x4f);
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        bus.set_cheats(Cheats::parse("SXIOPO\n0000756A").unwrap());
+        assert_eq!(bus.mem_read(0x91D9), 0xAD);
+        // The ROM itself stays as it was
+        assert_eq!(bus.peek(0x91D9), 1);
+
+        // RAM codes apply once a frame
+        while bus.peek(0x75) != 0x6A {
+            bus.tick(50);
+        }
+        bus.cheats_mut().set_all_enabled(false);
+        assert_eq!(bus.mem_read(0x91D9), 1);
+    }
+
+    #[test]
+    fn test_pal_dot_ratio() {
+        let mut rom = test::test_rom();
+        rom.region = Region::Pal;
+        let mut bus = Bus::new(rom, |_, _| {});
+        // 5 CPU cycles are 16 dots, in whatever steps they come
+        for cycles in [2, 1, 2] {
+            bus.tick(cycles);
+        }
+        assert_eq!(bus.ppu().dot(), 16);
+        bus.tick(1);
+        assert_eq!(bus.ppu().dot(), 19);
+    }
+
+    #[test]
+    fn test_fds_memory_map() {
+        let mut disk = b"\x01*NINTENDO-HVC*".to_vec();
+        disk.resize(crate::fds::SIDE_SIZE, 0);
+        let mut rom = Rom::new(&disk).unwrap();
+        rom.prg_rom = vec![0xEA; crate::fds::BIOS_SIZE];
+        let mut bus = Bus::new(rom, |_, _| {});
+
+        // RAM all the way up to the BIOS
+        bus.mem_write(0x6000, 1);
+        bus.mem_write(0xDFFF, 2);
+        assert_eq!(bus.mem_read(0x6000), 1);
+        assert_eq!(bus.peek(0xDFFF), 2);
+        assert_eq!(bus.mem_read(0xE000), 0xEA);
+        assert_eq!(bus.mem_read(0xFFFF), 0xEA);
+
+        // The RAM adapter sets mirroring and raises the timer IRQ
+        bus.mem_write(0x4023, 1);
+        bus.mem_write(0x4025, 0b0010_1000);
+        assert_eq!(bus.ppu().mirroring, crate::cartridge::Mirroring::Horizontal);
+        bus.mem_write(0x4020, 5);
+        bus.mem_write(0x4022, 2);
+        bus.tick(6);
+        assert!(bus.irq_pending());
+        bus.mem_read(0x4030);
+        assert!(!bus.irq_pending());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        bus.add_watchpoint(0x10, Access::Write);
+        bus.mem_read(0x10);
+        assert_eq!(bus.take_watch_hit(), None);
+
+        bus.mem_write(0x10, 0x42);
+        assert_eq!(
+            bus.take_watch_hit(),
+            Some(WatchHit {
+                addr: 0x10,
+                access: Access::Write,
+                value: 0x42
+            })
+        );
+
+        bus.remove_watchpoint(0x10);
+        bus.mem_write(0x10, 0x43);
+        assert_eq!(bus.take_watch_hit(), None);
+    }
 }
@@ -1,6 +1,14 @@
+use crate::fds::{self, DiskImage};
+use crate::game_db::{self, GameDb};
+use crate::region::Region;
+use crate::unif::{self, UNIF_TAG};
+
 const NES_TAG: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+// Far above any real cartridge or disk, it keeps an archive or patch that
+// claims a huge size from taking all memory
+pub const MAX_ROM_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mirroring {
@@ -9,26 +17,120 @@ pub enum Mirroring {
     FourScreen,
 }
 
+// A header field the game database disagreed with
+#[derive(Debug, PartialEq)]
+pub struct Correction {
+    pub field: &'static str,
+    pub header: String,
+    pub database: String,
+}
+
+impl std::fmt::Display for Correction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.header, self.database)
+    }
+}
+
+fn correct<T: PartialEq + std::fmt::Debug>(
+    corrections: &mut Vec<Correction>,
+    field: &'static str,
+    current: &mut T,
+    database: Option<T>,
+) {
+    if let Some(value) = database.filter(|value| value != current) {
+        corrections.push(Correction {
+            field,
+            header: format!("{:?}", current),
+            database: format!("{:?}", value),
+        });
+        *current = value;
+    }
+}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub mirroring: Mirroring,
+    // NES 2.0 default expansion device, 0 when unspecified or plain iNES
+    pub expansion_device: u8,
+    // From the NES 2.0 header, NTSC for plain iNES
+    pub region: Region,
+    // Battery backed PRG RAM
+    pub battery: bool,
+    // CRC32 and SHA-1 of PRG and CHR ROM, the game database keys
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+    // Header fields the game database fixed
+    pub corrections: Vec<Correction>,
+    // Famicom Disk System image, the BIOS goes in `prg_rom`
+    pub disk: Option<DiskImage>,
+}
+
+// NES 2.0 ROM size from the LSB byte and the MSB nibble in byte 9. An MSB
+// nibble of $F switches to the exponent-multiplier form 2^E * (MM * 2 + 1).
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0xf {
+        let exponent = lsb >> 2;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        (1usize << exponent) * multiplier
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * page_size
+    }
 }
 
 impl Rom {
+    // Parse an iNES, UNIF or FDS file, fixing the header from the built-in game database
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
+        Rom::with_database(raw, GameDb::embedded())
+    }
+
+    pub fn with_database(raw: &Vec<u8>, db: &GameDb) -> Result<Rom, String> {
+        let mut rom = Rom::parse(raw)?;
+        if let Some(game) = db.lookup(rom.crc32, &rom.sha1) {
+            let corrections = &mut rom.corrections;
+            correct(corrections, "mapper", &mut rom.mapper, game.mapper);
+            correct(
+                corrections,
+                "mirroring",
+                &mut rom.mirroring,
+                game.mirroring.clone(),
+            );
+            correct(corrections, "battery", &mut rom.battery, game.battery);
+            correct(corrections, "region", &mut rom.region, game.region);
+        }
+        Ok(rom)
+    }
+
+    fn parse(raw: &Vec<u8>) -> Result<Rom, String> {
+        if raw.starts_with(&UNIF_TAG) {
+            return unif::parse(raw);
+        }
+        if fds::is_fds(raw) {
+            return fds::parse_rom(raw);
+        }
+
         // Parse ROM header
-        if &raw[0..4] != NES_TAG {
+        if !raw.starts_with(&NES_TAG) {
             return Err("File is not a valid iNES format".to_string());
         }
+        if raw.len() < 16 {
+            return Err("iNES header is truncated".to_string());
+        }
 
         let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
         let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
+        if ines_ver != 0 && ines_ver != 2 {
             return Err(format!("Unsupported iNES version: {}", ines_ver));
         }
+        let nes2 = ines_ver == 2;
+        if nes2 && raw[8] & 0x0f != 0 {
+            return Err(format!(
+                "Unsupported mapper: {}",
+                (raw[8] as u16 & 0x0f) << 8 | mapper as u16
+            ));
+        }
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
@@ -38,18 +140,48 @@ impl Rom {
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            (
+                nes2_rom_size(raw[4], raw[9] & 0x0f, PRG_ROM_PAGE_SIZE),
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE),
+            )
+        } else {
+            (
+                raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                raw[5] as usize * CHR_ROM_PAGE_SIZE,
+            )
+        };
         let skip_trainer = raw[6] & 0b100 != 0;
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
 
+        let prg_rom = raw
+            .get(prg_rom_start..(prg_rom_start + prg_rom_size))
+            .ok_or("PRG ROM is truncated")?
+            .to_vec();
+        let chr_rom = raw
+            .get(chr_rom_start..(chr_rom_start + chr_rom_size))
+            .ok_or("CHR ROM is truncated")?
+            .to_vec();
+        let rom_data = [prg_rom.as_slice(), chr_rom.as_slice()].concat();
+
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            prg_rom,
+            chr_rom,
             mapper,
             mirroring,
+            expansion_device: if nes2 { raw[15] & 0x3f } else { 0 },
+            region: if nes2 {
+                Region::from_nes2(raw[12])
+            } else {
+                Region::Ntsc
+            },
+            battery: raw[6] & 0b10 != 0,
+            crc32: game_db::crc32(&rom_data),
+            sha1: game_db::sha1(&rom_data),
+            corrections: vec![],
+            disk: None,
         })
     }
 }
@@ -150,10 +282,33 @@ pub mod test {
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_header() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 0x01, 00, 00, 0x0F,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.expansion_device, 0x0F);
+        assert_eq!(rom.region, Region::Pal);
+
+        assert_eq!(
+            nes2_rom_size(0x01, 0x1, PRG_ROM_PAGE_SIZE),
+            257 * PRG_ROM_PAGE_SIZE
+        );
+        assert_eq!(nes2_rom_size(0b0001_0001, 0xf, PRG_ROM_PAGE_SIZE), 16 * 3);
+    }
+
+    #[test]
+    fn test_unknown_version_is_not_supported() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x4, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
@@ -162,7 +317,87 @@ pub mod test {
         let rom = Rom::new(&test_rom);
         match rom {
             Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "Unsupported iNES version: 2"),
+            Result::Err(str) => assert_eq!(str, "Unsupported iNES version: 1"),
         }
     }
+
+    #[test]
+    fn test_truncated_file_is_an_error() {
+        assert_eq!(
+            Rom::new(&vec![0x4E, 0x45]).err().unwrap(),
+            "File is not a valid iNES format"
+        );
+        assert_eq!(
+            Rom::new(&vec![0x4E, 0x45, 0x53, 0x1A, 0x02]).err().unwrap(),
+            "iNES header is truncated"
+        );
+
+        let header = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+        ];
+        let short_prg = create_rom(TestRom {
+            header: header.clone(),
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+        assert_eq!(Rom::new(&short_prg).err().unwrap(), "PRG ROM is truncated");
+        let short_chr = create_rom(TestRom {
+            header,
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 16],
+        });
+        assert_eq!(Rom::new(&short_chr).err().unwrap(), "CHR ROM is truncated");
+    }
+
+    #[test]
+    fn test_database_fixes_bad_header() {
+        // Header says mapper 3, vertical, no battery
+        let raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let crc32 = Rom::new(&raw).unwrap().crc32;
+        let db = GameDb::parse(&format!("{:08X} 2 horizontal yes - Bad dump", crc32)).unwrap();
+
+        let rom = Rom::with_database(&raw, &db).unwrap();
+        assert_eq!(rom.mapper, 2);
+        assert_eq!(rom.mirroring, Mirroring::Horizontal);
+        assert!(rom.battery);
+        assert_eq!(rom.region, Region::Ntsc);
+        let corrections: Vec<String> = rom.corrections.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            corrections,
+            [
+                "mapper: 3 -> 2",
+                "mirroring: Vertical -> Horizontal",
+                "battery: false -> true"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_embedded_database_fixes_bad_dump() {
+        // snake.nes as a bad dump would carry it: MMC1, horizontal, battery
+        let mut raw = std::fs::read("roms/snake.nes").unwrap();
+        raw[6] = 0b0001_0010;
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
+        assert_eq!(rom.corrections.len(), 3);
+    }
+
+    #[test]
+    fn test_database_agrees_with_good_header() {
+        let raw = std::fs::read("roms/nestest.nes").unwrap();
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.crc32, 0x158B_0388);
+        assert!(rom.corrections.is_empty());
+    }
 }
@@ -0,0 +1,191 @@
+use crate::ppu::NesPPU;
+use std::path::Path;
+
+// FCEUX .cdl layout: one flag byte per PRG ROM byte followed by one per CHR ROM byte.
+// PRG flags: xPdcAADC
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+// AA: which 8 KiB window ($8000/$A000/$C000/$E000) the byte was accessed through
+const PRG_WINDOW_SHIFT: u8 = 2;
+pub const PRG_INDIRECT_CODE: u8 = 0x10;
+pub const PRG_INDIRECT_DATA: u8 = 0x20;
+// CHR flags: xxxxxxRD
+pub const CHR_DRAWN: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+const TILE_SIZE: usize = 16;
+const NAMETABLE_TILES: usize = 0x3c0;
+// Sprites at Y >= $EF are hidden below the screen
+const SPRITE_HIDDEN_Y: u8 = 0xef;
+
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_len],
+            chr: vec![0; chr_len],
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], prg_len: usize, chr_len: usize) -> Result<Self, String> {
+        if bytes.len() != prg_len + chr_len {
+            return Err(format!(
+                "CDL size {} doesn't match ROM (PRG {} + CHR {})",
+                bytes.len(),
+                prg_len,
+                chr_len
+            ));
+        }
+        Ok(CodeDataLog {
+            prg: bytes[..prg_len].to_vec(),
+            chr: bytes[prg_len..].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    // Continue an existing log, or start a new one if the file doesn't exist yet
+    pub fn load_or_new(path: &Path, prg_len: usize, chr_len: usize) -> Result<Self, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => CodeDataLog::from_bytes(&bytes, prg_len, chr_len)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(CodeDataLog::new(prg_len, chr_len))
+            }
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.to_bytes()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    pub fn log_prg(&mut self, offset: usize, cpu_addr: u16, flags: u8) {
+        let window = ((cpu_addr >> 13) & 0b11) as u8;
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte |= flags | window << PRG_WINDOW_SHIFT;
+        }
+    }
+
+    pub fn log_chr(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+
+    // Mark the pattern tiles the renderer fetches for the current frame:
+    // the background of the first nametable and every visible sprite
+    pub fn log_frame(&mut self, ppu: &NesPPU) {
+        let bank = ppu.ctrl.bknd_pattern_addr() as usize;
+        for i in 0..NAMETABLE_TILES {
+            self.log_tile(bank, ppu.vram[i]);
+        }
+
+        let bank = ppu.ctrl.sprt_pattern_addr() as usize;
+        for sprite in ppu.oam.chunks(4) {
+            if sprite[0] < SPRITE_HIDDEN_Y {
+                self.log_tile(bank, sprite[1]);
+            }
+        }
+    }
+
+    fn log_tile(&mut self, bank: usize, tile: u8) {
+        let start = bank + tile as usize * TILE_SIZE;
+        for offset in start..start + TILE_SIZE {
+            self.log_chr(offset, CHR_DRAWN);
+        }
+    }
+
+    // (code, data, total) PRG byte counts
+    pub fn prg_coverage(&self) -> (usize, usize, usize) {
+        let code = self.prg.iter().filter(|f| *f & PRG_CODE != 0).count();
+        let data = self.prg.iter().filter(|f| *f & PRG_DATA != 0).count();
+        (code, data, self.prg.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{Mirroring, Rom};
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_prg_flags_and_roundtrip() {
+        let mut cdl = CodeDataLog::new(0x4000, 0x2000);
+        cdl.log_prg(0x0004, 0xC004, PRG_CODE);
+        cdl.log_prg(0x0100, 0x8100, PRG_DATA | PRG_INDIRECT_DATA);
+        assert_eq!(cdl.prg()[0x0004], 0b0000_1001);
+        assert_eq!(cdl.prg()[0x0100], 0b0010_0010);
+        assert_eq!(cdl.prg_coverage(), (1, 1, 0x4000));
+
+        let bytes = cdl.to_bytes();
+        assert_eq!(bytes.len(), 0x6000);
+        let loaded = CodeDataLog::from_bytes(&bytes, 0x4000, 0x2000).unwrap();
+        assert_eq!(loaded.prg(), cdl.prg());
+        assert!(CodeDataLog::from_bytes(&bytes, 0x8000, 0x2000).is_err());
+    }
+
+    #[test]
+    fn test_log_frame_marks_drawn_tiles() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::Horizontal);
+        ppu.vram[..NAMETABLE_TILES].fill(2);
+        ppu.oam.fill(0xff);
+        ppu.oam[..4].copy_from_slice(&[0x10, 0x05, 0x00, 0x10]);
+        // sprites from $1000
+        ppu.ctrl.update(0b0000_1000);
+
+        let mut cdl = CodeDataLog::new(0x4000, 0x2000);
+        cdl.log_frame(&ppu);
+
+        let drawn: Vec<usize> = (0..0x2000 / TILE_SIZE)
+            .filter(|tile| cdl.chr()[tile * TILE_SIZE] & CHR_DRAWN != 0)
+            .collect();
+        assert_eq!(drawn, vec![2, 0x105]);
+    }
+
+    #[test]
+    fn test_cpu_logs_code_and_data() {
+        let raw = std::fs::read("roms/nestest.nes").unwrap();
+        let rom = Rom::new(&raw).unwrap();
+        let mut bus = Bus::new(rom, |_, _| {});
+        bus.set_cdl(CodeDataLog::new(0x4000, 0x2000));
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0xC000;
+        for _ in 0..5000 {
+            cpu.step();
+        }
+
+        let cdl = cpu.bus.cdl().unwrap();
+        // $C000: JMP $C5F5, fetched through the $C000 window and never read as data
+        for offset in 0x0000..=0x0002 {
+            assert_eq!(cdl.prg()[offset], PRG_CODE | 2 << PRG_WINDOW_SHIFT);
+        }
+        // nestest keeps its tables apart from its code
+        let both = cdl
+            .prg()
+            .iter()
+            .filter(|flags| *flags & (PRG_CODE | PRG_DATA) == PRG_CODE | PRG_DATA)
+            .count();
+        assert_eq!(both, 0);
+        let (code, data, _) = cdl.prg_coverage();
+        assert!(code > 0x100);
+        assert!(data > 0);
+    }
+}
@@ -0,0 +1,330 @@
+use std::path::Path;
+
+// Game Genie letters, each one is a 4-bit value
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Patch {
+    // Game Genie: PRG reads of `addr` return `value`, only when the ROM holds
+    // `compare` there for 8-letter codes
+    Rom {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    // Pro Action Replay: `value` is written to RAM `addr` every frame
+    Ram {
+        addr: u16,
+        value: u8,
+    },
+}
+
+fn game_genie_digits(code: &str) -> Option<Vec<u8>> {
+    code.bytes()
+        .map(|c| {
+            let c = c.to_ascii_uppercase();
+            GAME_GENIE_LETTERS
+                .iter()
+                .position(|l| *l == c)
+                .map(|n| n as u8)
+        })
+        .collect()
+}
+
+// Letters scramble the bits, see https://www.nesdev.org/wiki/Game_Genie
+fn decode_game_genie(code: &str) -> Option<Patch> {
+    let n = game_genie_digits(code)?;
+    if n.len() != 6 && n.len() != 8 {
+        return None;
+    }
+    let addr = 0x8000
+        | ((n[3] & 7) as u16) << 12
+        | ((n[5] & 7) as u16) << 8
+        | ((n[4] & 8) as u16) << 8
+        | ((n[2] & 7) as u16) << 4
+        | ((n[1] & 8) as u16) << 4
+        | (n[4] & 7) as u16
+        | (n[3] & 8) as u16;
+    let value = (n[1] & 7) << 4 | (n[0] & 8) << 4 | (n[0] & 7);
+    Some(match n.len() {
+        6 => Patch::Rom {
+            addr,
+            value: value | (n[5] & 8),
+            compare: None,
+        },
+        _ => Patch::Rom {
+            addr,
+            value: value | (n[7] & 8),
+            compare: Some((n[7] & 7) << 4 | (n[6] & 8) << 4 | (n[6] & 7) | (n[5] & 8)),
+        },
+    })
+}
+
+// 8 hex digits `00AAAAVV`, or `AAAA:VV`
+fn decode_action_replay(code: &str) -> Option<Patch> {
+    let hex = |digits: &str| u32::from_str_radix(digits, 16).ok();
+    let (addr, value) = match code.split_once(':') {
+        Some((addr, value)) if addr.len() == 4 && value.len() == 2 => (hex(addr)?, hex(value)?),
+        None if code.len() == 8 && code.starts_with("00") => {
+            let raw = hex(code)?;
+            (raw >> 8, raw & 0xff)
+        }
+        _ => return None,
+    };
+    // Only RAM and cartridge RAM, not I/O registers or ROM
+    if !(0x0000..0x2000).contains(&addr) && !(0x6000..0x8000).contains(&addr) {
+        return None;
+    }
+    Some(Patch::Ram {
+        addr: addr as u16,
+        value: value as u8,
+    })
+}
+
+impl Patch {
+    pub fn decode(code: &str) -> Result<Patch, String> {
+        decode_game_genie(code)
+            .or_else(|| decode_action_replay(code))
+            .ok_or_else(|| format!("Invalid cheat code: {}", code))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub code: String,
+    pub description: String,
+    pub enabled: bool,
+    pub patch: Patch,
+}
+
+// Cheat file, one code per line followed by an optional description. A `-`
+// in front of the code keeps it disabled:
+//
+//   SXIOPO  Infinite lives
+//   -0000756A  Start with fire flower
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    // Switch for all of them at once
+    enabled: bool,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Cheats::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats {
+            cheats: vec![],
+            enabled: true,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut cheats = Cheats::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (code, enabled) = match code.strip_prefix('-') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            let index = cheats
+                .add(code, description.trim())
+                .map_err(|e| format!("line {}: {}", n + 1, e))?;
+            cheats.cheats[index].enabled = enabled;
+        }
+        Ok(cheats)
+    }
+
+    // The cheat file of a ROM, if it has one
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Cheats::new());
+        }
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Cheats::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn to_text(&self) -> String {
+        self.cheats
+            .iter()
+            .map(|cheat| {
+                let disabled = if cheat.enabled { "" } else { "-" };
+                format!("{}{} {}\n", disabled, cheat.code, cheat.description).replace(" \n", "\n")
+            })
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.to_text()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Add an enabled cheat, returns its index
+    pub fn add(&mut self, code: &str, description: &str) -> Result<usize, String> {
+        let code = code.to_ascii_uppercase();
+        let patch = Patch::decode(&code)?;
+        self.cheats.push(Cheat {
+            code,
+            description: description.to_string(),
+            enabled: true,
+            patch,
+        });
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Cheat, String> {
+        if index >= self.cheats.len() {
+            return Err(format!("No cheat #{}", index));
+        }
+        Ok(self.cheats.remove(index))
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> Result<(), String> {
+        let cheat = self
+            .cheats
+            .get_mut(index)
+            .ok_or_else(|| format!("No cheat #{}", index))?;
+        cheat.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_all_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn active(&self) -> impl Iterator<Item = &Patch> {
+        self.cheats
+            .iter()
+            .filter(|cheat| self.enabled && cheat.enabled)
+            .map(|cheat| &cheat.patch)
+    }
+
+    // What a PRG read of `addr` returns when the ROM holds `value`
+    pub fn read_prg(&self, addr: u16, value: u8) -> u8 {
+        for patch in self.active() {
+            if let Patch::Rom {
+                addr: at,
+                value: replace,
+                compare,
+            } = *patch
+            {
+                if at == addr && compare.is_none_or(|c| c == value) {
+                    return replace;
+                }
+            }
+        }
+        value
+    }
+
+    // RAM writes to apply once per frame
+    pub fn ram_writes(&self) -> Vec<(u16, u8)> {
+        self.active()
+            .filter_map(|patch| match *patch {
+                Patch::Ram { addr, value } => Some((addr, value)),
+                Patch::Rom { .. } => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_game_genie() {
+        assert_eq!(
+            Patch::decode("GOSSIP"),
+            Ok(Patch::Rom {
+                addr: 0xD1DD,
+                value: 0x14,
+                compare: None
+            })
+        );
+        assert_eq!(
+            Patch::decode("ZEXPYGLA"),
+            Ok(Patch::Rom {
+                addr: 0x94A7,
+                value: 0x02,
+                compare: Some(0x03)
+            })
+        );
+        // Super Mario Bros. infinite lives
+        assert_eq!(
+            Patch::decode("SXIOPO"),
+            Ok(Patch::Rom {
+                addr: 0x91D9,
+                value: 0xAD,
+                compare: None
+            })
+        );
+        assert!(Patch::decode("GOSSI").is_err());
+        assert!(Patch::decode("GOSSIB").is_err());
+    }
+
+    #[test]
+    fn test_decode_action_replay() {
+        assert_eq!(
+            Patch::decode("0000756A"),
+            Ok(Patch::Ram {
+                addr: 0x0075,
+                value: 0x6A
+            })
+        );
+        assert_eq!(
+            Patch::decode("07FA:09"),
+            Ok(Patch::Ram {
+                addr: 0x07FA,
+                value: 0x09
+            })
+        );
+        assert!(Patch::decode("0080006A").is_err());
+        assert!(Patch::decode("2000:80").is_err());
+        assert!(Patch::decode("12345678").is_err());
+    }
+
+    #[test]
+    fn test_cheat_file() {
+        let mut cheats =
+            Cheats::parse("# smb\nsxiopo  Infinite lives\n-0000756A Fire flower\nZEXPYGLA\n")
+                .unwrap();
+        assert_eq!(cheats.list().len(), 3);
+        assert_eq!(cheats.list()[0].code, "SXIOPO");
+        assert_eq!(cheats.list()[0].description, "Infinite lives");
+        assert!(!cheats.list()[1].enabled);
+        assert_eq!(
+            Cheats::parse(&cheats.to_text()).unwrap().list(),
+            cheats.list()
+        );
+
+        assert_eq!(cheats.read_prg(0x91D9, 0xCE), 0xAD);
+        // 8-letter codes only patch when the ROM has the compare value
+        assert_eq!(cheats.read_prg(0x94A7, 0x03), 0x02);
+        assert_eq!(cheats.read_prg(0x94A7, 0x04), 0x04);
+        assert!(cheats.ram_writes().is_empty());
+
+        cheats.set_enabled(1, true).unwrap();
+        assert_eq!(cheats.ram_writes(), [(0x0075, 0x6A)]);
+        cheats.set_all_enabled(false);
+        assert_eq!(cheats.read_prg(0x91D9, 0xCE), 0xCE);
+        assert!(cheats.ram_writes().is_empty());
+
+        assert!(cheats.set_enabled(5, true).is_err());
+        assert!(Cheats::parse("NOTACODE").is_err());
+    }
+}
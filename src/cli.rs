@@ -0,0 +1,440 @@
+use crate::input::multitap::Multitap;
+use crate::input::DeviceKind;
+use crate::region::Region;
+use crate::testrom::DEFAULT_TIMEOUT_FRAMES;
+use crate::trace::TraceFormat;
+
+const DEFAULT_ROM: &str = "roms/games/pacman.nes";
+const DEFAULT_HEADLESS_FRAMES: usize = 60;
+const DEFAULT_SCREENSHOT_DIR: &str = "screenshots";
+
+pub struct TraceArgs {
+    pub path: String,
+    pub format: TraceFormat,
+    pub start: Option<u16>,
+    pub stop: Option<u16>,
+}
+
+pub struct DisasmArgs {
+    pub rom: String,
+    pub entry: Option<String>,
+    pub patch: Option<String>,
+    pub output: Option<String>,
+    pub symbols: Vec<String>,
+    pub cdl: Option<String>,
+}
+
+pub struct HeadlessArgs {
+    pub frames: usize,
+    pub screenshots: Vec<usize>,
+    pub screenshot_dir: String,
+    pub input: Option<String>,
+    pub ppu_dump: Option<String>,
+}
+
+pub struct Args {
+    pub rom: String,
+    // ROM to load from a zip holding several
+    pub entry: Option<String>,
+    pub disasm: Option<DisasmArgs>,
+    pub headless: Option<HeadlessArgs>,
+    pub config: Option<String>,
+    pub multitap: Option<Multitap>,
+    pub ports: [Option<DeviceKind>; 2],
+    // Overrides the region the ROM says it's for
+    pub region: Option<Region>,
+    pub symbols: Vec<String>,
+    pub cdl: Option<String>,
+    // Cheat file, the ROM's .cht sibling when not given
+    pub cheats: Option<String>,
+    // IPS, BPS or UPS patch, a same-named sibling of the ROM when not given
+    pub patch: Option<String>,
+    // Game database (text or nes20db XML) used instead of the built-in one
+    pub game_db: Option<String>,
+    // Disk System BIOS, disksys.rom next to the image when not given
+    pub fds_bios: Option<String>,
+    // Save FDS disk writes into the image instead of a diff beside it
+    pub fds_write_back: bool,
+    pub trace: Option<TraceArgs>,
+    pub test_roms: Option<String>,
+    pub timeout_frames: usize,
+    pub debug: bool,
+    pub ppu_viewer: bool,
+    pub gdb_port: Option<u16>,
+}
+
+// Parse a 16-bit address written as `C000`, `$C000` or `0xC000`
+pub fn parse_addr(value: &str) -> Result<u16, String> {
+    let digits = value
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", value))
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid frame count: {}", value))
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", flag))
+}
+
+// `nes disasm rom.nes [-o out.s] [--symbols file]... [--cdl file] [--entry name]
+// [--patch file]`
+fn parse_disasm<I: Iterator<Item = String>>(mut args: I) -> Result<DisasmArgs, String> {
+    let mut rom = None;
+    let mut entry = None;
+    let mut patch = None;
+    let mut output = None;
+    let mut symbols = vec![];
+    let mut cdl = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(next_value(&mut args, &arg)?),
+            "--symbols" => symbols.push(next_value(&mut args, &arg)?),
+            "--cdl" => cdl = Some(next_value(&mut args, &arg)?),
+            "--entry" => entry = Some(next_value(&mut args, &arg)?),
+            "--patch" => patch = Some(next_value(&mut args, &arg)?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    Ok(DisasmArgs {
+        rom: rom.ok_or("Missing ROM for disasm")?,
+        entry,
+        patch,
+        output,
+        symbols,
+        cdl,
+    })
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut args = args.peekable();
+    let mut disasm = None;
+    if args.peek().map(String::as_str) == Some("disasm") {
+        args.next();
+        disasm = Some(parse_disasm(&mut args)?);
+    }
+
+    let mut rom = None;
+    let mut symbols = vec![];
+    let mut cdl = None;
+    let mut cheats = None;
+    let mut patch = None;
+    let mut entry = None;
+    let mut game_db = None;
+    let mut fds_bios = None;
+    let mut fds_write_back = false;
+    let mut trace_path = None;
+    let mut trace_format = TraceFormat::Nestest;
+    let mut trace_start = None;
+    let mut trace_stop = None;
+    let mut test_roms = None;
+    let mut timeout_frames = DEFAULT_TIMEOUT_FRAMES;
+    let mut debug = false;
+    let mut ppu_viewer = false;
+    let mut gdb_port = None;
+    let mut config = None;
+    let mut multitap = None;
+    let mut ports = [None; 2];
+    let mut region = None;
+    let mut headless = false;
+    let mut frames = DEFAULT_HEADLESS_FRAMES;
+    let mut screenshots = vec![];
+    let mut screenshot_dir = DEFAULT_SCREENSHOT_DIR.to_string();
+    let mut input = None;
+    let mut ppu_dump = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => trace_path = Some(next_value(&mut args, &arg)?),
+            "--trace-format" => trace_format = next_value(&mut args, &arg)?.parse()?,
+            "--trace-start" => trace_start = Some(parse_addr(&next_value(&mut args, &arg)?)?),
+            "--trace-stop" => trace_stop = Some(parse_addr(&next_value(&mut args, &arg)?)?),
+            "--symbols" => symbols.push(next_value(&mut args, &arg)?),
+            "--cdl" => cdl = Some(next_value(&mut args, &arg)?),
+            "--cheats" => cheats = Some(next_value(&mut args, &arg)?),
+            "--entry" => entry = Some(next_value(&mut args, &arg)?),
+            "--patch" => patch = Some(next_value(&mut args, &arg)?),
+            "--game-db" => game_db = Some(next_value(&mut args, &arg)?),
+            "--fds-bios" => fds_bios = Some(next_value(&mut args, &arg)?),
+            "--fds-write-back" => fds_write_back = true,
+            "--debug" => debug = true,
+            "--ppu-viewer" => ppu_viewer = true,
+            "--gdb" => {
+                let value = next_value(&mut args, &arg)?;
+                gdb_port = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid port: {}", value))?,
+                );
+            }
+            "--config" => config = Some(next_value(&mut args, &arg)?),
+            "--multitap" => multitap = Some(next_value(&mut args, &arg)?.parse()?),
+            "--port1" => ports[0] = Some(next_value(&mut args, &arg)?.parse()?),
+            "--port2" => ports[1] = Some(next_value(&mut args, &arg)?.parse()?),
+            "--region" => region = Some(next_value(&mut args, &arg)?.parse()?),
+            "--headless" => headless = true,
+            "--frames" => frames = parse_count(&next_value(&mut args, &arg)?)?,
+            "--screenshot" => {
+                for value in next_value(&mut args, &arg)?.split(',') {
+                    screenshots.push(parse_count(value)?);
+                }
+            }
+            "--screenshot-dir" => screenshot_dir = next_value(&mut args, &arg)?,
+            "--input" => input = Some(next_value(&mut args, &arg)?),
+            "--ppu-dump" => ppu_dump = Some(next_value(&mut args, &arg)?),
+            "--test-roms" => test_roms = Some(next_value(&mut args, &arg)?),
+            "--timeout" => timeout_frames = parse_count(&next_value(&mut args, &arg)?)?,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    let trace = trace_path.map(|path| TraceArgs {
+        path,
+        format: trace_format,
+        start: trace_start,
+        stop: trace_stop,
+    });
+
+    let headless = headless.then_some(HeadlessArgs {
+        frames,
+        screenshots,
+        screenshot_dir,
+        input,
+        ppu_dump,
+    });
+
+    Ok(Args {
+        rom: rom.unwrap_or_else(|| DEFAULT_ROM.to_string()),
+        entry,
+        disasm,
+        headless,
+        config,
+        multitap,
+        ports,
+        region,
+        symbols,
+        cdl,
+        cheats,
+        patch,
+        game_db,
+        fds_bios,
+        fds_write_back,
+        trace,
+        test_roms,
+        timeout_frames,
+        debug,
+        ppu_viewer,
+        gdb_port,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_trace_args() {
+        let args = args(
+            "game.nes --trace out.log --trace-format mesen --trace-start $C000 --trace-stop 0xC100",
+        )
+        .unwrap();
+        assert_eq!(args.rom, "game.nes");
+        assert!(!args.debug);
+        assert!(!args.ppu_viewer);
+
+        let trace = args.trace.unwrap();
+        assert_eq!(trace.path, "out.log");
+        assert_eq!(trace.format, TraceFormat::Mesen);
+        assert_eq!(trace.start, Some(0xC000));
+        assert_eq!(trace.stop, Some(0xC100));
+    }
+
+    #[test]
+    fn test_parse_test_roms() {
+        let args = args("--test-roms roms/tests --timeout 600").unwrap();
+        assert_eq!(args.test_roms.as_deref(), Some("roms/tests"));
+        assert_eq!(args.timeout_frames, 600);
+    }
+
+    #[test]
+    fn test_parse_debug() {
+        assert!(args("game.nes --debug").unwrap().debug);
+        assert!(!args("game.nes").unwrap().debug);
+    }
+
+    #[test]
+    fn test_parse_symbols() {
+        assert!(args("game.nes").unwrap().symbols.is_empty());
+        assert_eq!(
+            args("game.nes --symbols game.dbg --symbols game.nl")
+                .unwrap()
+                .symbols,
+            vec!["game.dbg", "game.nl"]
+        );
+    }
+
+    #[test]
+    fn test_parse_cdl() {
+        assert!(args("game.nes").unwrap().cdl.is_none());
+        assert_eq!(
+            args("game.nes --cdl game.cdl").unwrap().cdl.as_deref(),
+            Some("game.cdl")
+        );
+    }
+
+    #[test]
+    fn test_parse_ppu_viewer() {
+        assert!(args("game.nes --ppu-viewer").unwrap().ppu_viewer);
+        assert!(!args("game.nes").unwrap().ppu_viewer);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert!(args("game.nes").unwrap().config.is_none());
+        assert_eq!(
+            args("game.nes --config nes.cfg").unwrap().config.as_deref(),
+            Some("nes.cfg")
+        );
+    }
+
+    #[test]
+    fn test_parse_cheats() {
+        assert!(args("game.nes").unwrap().cheats.is_none());
+        assert_eq!(
+            args("game.nes --cheats game.cht")
+                .unwrap()
+                .cheats
+                .as_deref(),
+            Some("game.cht")
+        );
+    }
+
+    #[test]
+    fn test_parse_game_db() {
+        assert!(args("game.nes").unwrap().game_db.is_none());
+        assert_eq!(
+            args("game.nes --game-db nes20db.xml")
+                .unwrap()
+                .game_db
+                .as_deref(),
+            Some("nes20db.xml")
+        );
+    }
+
+    #[test]
+    fn test_parse_patch() {
+        assert!(args("game.nes").unwrap().patch.is_none());
+        assert_eq!(
+            args("game.nes --patch fix.ips").unwrap().patch.as_deref(),
+            Some("fix.ips")
+        );
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        assert!(args("games.zip").unwrap().entry.is_none());
+        assert_eq!(
+            args("games.zip --entry game.nes").unwrap().entry.as_deref(),
+            Some("game.nes")
+        );
+    }
+
+    #[test]
+    fn test_parse_fds() {
+        let defaults = args("game.fds").unwrap();
+        assert!(defaults.fds_bios.is_none());
+        assert!(!defaults.fds_write_back);
+
+        let parsed = args("game.fds --fds-bios disksys.rom --fds-write-back").unwrap();
+        assert_eq!(parsed.fds_bios.as_deref(), Some("disksys.rom"));
+        assert!(parsed.fds_write_back);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(args("--bogus").is_err());
+        assert!(args("--trace").is_err());
+        assert!(args("--config").is_err());
+        assert!(args("--trace x --trace-format vice").is_err());
+        assert!(args("--trace x --trace-start zz").is_err());
+        assert!(args("--timeout soon").is_err());
+        assert!(args("--gdb 70000").is_err());
+        assert!(args("disasm").is_err());
+        assert!(args("disasm game.nes -x").is_err());
+    }
+
+    #[test]
+    fn test_parse_disasm() {
+        let disasm =
+            args("disasm game.nes -o game.s --symbols game.dbg --symbols game.mlb --cdl game.cdl")
+                .unwrap()
+                .disasm
+                .unwrap();
+        assert_eq!(disasm.rom, "game.nes");
+        assert_eq!(disasm.output.as_deref(), Some("game.s"));
+        assert_eq!(disasm.symbols, vec!["game.dbg", "game.mlb"]);
+        assert_eq!(disasm.cdl.as_deref(), Some("game.cdl"));
+        assert!(disasm.entry.is_none());
+        assert!(disasm.patch.is_none());
+        assert!(args("game.nes").unwrap().disasm.is_none());
+
+        let disasm = args("disasm games.zip --entry game.nes --patch fix.ips")
+            .unwrap()
+            .disasm
+            .unwrap();
+        assert_eq!(disasm.entry.as_deref(), Some("game.nes"));
+        assert_eq!(disasm.patch.as_deref(), Some("fix.ips"));
+    }
+
+    #[test]
+    fn test_parse_headless() {
+        let headless = args(
+            "game.nes --headless --frames 120 --screenshot 1,60 --screenshot 120 --input run.txt",
+        )
+        .unwrap()
+        .headless
+        .unwrap();
+        assert_eq!(headless.frames, 120);
+        assert_eq!(headless.screenshots, vec![1, 60, 120]);
+        assert_eq!(headless.screenshot_dir, "screenshots");
+        assert_eq!(headless.input.as_deref(), Some("run.txt"));
+        assert!(headless.ppu_dump.is_none());
+        assert!(args("game.nes --frames 10").unwrap().headless.is_none());
+        assert!(args("--headless --screenshot 1,x").is_err());
+    }
+
+    #[test]
+    fn test_parse_input_devices() {
+        let parsed = args("game.nes --multitap fourscore").unwrap();
+        assert_eq!(parsed.multitap, Some(Multitap::FourScore));
+        assert_eq!(parsed.ports, [None, None]);
+        assert_eq!(
+            args("--port2 arkanoid").unwrap().ports,
+            [None, Some(DeviceKind::Arkanoid)]
+        );
+        assert!(args("--multitap satellite2").is_err());
+        assert!(args("--port2 zapper2").is_err());
+    }
+
+    #[test]
+    fn test_parse_region() {
+        assert_eq!(args("game.nes").unwrap().region, None);
+        assert_eq!(args("--region PAL").unwrap().region, Some(Region::Pal));
+        assert_eq!(args("--region dendy").unwrap().region, Some(Region::Dendy));
+        assert!(args("--region secam").is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use crate::input::multitap::Multitap;
+use crate::input::DeviceKind;
+use crate::joypad::{self, button_from_name, button_name, InputMacro, JoypadButtons};
+use std::path::{Path, PathBuf};
+
+// Settings kept in an INI file in the user's config directory:
+//
+//   [joypad1]
+//   a = J
+//   [hotkeys]
+//   screenshot = F9
+//   [video]
+//   scale = 3
+//   [macros]
+//   H = down+b*3,-,a
+//
+// Keys are SDL key names ("Right Shift", "Keypad Enter", "F5"). The frontend
+// resolves them; an empty value leaves the button or hotkey unbound. Macros
+// play on controller 1, see `InputMacro::parse` for the format.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Pause,
+    FastForward,
+    Screenshot,
+    Debugger,
+    ScaleUp,
+    ScaleDown,
+    RecordMacro,
+    ToggleCheats,
+    SwitchDiskSide,
+    FrameAdvance,
+    SlowMotion,
+    ShowFps,
+}
+
+const HOTKEYS: [(Hotkey, &str, &str); 12] = [
+    (Hotkey::Pause, "pause", "P"),
+    (Hotkey::FastForward, "fast_forward", "Tab"),
+    (Hotkey::FrameAdvance, "frame_advance", "\\"),
+    (Hotkey::SlowMotion, "slow_motion", "F4"),
+    (Hotkey::ShowFps, "show_fps", "F10"),
+    (Hotkey::Screenshot, "screenshot", "F9"),
+    (Hotkey::Debugger, "debugger", "F12"),
+    (Hotkey::ScaleUp, "scale_up", "="),
+    (Hotkey::ScaleDown, "scale_down", "-"),
+    (Hotkey::RecordMacro, "record_macro", "F8"),
+    (Hotkey::ToggleCheats, "toggle_cheats", "F6"),
+    (Hotkey::SwitchDiskSide, "switch_disk_side", "F11"),
+];
+
+const JOYPAD1_KEYS: [&str; 8] = ["J", "K", "Space", "Return", "W", "S", "A", "D"];
+const JOYPAD2_KEYS: [&str; 8] = [
+    ".",
+    ",",
+    "Right Shift",
+    "Right Ctrl",
+    "Up",
+    "Down",
+    "Left",
+    "Right",
+];
+
+const TURBO_BUTTONS: [JoypadButtons; 2] = [JoypadButtons::ButtonA, JoypadButtons::ButtonB];
+const TURBO1_KEYS: [&str; 2] = ["U", "I"];
+
+// Power Pad buttons 1-12 on the numeric keypad
+const POWER_PAD_KEYS: [&str; 12] = [
+    "Keypad 7",
+    "Keypad 8",
+    "Keypad 9",
+    "Keypad -",
+    "Keypad 4",
+    "Keypad 5",
+    "Keypad 6",
+    "Keypad +",
+    "Keypad 1",
+    "Keypad 2",
+    "Keypad 3",
+    "Keypad Enter",
+];
+
+pub const MAX_SCALE: u32 = 8;
+
+// `auto` (None) leaves the choice to the ROM header
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputConfig {
+    pub multitap: Option<Multitap>,
+    pub ports: [Option<DeviceKind>; 2],
+    // Frames turbo buttons stay pressed, then released
+    pub turbo_rate: u8,
+    // Let left+right and up+down be pressed together
+    pub allow_opposing: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoConfig {
+    pub scale: u32,
+    // .pal file replacing the built-in palette
+    pub palette: Option<String>,
+    // Wait for the monitor's refresh when presenting, to avoid tearing.
+    // Emulation speed doesn't depend on it.
+    pub vsync: bool,
+    // Frames per second and speed in the corner of the screen
+    pub show_fps: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    // Key name for every button of controllers 1-4, 3 and 4 go through a multitap
+    pub joypads: [Vec<(JoypadButtons, String)>; 4],
+    // Turbo A and B keys, `turbo_a` and `turbo_b` in the joypad sections
+    pub turbo: [Vec<(JoypadButtons, String)>; 4],
+    pub hotkeys: Vec<(Hotkey, String)>,
+    // Key name for Power Pad buttons 1-12
+    pub power_pad: Vec<String>,
+    pub input: InputConfig,
+    pub video: VideoConfig,
+    // Key name and the macro it plays
+    pub macros: Vec<(String, InputMacro)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let bindings = |keys: [&str; 8]| {
+            joypad::buttons()
+                .zip(keys)
+                .map(|(button, key)| (button, key.to_string()))
+                .collect()
+        };
+        let turbo = |keys: [&str; 2]| {
+            TURBO_BUTTONS
+                .into_iter()
+                .zip(keys)
+                .map(|(button, key)| (button, key.to_string()))
+                .collect()
+        };
+        Config {
+            joypads: [
+                bindings(JOYPAD1_KEYS),
+                bindings(JOYPAD2_KEYS),
+                bindings([""; 8]),
+                bindings([""; 8]),
+            ],
+            turbo: [
+                turbo(TURBO1_KEYS),
+                turbo([""; 2]),
+                turbo([""; 2]),
+                turbo([""; 2]),
+            ],
+            hotkeys: HOTKEYS
+                .iter()
+                .map(|(hotkey, _, key)| (*hotkey, key.to_string()))
+                .collect(),
+            power_pad: POWER_PAD_KEYS.iter().map(|key| key.to_string()).collect(),
+            input: InputConfig {
+                multitap: None,
+                ports: [None; 2],
+                turbo_rate: joypad::DEFAULT_TURBO_RATE,
+                allow_opposing: false,
+            },
+            video: VideoConfig {
+                scale: 3,
+                palette: None,
+                vsync: true,
+                show_fps: false,
+            },
+            macros: vec![],
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/nes/config.ini, falling back to ~/.config and %APPDATA%
+pub fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("nes").join("config.ini"))
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", key, value))
+}
+
+fn parse_auto<T: std::str::FromStr<Err = String>>(value: &str) -> Result<Option<T>, String> {
+    match value {
+        "auto" => Ok(None),
+        _ => value.parse().map(Some),
+    }
+}
+
+fn auto_name(name: Option<&'static str>) -> &'static str {
+    name.unwrap_or("auto")
+}
+
+fn set_binding<K: Copy + PartialEq>(bindings: &mut [(K, String)], key: K, value: &str) {
+    if let Some(binding) = bindings.iter_mut().find(|(k, _)| *k == key) {
+        binding.1 = value.to_string();
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut section = String::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let err = |msg: String| format!("line {}: {}", n + 1, msg);
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_ascii_lowercase();
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| err(format!("expected key = value: {}", line)))?;
+            config.set(&section, key, value).map_err(err)?;
+        }
+
+        if config.video.scale == 0 || config.video.scale > MAX_SCALE {
+            return Err(format!("Window scale must be 1-{}", MAX_SCALE));
+        }
+        if config.input.turbo_rate == 0 {
+            return Err("Turbo rate must be at least 1 frame".to_string());
+        }
+        // A key does one thing, or a single press would hit several
+        let bindings = config.bindings();
+        for (i, (key, setting)) in bindings.iter().enumerate() {
+            if let Some((_, other)) = bindings[..i]
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            {
+                return Err(format!(
+                    "{} is bound to both {} and {}",
+                    key, other, setting
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    // Every bound key with the setting it's bound to, unbound ones left out
+    fn bindings(&self) -> Vec<(&str, String)> {
+        let mut bindings = vec![];
+        for (port, joypad) in self.joypads.iter().enumerate() {
+            for (button, key) in joypad {
+                bindings.push((
+                    key.as_str(),
+                    format!("[joypad{}] {}", port + 1, button_name(*button)),
+                ));
+            }
+            for (button, key) in &self.turbo[port] {
+                bindings.push((
+                    key.as_str(),
+                    format!("[joypad{}] turbo_{}", port + 1, button_name(*button)),
+                ));
+            }
+        }
+        for (hotkey, key) in &self.hotkeys {
+            bindings.push((key.as_str(), format!("[hotkeys] {}", hotkey.name())));
+        }
+        for (i, key) in self.power_pad.iter().enumerate() {
+            bindings.push((key.as_str(), format!("[powerpad] {}", i + 1)));
+        }
+        for (key, _) in &self.macros {
+            bindings.push((key.as_str(), format!("[macros] {}", key)));
+        }
+        bindings.retain(|(key, _)| !key.is_empty());
+        bindings
+    }
+
+    // The button or hotkey `key` is bound to. Macros aren't included, binding
+    // a new one to the same key replaces them.
+    pub fn bound_to(&self, key: &str) -> Option<String> {
+        self.bindings()
+            .into_iter()
+            .find(|(k, setting)| k.eq_ignore_ascii_case(key) && !setting.starts_with("[macros]"))
+            .map(|(_, setting)| setting)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), String> {
+        let unknown = || format!("Unknown setting [{}] {}", section, key);
+        match section {
+            "joypad1" | "joypad2" | "joypad3" | "joypad4" => {
+                let player = (section.as_bytes()[6] - b'1') as usize;
+                match key.strip_prefix("turbo_") {
+                    Some(name) => {
+                        let button = button_from_name(name)
+                            .filter(|b| TURBO_BUTTONS.contains(b))
+                            .ok_or_else(unknown)?;
+                        set_binding(&mut self.turbo[player], button, value);
+                    }
+                    None => {
+                        let button = button_from_name(key).ok_or_else(unknown)?;
+                        set_binding(&mut self.joypads[player], button, value);
+                    }
+                }
+            }
+            "hotkeys" => {
+                let (hotkey, _, _) = HOTKEYS
+                    .iter()
+                    .find(|(_, name, _)| *name == key)
+                    .ok_or_else(unknown)?;
+                set_binding(&mut self.hotkeys, *hotkey, value);
+            }
+            "powerpad" => {
+                let button: usize = key.parse().map_err(|_| unknown())?;
+                let binding = button
+                    .checked_sub(1)
+                    .and_then(|i| self.power_pad.get_mut(i))
+                    .ok_or_else(unknown)?;
+                *binding = value.to_string();
+            }
+            "input" => match key {
+                "multitap" => self.input.multitap = parse_auto(value)?,
+                "port1" => self.input.ports[0] = parse_auto(value)?,
+                "port2" => self.input.ports[1] = parse_auto(value)?,
+                "turbo_rate" => self.input.turbo_rate = parse_value(key, value)?,
+                "allow_opposing" => self.input.allow_opposing = parse_value(key, value)?,
+                _ => return Err(unknown()),
+            },
+            "video" => match key {
+                "scale" => self.video.scale = parse_value(key, value)?,
+                "palette" => self.video.palette = Some(value.to_string()).filter(|v| !v.is_empty()),
+                "vsync" => self.video.vsync = parse_value(key, value)?,
+                "show_fps" => self.video.show_fps = parse_value(key, value)?,
+                _ => return Err(unknown()),
+            },
+            "macros" => {
+                let input_macro = InputMacro::parse(value)?;
+                self.macros.retain(|(k, _)| k != key);
+                self.macros.push((key.to_string(), input_macro));
+            }
+            _ => return Err(unknown()),
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn to_ini(&self) -> String {
+        let mut out = String::new();
+        for (port, bindings) in self.joypads.iter().enumerate() {
+            out += &format!("[joypad{}]\n", port + 1);
+            for (button, key) in bindings {
+                out += &format!("{} = {}\n", button_name(*button), key);
+            }
+            for (button, key) in &self.turbo[port] {
+                out += &format!("turbo_{} = {}\n", button_name(*button), key);
+            }
+            out += "\n";
+        }
+
+        out += "[hotkeys]\n";
+        for (hotkey, key) in &self.hotkeys {
+            out += &format!("{} = {}\n", hotkey.name(), key);
+        }
+
+        out += "\n[powerpad]\n";
+        for (i, key) in self.power_pad.iter().enumerate() {
+            out += &format!("{} = {}\n", i + 1, key);
+        }
+
+        out += &format!(
+            "\n[input]\nmultitap = {}\nport1 = {}\nport2 = {}\nturbo_rate = {}\nallow_opposing = {}\n",
+            auto_name(self.input.multitap.map(|m| m.name())),
+            auto_name(self.input.ports[0].map(|d| d.name())),
+            auto_name(self.input.ports[1].map(|d| d.name())),
+            self.input.turbo_rate,
+            self.input.allow_opposing,
+        );
+
+        out += &format!("\n[video]\nscale = {}\n", self.video.scale);
+        out += &format!(
+            "palette = {}\nvsync = {}\nshow_fps = {}\n",
+            self.video.palette.as_deref().unwrap_or(""),
+            self.video.vsync,
+            self.video.show_fps
+        );
+
+        out += "\n[macros]\n";
+        for (key, input_macro) in &self.macros {
+            out += &format!("{} = {}\n", key, input_macro);
+        }
+        out
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(err)?;
+        }
+        std::fs::write(path, self.to_ini()).map_err(err)
+    }
+
+    pub fn hotkey(&self, hotkey: Hotkey) -> &str {
+        self.hotkeys
+            .iter()
+            .find(|(h, _)| *h == hotkey)
+            .map_or("", |(_, key)| key)
+    }
+}
+
+impl Hotkey {
+    pub fn name(&self) -> &'static str {
+        HOTKEYS
+            .iter()
+            .find(|(hotkey, _, _)| hotkey == self)
+            .map_or("?", |(_, name, _)| name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_overrides_defaults() {
+        let config = Config::parse(
+            "# mine\n[joypad1]\na = L\nSelect = Left Shift\n\n[hotkeys]\npause = \n\n[video]\nscale = 2\npalette = smooth.pal\n",
+        )
+        .unwrap();
+        let joypad1 = &config.joypads[0];
+        assert_eq!(joypad1[0].1, "L");
+        assert_eq!(joypad1[2].1, "Left Shift");
+        assert_eq!(joypad1[4].1, "W");
+        assert_eq!(config.hotkey(Hotkey::Pause), "");
+        assert_eq!(config.hotkey(Hotkey::Screenshot), "F9");
+        assert_eq!(config.video.scale, 2);
+        assert_eq!(config.video.palette.as_deref(), Some("smooth.pal"));
+        assert_eq!(config.turbo[0][0].1, "U");
+        assert_eq!(config.hotkey(Hotkey::RecordMacro), "F8");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut config = Config::default();
+        config.video.scale = 4;
+        config.joypads[1][0].1 = "Keypad 0".to_string();
+        config.joypads[3][7].1 = "L".to_string();
+        config.video.vsync = false;
+        config.video.show_fps = true;
+        config.input.ports[1] = Some(DeviceKind::PowerPad);
+        config.power_pad[11] = "Keypad *".to_string();
+        config.input.multitap = Some(Multitap::None);
+        config.input.turbo_rate = 4;
+        config.input.allow_opposing = true;
+        config.turbo[1][1].1 = "Keypad /".to_string();
+        config
+            .macros
+            .push(("H".to_string(), InputMacro::parse("down+b*3,-,a").unwrap()));
+        assert_eq!(Config::parse(&config.to_ini()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Config::parse("[joypad1]\nturbo = X").is_err());
+        assert!(Config::parse("[joypad5]\na = X").is_err());
+        assert!(Config::parse("[video]\nscale = big").is_err());
+        assert!(Config::parse("[video]\nscale = 0").is_err());
+        assert!(Config::parse("[audio]\nvolume = 50").is_err());
+        assert!(Config::parse("[network]\nport = 1").is_err());
+        assert!(Config::parse("[input]\nport1 = keyboard").is_err());
+        assert!(Config::parse("[powerpad]\n13 = X").is_err());
+        assert!(Config::parse("[powerpad]\n0 = X").is_err());
+        assert!(Config::parse("[hotkeys]\nscreenshot").is_err());
+        assert!(Config::parse("[joypad1]\nturbo_start = X").is_err());
+        assert!(Config::parse("[input]\nturbo_rate = 0").is_err());
+        assert!(Config::parse("[macros]\nH = jump*2").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let defaults = Config::default();
+        assert_eq!(Config::parse(&defaults.to_ini()).unwrap(), defaults);
+        assert_eq!(
+            Config::parse("[joypad2]\nstart = Keypad Enter")
+                .err()
+                .unwrap(),
+            "Keypad Enter is bound to both [joypad2] start and [powerpad] 12"
+        );
+        assert_eq!(
+            Config::parse("[macros]\nj = a,b").err().unwrap(),
+            "j is bound to both [joypad1] a and [macros] j"
+        );
+        assert!(Config::parse("[joypad1]\na = \nb = \n").is_ok());
+        assert_eq!(
+            defaults.bound_to("f9").as_deref(),
+            Some("[hotkeys] screenshot")
+        );
+        assert_eq!(defaults.bound_to("H"), None);
+    }
+}
@@ -66,20 +66,24 @@ mod interrupt {
     #[derive(PartialEq, Eq)]
     pub enum InterruptType {
         NMI,
+        IRQ,
     }
 
     #[derive(PartialEq, Eq)]
     pub(super) struct Interrupt {
         pub(super) itype: InterruptType,
         pub(super) vector_addr: u16,
-        pub(super) b_flag_mask: u8,
         pub(super) cpu_cycles: u8,
     }
     pub(super) const NMI: Interrupt = Interrupt {
         itype: InterruptType::NMI,
         vector_addr: 0xfffA,
-        b_flag_mask: 0b00100000,
-        cpu_cycles: 2,
+        cpu_cycles: 7,
+    };
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        cpu_cycles: 7,
     };
 }
 
@@ -105,6 +109,72 @@ fn page_cross(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
 
+fn read_u16(read: &mut impl FnMut(u16) -> u8, addr: u16) -> u16 {
+    let lo = read(addr) as u16;
+    let hi = read(addr.wrapping_add(1)) as u16;
+    hi << 8 | lo
+}
+
+// Address `mode` points at for the operand at `addr`, and whether indexing
+// crossed a page
+fn effective_address(
+    mode: &AddressingMode,
+    addr: u16,
+    register_x: u8,
+    register_y: u8,
+    mut read: impl FnMut(u16) -> u8,
+) -> (u16, bool) {
+    match mode {
+        AddressingMode::ZeroPage => (read(addr) as u16, false),
+
+        AddressingMode::Absolute => (read_u16(&mut read, addr), false),
+
+        AddressingMode::ZeroPageX => {
+            let pos = read(addr);
+            let addr = pos.wrapping_add(register_x) as u16;
+            (addr, false)
+        }
+        AddressingMode::ZeroPageY => {
+            let pos = read(addr);
+            let addr = pos.wrapping_add(register_y) as u16;
+            (addr, false)
+        }
+
+        AddressingMode::AbsoluteX => {
+            let base = read_u16(&mut read, addr);
+            let addr = base.wrapping_add(register_x as u16);
+            (addr, page_cross(base, addr))
+        }
+        AddressingMode::AbsoluteY => {
+            let base = read_u16(&mut read, addr);
+            let addr = base.wrapping_add(register_y as u16);
+            (addr, page_cross(base, addr))
+        }
+
+        AddressingMode::IndirectX => {
+            let base = read(addr);
+
+            let ptr: u8 = base.wrapping_add(register_x);
+            let lo = read(ptr as u16);
+            let hi = read(ptr.wrapping_add(1) as u16);
+            ((hi as u16) << 8 | (lo as u16), false)
+        }
+        AddressingMode::IndirectY => {
+            let base = read(addr);
+
+            let lo = read(base as u16);
+            let hi = read(base.wrapping_add(1) as u16);
+            let deref_base = (hi as u16) << 8 | (lo as u16);
+            let deref = deref_base.wrapping_add(register_y as u16);
+            (deref, page_cross(deref, deref_base))
+        }
+
+        _ => {
+            panic!("mode {:?} is not supported", mode);
+        }
+    }
+}
+
 impl<'a> CPU<'a> {
     pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
         CPU {
@@ -131,6 +201,9 @@ impl<'a> CPU<'a> {
         self.stack_pointer = STACK_RESET;
         self.status = CpuFlags::from_bits_truncate(0b100100);
         self.program_counter = self.mem_read_u16(0xFFFC);
+
+        // Reset sequence takes 7 cycles
+        self.bus.tick(7);
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
@@ -139,7 +212,9 @@ impl<'a> CPU<'a> {
             self.mem_write(address, byte);
             address += 1;
         }
-        self.mem_write_u16(0xFFFC, 0x0600)
+        // The reset vector is in cartridge ROM, so it's patched rather than written
+        self.bus.poke(0xFFFC, 0x00);
+        self.bus.poke(0xFFFD, 0x06);
     }
 
     pub fn run(&mut self) {
@@ -150,542 +225,564 @@ impl<'a> CPU<'a> {
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes = *opcodes::OPCODES_MAP;
-
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI)
-            }
+            self.poll_interrupts();
 
             callback(self);
 
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+            if !self.execute() {
+                return;
+            }
+        }
+    }
 
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
+    // Execute a single instruction, servicing a pending NMI or IRQ first.
+    // Returns false once the CPU hits BRK.
+    pub fn step(&mut self) -> bool {
+        self.poll_interrupts();
+        self.execute()
+    }
 
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
+    fn poll_interrupts(&mut self) {
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(interrupt::NMI)
+        } else if self.bus.irq_pending() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            self.interrupt(interrupt::IRQ)
+        }
+    }
 
-                0xAA => self.tax(),
-                0xe8 => self.inx(),
-                0x00 => return,
+    fn execute(&mut self) -> bool {
+        let ref opcodes = *opcodes::OPCODES_MAP;
 
-                /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+        let code = self.bus.fetch_opcode(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
 
-                /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+        let opcode = opcodes
+            .get(&code)
+            .expect(&format!("OpCode {:x} is not recognized", code));
+        self.bus.log_instruction(program_counter_state - 1, opcode);
 
-                /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+        match code {
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            }
 
-                /* CLC */ 0x18 => self.clear_carry_flag(),
+            0xAA => self.tax(),
+            0xe8 => self.inx(),
+            0x00 => return false,
 
-                /* SEC */ 0x38 => self.set_carry_flag(),
+            /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
 
-                /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
 
-                /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+            /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
 
-                /* PHA */ 0x48 => self.stack_push(self.register_a),
+            /* CLC */ 0x18 => self.clear_carry_flag(),
 
-                /* PLA */
-                0x68 => {
-                    self.pla();
-                }
+            /* SEC */ 0x38 => self.set_carry_flag(),
 
-                /* PHP */
-                0x08 => {
-                    self.php();
-                }
+            /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
 
-                /* PLP */
-                0x28 => {
-                    self.plp();
-                }
+            /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
 
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
+            /* PHA */ 0x48 => self.stack_push(self.register_a),
 
-                /* SBC */
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
+            /* PLA */
+            0x68 => {
+                self.pla();
+            }
 
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
+            /* PHP */
+            0x08 => {
+                self.php();
+            }
 
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
+            /* PLP */
+            0x28 => {
+                self.plp();
+            }
 
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
+            /* ADC */
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
 
-                /* LSR */ 0x4a => self.lsr_accumulator(),
+            /* SBC */
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode);
+            }
 
-                /* LSR */
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
+            /* AND */
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
 
-                /*ASL*/ 0x0a => self.asl_accumulator(),
+            /* EOR */
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
 
-                /* ASL */
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
+            /* ORA */
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
 
-                /*ROL*/ 0x2a => self.rol_accumulator(),
+            /* LSR */ 0x4a => self.lsr_accumulator(),
 
-                /* ROL */
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
+            /* LSR */
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
 
-                /* ROR */ 0x6a => self.ror_accumulator(),
+            /*ASL*/ 0x0a => self.asl_accumulator(),
 
-                /* ROR */
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
+            /* ASL */
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
 
-                /* INC */
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
+            /*ROL*/ 0x2a => self.rol_accumulator(),
 
-                /* INY */
-                0xc8 => self.iny(),
+            /* ROL */
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
 
-                /* DEC */
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                }
+            /* ROR */ 0x6a => self.ror_accumulator(),
 
-                /* DEX */
-                0xca => {
-                    self.dex();
-                }
+            /* ROR */
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
 
-                /* DEY */
-                0x88 => {
-                    self.dey();
-                }
+            /* INC */
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            }
 
-                /* CMP */
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
+            /* INY */
+            0xc8 => self.iny(),
 
-                /* CPY */
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
+            /* DEC */
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            }
 
-                /* CPX */
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+            /* DEX */
+            0xca => {
+                self.dex();
+            }
 
-                /* JMP Absolute */
-                0x4c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
+            /* DEY */
+            0x88 => {
+                self.dey();
+            }
 
-                /* JMP Indirect */
-                0x6c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
+            /* CMP */
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
 
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
+            /* CPY */
+            0xc0 | 0xc4 | 0xcc => {
+                self.compare(&opcode.mode, self.register_y);
+            }
 
-                    self.program_counter = indirect_ref;
-                }
+            /* CPX */
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
 
-                /* JSR */
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
+            /* JMP Absolute */
+            0x4c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
 
-                /* RTS */
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
+            /* JMP Indirect */
+            0x6c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
 
-                /* RTI */
-                0x40 => {
-                    self.status = CpuFlags::from_bits_truncate(self.stack_pop());
-                    self.status.remove(CpuFlags::BREAK);
-                    self.status.insert(CpuFlags::UNUSED);
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
 
-                    self.program_counter = self.stack_pop_u16();
-                }
+                self.program_counter = indirect_ref;
+            }
 
-                /* BNE */
-                0xd0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                }
+            /* JSR */
+            0x20 => {
+                self.stack_push_u16(self.program_counter + 2 - 1);
+                let target_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = target_address
+            }
 
-                /* BVS */
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* RTS */
+            0x60 => {
+                self.program_counter = self.stack_pop_u16() + 1;
+            }
 
-                /* BVC */
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* RTI */
+            0x40 => {
+                self.status = CpuFlags::from_bits_truncate(self.stack_pop());
+                self.status.remove(CpuFlags::BREAK);
+                self.status.insert(CpuFlags::UNUSED);
 
-                /* BPL */
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIVE));
-                }
+                self.program_counter = self.stack_pop_u16();
+            }
 
-                /* BMI */
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIVE));
-                }
+            /* BNE */
+            0xd0 => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* BEQ */
-                0xf0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                }
+            /* BVS */
+            0x70 => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BCS */
-                0xb0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                }
+            /* BVC */
+            0x50 => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BCC */
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                }
+            /* BPL */
+            0x10 => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIVE));
+            }
 
-                /* BIT */
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
+            /* BMI */
+            0x30 => {
+                self.branch(self.status.contains(CpuFlags::NEGATIVE));
+            }
 
-                /* STA */
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+            /* BEQ */
+            0xf0 => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* STX */
-                0x86 | 0x96 | 0x8e => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                }
+            /* BCS */
+            0xb0 => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* STY */
-                0x84 | 0x94 | 0x8c => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                }
+            /* BCC */
+            0x90 => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* LDX */
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                }
+            /* BIT */
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            }
 
-                /* LDY */
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                }
+            /* STA */
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
 
-                /* NOP */
-                0xea => {
-                    //do nothing
-                }
+            /* STX */
+            0x86 | 0x96 | 0x8e => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_x);
+            }
 
-                /* TAY */
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
+            /* STY */
+            0x84 | 0x94 | 0x8c => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_y);
+            }
 
-                /* TSX */
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
+            /* LDX */
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode);
+            }
 
-                /* TXA */
-                0x8a => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* LDY */
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode);
+            }
 
-                /* TXS */
-                0x9a => {
-                    self.stack_pointer = self.register_x;
-                }
+            /* NOP */
+            0xea => {
+                //do nothing
+            }
 
-                /* TYA */
-                0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* TAY */
+            0xa8 => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
 
-                /* unofficial */
+            /* TSX */
+            0xba => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
 
-                /* DCP */
-                0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_sub(1);
-                    self.mem_write(addr, data);
-                    // self._update_zero_and_negative_flags(data);
-                    if data <= self.register_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
+            /* TXA */
+            0x8a => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                    self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-                }
+            /* TXS */
+            0x9a => {
+                self.stack_pointer = self.register_x;
+            }
 
-                /* RLA */
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
-                    let data = self.rol(&opcode.mode);
-                    self.and_with_register_a(data);
-                }
+            /* TYA */
+            0x98 => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                /* SLO */ //todo tests
-                0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
-                    let data = self.asl(&opcode.mode);
-                    self.or_with_register_a(data);
-                }
+            /* unofficial */
 
-                /* SRE */ //todo tests
-                0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
-                    let data = self.lsr(&opcode.mode);
-                    self.xor_with_register_a(data);
+            /* DCP */
+            0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data.wrapping_sub(1);
+                self.mem_write(addr, data);
+                // self._update_zero_and_negative_flags(data);
+                if data <= self.register_a {
+                    self.status.insert(CpuFlags::CARRY);
                 }
 
-                /* SKB */
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
-                    /* 2 byte NOP (immediate ) */
-                    // todo: might be worth doing the read
-                }
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            }
 
-                /* AXS */
-                0xCB => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    let x_and_a = self.register_x & self.register_a;
-                    let result = x_and_a.wrapping_sub(data);
+            /* RLA */
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
+                let data = self.rol(&opcode.mode);
+                self.and_with_register_a(data);
+            }
 
-                    if data <= x_and_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
-                    self.update_zero_and_negative_flags(result);
+            /* SLO */ //todo tests
+            0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
+                let data = self.asl(&opcode.mode);
+                self.or_with_register_a(data);
+            }
 
-                    self.register_x = result;
-                }
+            /* SRE */ //todo tests
+            0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
+                let data = self.lsr(&opcode.mode);
+                self.xor_with_register_a(data);
+            }
 
-                /* ARR */
-                0x6B => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.ror_accumulator();
-                    //todo: registers
-                    let result = self.register_a;
-                    let bit_5 = (result >> 5) & 1;
-                    let bit_6 = (result >> 6) & 1;
-
-                    if bit_6 == 1 {
-                        self.status.insert(CpuFlags::CARRY)
-                    } else {
-                        self.status.remove(CpuFlags::CARRY)
-                    }
-
-                    if bit_5 ^ bit_6 == 1 {
-                        self.status.insert(CpuFlags::OVERFLOW);
-                    } else {
-                        self.status.remove(CpuFlags::OVERFLOW);
-                    }
-
-                    self.update_zero_and_negative_flags(result);
-                }
+            /* SKB */
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
+                /* 2 byte NOP (immediate ) */
+                // todo: might be worth doing the read
+            }
 
-                /* unofficial SBC */
-                0xeb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.sub_from_register_a(data);
-                }
+            /* AXS */
+            0xCB => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
 
-                /* ANC */
-                0x0b | 0x2b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    if self.status.contains(CpuFlags::NEGATIVE) {
-                        self.status.insert(CpuFlags::CARRY);
-                    } else {
-                        self.status.remove(CpuFlags::CARRY);
-                    }
+                if data <= x_and_a {
+                    self.status.insert(CpuFlags::CARRY);
                 }
+                self.update_zero_and_negative_flags(result);
 
-                /* ALR */
-                0x4b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.lsr_accumulator();
-                }
+                self.register_x = result;
+            }
 
-                //todo: test for everything bellow
-
-                /* NOP read */
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
-                    /* do nothing */
-                }
+            /* ARR */
+            0x6B => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.ror_accumulator();
+                //todo: registers
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
 
-                /* RRA */
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                    let data = self.ror(&opcode.mode);
-                    self.add_to_register_a(data);
+                if bit_6 == 1 {
+                    self.status.insert(CpuFlags::CARRY)
+                } else {
+                    self.status.remove(CpuFlags::CARRY)
                 }
 
-                /* ISB */
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                    let data = self.inc(&opcode.mode);
-                    self.sub_from_register_a(data);
+                if bit_5 ^ bit_6 == 1 {
+                    self.status.insert(CpuFlags::OVERFLOW);
+                } else {
+                    self.status.remove(CpuFlags::OVERFLOW);
                 }
 
-                /* NOPs */
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => { /* do nothing */ }
+                self.update_zero_and_negative_flags(result);
+            }
 
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+            /* unofficial SBC */
+            0xeb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.sub_from_register_a(data);
+            }
 
-                /* LAX */
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.set_register_a(data);
-                    self.register_x = self.register_a;
+            /* ANC */
+            0x0b | 0x2b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                if self.status.contains(CpuFlags::NEGATIVE) {
+                    self.status.insert(CpuFlags::CARRY);
+                } else {
+                    self.status.remove(CpuFlags::CARRY);
                 }
+            }
 
-                /* SAX */
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let data = self.register_a & self.register_x;
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, data);
-                }
+            /* ALR */
+            0x4b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.lsr_accumulator();
+            }
 
-                /* LXA */
-                0xab => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                }
+            //todo: test for everything bellow
 
-                /* XAA */
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
+            /* NOP read */
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c | 0x3c
+            | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                if page_cross {
+                    self.bus.tick(1);
                 }
+                /* do nothing */
+            }
 
-                /* LAS */
-                0xbb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data & self.stack_pointer;
-                    self.register_a = data;
-                    self.register_x = data;
-                    self.stack_pointer = data;
-                    self.update_zero_and_negative_flags(data);
-                }
+            /* RRA */
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
+                let data = self.ror(&opcode.mode);
+                self.add_to_register_a(data);
+            }
+
+            /* ISB */
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
+                let data = self.inc(&opcode.mode);
+                self.sub_from_register_a(data);
+            }
 
-                /* TAS */
-                0x9b => {
-                    let data = self.register_a & self.register_x;
-                    self.stack_pointer = data;
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* NOPs */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => { /* do nothing */
+            }
 
-                    let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                    self.mem_write(mem_address, data)
-                }
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
 
-                /* AHX  Indirect Y */
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
+            /* LAX */
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                if page_cross {
+                    self.bus.tick(1);
                 }
+                self.set_register_a(data);
+                self.register_x = self.register_a;
+            }
 
-                /* AHX Absolute Y*/
-                0x9f => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* SAX */
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                let data = self.register_a & self.register_x;
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, data);
+            }
 
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
+            /* LXA */
+            0xab => {
+                self.lda(&opcode.mode);
+                self.tax();
+            }
 
-                /* SHX */
-                0x9e => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* XAA */
+            0x8b => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+            }
 
-                    // todo if cross page boundry {
-                    //     mem_address &= (self.x as u16) << 8;
-                    // }
-                    let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+            /* LAS */
+            0xbb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+            }
+
+            /* TAS */
+            0x9b => {
+                let data = self.register_a & self.register_x;
+                self.stack_pointer = data;
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+
+                let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
+                self.mem_write(mem_address, data)
+            }
 
-                /* SHY */
-                0x9c => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                } // _ => todo!(),
+            /* AHX  Indirect Y */
+            0x93 => {
+                let pos: u8 = self.mem_read(self.program_counter);
+                let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
             }
 
-            self.bus.tick(opcode.cycles);
+            /* AHX Absolute Y*/
+            0x9f => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
             }
+
+            /* SHX */
+            0x9e => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+
+                // todo if cross page boundry {
+                //     mem_address &= (self.x as u16) << 8;
+                // }
+                let data = self.register_x & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
+
+            /* SHY */
+            0x9c => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_x as u16;
+                let data = self.register_y & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            } // _ => todo!(),
+        }
+
+        self.bus.tick(opcode.cycles);
+
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
         }
+
+        true
     }
 
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stack_push_u16(self.program_counter);
+        // Hardware interrupts push P with B clear, only BRK and PHP set it
         let mut flag = self.status.clone();
-        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
-        flag.set(CpuFlags::UNUSED, interrupt.b_flag_mask & 0b100000 == 1);
+        flag.remove(CpuFlags::BREAK);
+        flag.insert(CpuFlags::UNUSED);
 
         self.stack_push(flag.bits());
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
@@ -696,55 +793,14 @@ impl<'a> CPU<'a> {
 
     // Get absolute address and page cross flag
     pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
-        match mode {
-            AddressingMode::ZeroPage => (self.mem_read(addr) as u16, false),
-
-            AddressingMode::Absolute => (self.mem_read_u16(addr), false),
-
-            AddressingMode::ZeroPageX => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                (addr, false)
-            }
-            AddressingMode::ZeroPageY => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                (addr, false)
-            }
-
-            AddressingMode::AbsoluteX => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_x as u16);
-                (addr, page_cross(base, addr))
-            }
-            AddressingMode::AbsoluteY => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_y as u16);
-                (addr, page_cross(base, addr))
-            }
-
-            AddressingMode::IndirectX => {
-                let base = self.mem_read(addr);
-
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                ((hi as u16) << 8 | (lo as u16), false)
-            }
-            AddressingMode::IndirectY => {
-                let base = self.mem_read(addr);
-
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                (deref, page_cross(deref, deref_base))
-            }
+        let (x, y) = (self.register_x, self.register_y);
+        effective_address(mode, addr, x, y, |addr| self.bus.mem_read(addr))
+    }
 
-            _ => {
-                panic!("mode {:?} is not supported", mode);
-            }
-        }
+    // Same as get_absolute_address but without side effects, for tooling
+    pub fn peek_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
+        let (x, y) = (self.register_x, self.register_y);
+        effective_address(mode, addr, x, y, |addr| self.bus.peek(addr)).0
     }
 
     fn and_with_register_a(&mut self, data: u8) {
@@ -1178,7 +1234,7 @@ mod tests {
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
-        let bus = Bus::new(test::test_rom());
+        let bus = Bus::new(test::test_rom(), |_, _| {});
         let mut cpu = CPU::new(bus);
         cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
         assert_eq!(cpu.register_a, 5);
@@ -1188,17 +1244,19 @@ mod tests {
 
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
-        let bus = Bus::new(test::test_rom());
+        let bus = Bus::new(test::test_rom(), |_, _| {});
         let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xaa, 0x00]);
+        cpu.reset();
         cpu.register_a = 10;
-        cpu.load_and_run(vec![0xaa, 0x00]);
+        cpu.run();
 
         assert_eq!(cpu.register_x, 10)
     }
 
     #[test]
     fn test_5_ops_working_together() {
-        let bus = Bus::new(test::test_rom());
+        let bus = Bus::new(test::test_rom(), |_, _| {});
         let mut cpu = CPU::new(bus);
         cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
 
@@ -1207,17 +1265,19 @@ mod tests {
 
     #[test]
     fn test_inx_overflow() {
-        let bus = Bus::new(test::test_rom());
+        let bus = Bus::new(test::test_rom(), |_, _| {});
         let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xe8, 0xe8, 0x00]);
+        cpu.reset();
         cpu.register_x = 0xff;
-        cpu.load_and_run(vec![0xe8, 0xe8, 0x00]);
+        cpu.run();
 
         assert_eq!(cpu.register_x, 1)
     }
 
     #[test]
     fn test_lda_from_memory() {
-        let bus = Bus::new(test::test_rom());
+        let bus = Bus::new(test::test_rom(), |_, _| {});
         let mut cpu = CPU::new(bus);
         cpu.mem_write(0x10, 0x55);
 
@@ -1225,4 +1285,21 @@ mod tests {
 
         assert_eq!(cpu.register_a, 0x55);
     }
+
+    #[test]
+    fn test_interrupt_pushes_flags_and_takes_7_cycles() {
+        let bus = Bus::new(test::test_rom(), |_, _| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        for interrupt in [interrupt::NMI, interrupt::IRQ] {
+            cpu.status = CpuFlags::from_bits_truncate(0b1001_0001);
+            let stack = STACK + cpu.stack_pointer as u16;
+            let cycles = cpu.bus.cycles();
+            cpu.interrupt(interrupt);
+
+            assert_eq!(cpu.mem_read(stack - 2), 0b1010_0001);
+            assert_eq!(cpu.bus.cycles() - cycles, 7);
+            assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        }
+    }
 }
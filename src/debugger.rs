@@ -0,0 +1,785 @@
+use crate::bus::Access;
+use crate::bus::Bus;
+use crate::cli::parse_addr;
+use crate::cpu::CpuFlags;
+use crate::cpu::CPU;
+use crate::disasm::{Instruction, Labels};
+use crate::ram_search::{Comparison, RamSearch, Region, ValueType, Watch};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+
+const HELP: &str = "\
+s [n]            step n instructions
+n                step over JSR
+o                step out of the current subroutine (until RTS/RTI)
+c                continue
+g <addr>         run to address
+b <addr>         add PC breakpoint        bd <addr>  delete breakpoint
+w <addr> [r|w|rw] add watchpoint          wd <addr>  delete watchpoint
+l                list breakpoints and watchpoints
+r                show registers
+r <reg> <value>  set register (a, x, y, sp, pc, p)
+f <flag> <0|1>   set flag (n, v, u, b, d, i, z, c)
+m <addr> [len]   hexdump memory
+e <addr> <bytes> edit memory
+d [addr] [n]     disassemble (defaults to around PC)
+ch               list cheats              ch add <code> [description]
+ch on|off|del <n> enable, disable or delete a cheat
+rs new [region] [type] start a RAM search (ram, prgram, vram, palette, oam; 8, 16, s8, s16)
+rs eq|ch|inc|dec keep unchanged, changed, increased or decreased values
+rs = <value>     keep a value         rs  show results    rs snap  take a new snapshot
+rw <addr> [type] [region] watch a value   rw  show watches    rw del <n>
+q                quit
+<addr> is hex ($C000, 0xC000, C000) or a symbol name";
+
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    Step(usize),
+    // Run until PC reaches `addr` (with the stack back at `sp` for step over)
+    RunTo { addr: u16, sp: Option<u8> },
+    // Run until a RTS/RTI pops the stack above `sp`
+    StepOut { sp: u8 },
+}
+
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: BTreeSet<u16>,
+    last_opcode: u8,
+    last_command: String,
+    quit: bool,
+    labels: Labels,
+    ram_search: Option<RamSearch>,
+    ram_watches: Vec<Watch>,
+}
+
+// Candidates printed after a RAM search step
+const MAX_SEARCH_RESULTS: usize = 16;
+
+// Decode the instruction at `addr` without side effects, returns the text and its length
+pub fn disassemble(bus: &Bus, addr: u16, labels: &Labels) -> (String, u16) {
+    let bytes: Vec<u8> = (0..3).map(|i| bus.peek(addr.wrapping_add(i))).collect();
+    match Instruction::decode(&bytes, addr) {
+        Some(ins) => (ins.format(labels), ins.size()),
+        None => (format!(".byte ${:02X}", bytes[0]), 1),
+    }
+}
+
+fn parse_byte(value: &str) -> Result<u8, String> {
+    let digits = value.trim_start_matches('$').trim_start_matches("0x");
+    u8::from_str_radix(digits, 16).map_err(|_| format!("Invalid byte: {}", value))
+}
+
+// Search values are decimal unless written as hex ($10, 0x10)
+fn parse_value(value: &str) -> Result<i32, String> {
+    let err = || format!("Invalid value: {}", value);
+    match value.strip_prefix('$').or_else(|| value.strip_prefix("0x")) {
+        Some(digits) => i32::from_str_radix(digits, 16).map_err(|_| err()),
+        None => value.parse().map_err(|_| err()),
+    }
+}
+
+// `[region] [type]` in any order, internal RAM and unsigned bytes by default
+fn region_and_type(args: &[&str]) -> Result<(Region, ValueType), String> {
+    let mut region = Region::Ram;
+    let mut value_type = ValueType {
+        word: false,
+        signed: false,
+    };
+    for arg in args {
+        match arg.parse() {
+            Ok(parsed) => value_type = parsed,
+            Err(_) => region = arg.parse()?,
+        }
+    }
+    Ok((region, value_type))
+}
+
+fn flag_from_name(name: &str) -> Result<CpuFlags, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "n" => Ok(CpuFlags::NEGATIVE),
+        "v" => Ok(CpuFlags::OVERFLOW),
+        "u" => Ok(CpuFlags::UNUSED),
+        "b" => Ok(CpuFlags::BREAK),
+        "d" => Ok(CpuFlags::DECIMAL_MODE),
+        "i" => Ok(CpuFlags::INTERRUPT_DISABLE),
+        "z" => Ok(CpuFlags::ZERO),
+        "c" => Ok(CpuFlags::CARRY),
+        _ => Err(format!("Unknown flag: {}", name)),
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            mode: Mode::Running,
+            breakpoints: BTreeSet::new(),
+            last_opcode: 0,
+            last_command: String::new(),
+            quit: false,
+            labels: Labels::new(),
+            ram_search: None,
+            ram_watches: vec![],
+        }
+    }
+
+    // Symbol names to show in disassembly and accept in place of addresses
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = labels;
+    }
+
+    // Address argument: a symbol name or a hex address
+    fn addr(&self, value: &str) -> Result<u16, String> {
+        match self.labels.iter().find(|(_, name)| name.as_str() == value) {
+            Some((addr, _)) => Ok(*addr),
+            None => parse_addr(value),
+        }
+    }
+
+    // `$C000 (reset)` when the address has a name
+    fn describe(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(name) => format!("${:04X} ({})", addr, name),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    // Called before every instruction. Decides whether execution should stop here
+    // and, if so, runs the REPL on stdin until a command resumes execution.
+    pub fn hook(&mut self, cpu: &mut CPU) {
+        if let Some(reason) = self.check_stop(cpu) {
+            if !reason.is_empty() {
+                println!("{}", reason);
+            }
+            self.mode = Mode::Paused;
+        }
+
+        if self.mode == Mode::Paused {
+            println!("{}", self.location(cpu));
+            if !self.ram_watches.is_empty() {
+                println!("{}", self.watch_list(cpu));
+            }
+            self.repl(cpu, &mut std::io::stdin().lock(), &mut std::io::stdout());
+        }
+
+        self.last_opcode = cpu.bus.peek(cpu.program_counter);
+    }
+
+    // Set by `q`, the frontend stops the emulator after saving
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    fn check_stop(&mut self, cpu: &mut CPU) -> Option<String> {
+        let pc = cpu.program_counter;
+
+        if let Some(hit) = cpu.bus.take_watch_hit() {
+            let access = match hit.access {
+                Access::Read => "read",
+                Access::Write => "write",
+            };
+            return Some(format!(
+                "Watchpoint: {} ${:04X} = {:02X}",
+                access, hit.addr, hit.value
+            ));
+        }
+
+        match self.mode {
+            Mode::Step(n) if n <= 1 => return Some(String::new()),
+            Mode::Step(n) => self.mode = Mode::Step(n - 1),
+            Mode::RunTo { addr, sp } => {
+                if pc == addr && sp.is_none_or(|sp| sp == cpu.stack_pointer) {
+                    return Some(String::new());
+                }
+            }
+            Mode::StepOut { sp } => {
+                let returned = self.last_opcode == RTS || self.last_opcode == RTI;
+                if returned && cpu.stack_pointer > sp {
+                    return Some(String::new());
+                }
+            }
+            Mode::Running | Mode::Paused => {}
+        }
+
+        if self.mode != Mode::Paused && self.breakpoints.contains(&pc) {
+            return Some(format!("Breakpoint at {}", self.describe(pc)));
+        }
+        None
+    }
+
+    fn repl<R: BufRead, W: Write>(&mut self, cpu: &mut CPU, input: &mut R, output: &mut W) {
+        while self.mode == Mode::Paused && !self.quit {
+            write!(output, "> ").unwrap();
+            output.flush().unwrap();
+
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                // stdin closed, nothing left to drive the session
+                self.quit = true;
+                break;
+            }
+
+            match self.command(cpu, &line) {
+                Ok(out) if out.is_empty() => {}
+                Ok(out) => writeln!(output, "{}", out).unwrap(),
+                Err(err) => writeln!(output, "error: {}", err).unwrap(),
+            }
+        }
+    }
+
+    fn location(&self, cpu: &CPU) -> String {
+        let (text, _) = disassemble(&cpu.bus, cpu.program_counter, &self.labels);
+        let location = format!(
+            "${:04X}: {:<16} {}",
+            cpu.program_counter,
+            text,
+            registers(cpu)
+        );
+        match self.labels.get(&cpu.program_counter) {
+            Some(name) => format!("{}:\n{}", name, location),
+            None => location,
+        }
+    }
+
+    // Execute one debugger command. An empty line repeats the previous command.
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let arg = |idx: usize| {
+            args.get(idx)
+                .copied()
+                .ok_or_else(|| format!("Missing argument for {}", args[0]))
+        };
+
+        match args.first().copied().unwrap_or("") {
+            "" => Ok(String::new()),
+            "h" | "help" => Ok(HELP.to_string()),
+            "q" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            "s" | "step" => {
+                let count = match args.get(1) {
+                    Some(n) => n.parse().map_err(|_| format!("Invalid count: {}", n))?,
+                    None => 1,
+                };
+                self.mode = Mode::Step(count);
+                Ok(String::new())
+            }
+            "n" | "next" => {
+                let pc = cpu.program_counter;
+                self.mode = if cpu.bus.peek(pc) == JSR {
+                    Mode::RunTo {
+                        addr: pc.wrapping_add(3),
+                        sp: Some(cpu.stack_pointer),
+                    }
+                } else {
+                    Mode::Step(1)
+                };
+                Ok(String::new())
+            }
+            "o" | "out" => {
+                self.mode = Mode::StepOut {
+                    sp: cpu.stack_pointer,
+                };
+                Ok(String::new())
+            }
+            "c" | "continue" => {
+                self.mode = Mode::Running;
+                Ok(String::new())
+            }
+            "g" | "goto" => {
+                self.mode = Mode::RunTo {
+                    addr: self.addr(arg(1)?)?,
+                    sp: None,
+                };
+                Ok(String::new())
+            }
+            "b" | "break" => {
+                let addr = self.addr(arg(1)?)?;
+                self.breakpoints.insert(addr);
+                Ok(format!("Breakpoint at {}", self.describe(addr)))
+            }
+            "bd" => {
+                let addr = self.addr(arg(1)?)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("No breakpoint at ${:04X}", addr));
+                }
+                Ok(String::new())
+            }
+            "w" | "watch" => {
+                let addr = self.addr(arg(1)?)?;
+                let kind = args.get(2).copied().unwrap_or("rw");
+                if !matches!(kind, "r" | "w" | "rw") {
+                    return Err(format!("Invalid watch kind: {}", kind));
+                }
+                if kind.contains('r') {
+                    cpu.bus.add_watchpoint(addr, Access::Read);
+                }
+                if kind.contains('w') {
+                    cpu.bus.add_watchpoint(addr, Access::Write);
+                }
+                Ok(format!("Watchpoint ({}) at {}", kind, self.describe(addr)))
+            }
+            "wd" => {
+                cpu.bus.remove_watchpoint(self.addr(arg(1)?)?);
+                Ok(String::new())
+            }
+            "l" | "list" => {
+                let mut out: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|addr| format!("break {}", self.describe(*addr)))
+                    .collect();
+                for (addr, access) in cpu.bus.watchpoints() {
+                    let kind = if access == Access::Read { "r" } else { "w" };
+                    out.push(format!("watch {} {}", self.describe(addr), kind));
+                }
+                Ok(out.join("\n"))
+            }
+            "r" | "regs" if args.len() == 1 => Ok(registers(cpu)),
+            "r" | "regs" => {
+                let value = arg(2)?;
+                match arg(1)?.to_ascii_lowercase().as_str() {
+                    "a" => cpu.register_a = parse_byte(value)?,
+                    "x" => cpu.register_x = parse_byte(value)?,
+                    "y" => cpu.register_y = parse_byte(value)?,
+                    "sp" => cpu.stack_pointer = parse_byte(value)?,
+                    "p" => cpu.status = CpuFlags::from_bits_truncate(parse_byte(value)?),
+                    "pc" => cpu.program_counter = self.addr(value)?,
+                    reg => return Err(format!("Unknown register: {}", reg)),
+                }
+                Ok(registers(cpu))
+            }
+            "f" | "flag" => {
+                let flag = flag_from_name(arg(1)?)?;
+                match arg(2)? {
+                    "0" => cpu.status.remove(flag),
+                    "1" => cpu.status.insert(flag),
+                    value => return Err(format!("Invalid flag value: {}", value)),
+                }
+                Ok(registers(cpu))
+            }
+            "m" | "mem" => {
+                let addr = self.addr(arg(1)?)?;
+                let len = match args.get(2) {
+                    Some(len) => parse_addr(len)?,
+                    None => 0x40,
+                };
+                Ok(hexdump(&cpu.bus, addr, len))
+            }
+            "e" | "edit" => {
+                let addr = self.addr(arg(1)?)?;
+                let bytes = args[2..]
+                    .iter()
+                    .map(|b| parse_byte(b))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    cpu.bus.poke(addr.wrapping_add(i as u16), *byte);
+                }
+                Ok(hexdump(&cpu.bus, addr, bytes.len() as u16))
+            }
+            "d" | "dis" => {
+                let count = match args.get(2) {
+                    Some(n) => n.parse().map_err(|_| format!("Invalid count: {}", n))?,
+                    None => 10,
+                };
+                let start = match args.get(1) {
+                    Some(addr) => self.addr(addr)?,
+                    None => start_before(&cpu.bus, cpu.program_counter),
+                };
+                Ok(self.disassembly(cpu, start, count))
+            }
+            "ch" | "cheat" => {
+                let cheats = cpu.bus.cheats_mut();
+                let index = || -> Result<usize, String> {
+                    let n = arg(2)?;
+                    n.parse()
+                        .map_err(|_| format!("Invalid cheat number: {}", n))
+                };
+                match args.get(1).copied().unwrap_or("") {
+                    "" => {}
+                    "add" => {
+                        cheats.add(arg(2)?, &args[3..].join(" "))?;
+                    }
+                    "on" => cheats.set_enabled(index()?, true)?,
+                    "off" => cheats.set_enabled(index()?, false)?,
+                    "del" => {
+                        cheats.remove(index()?)?;
+                    }
+                    cmd => return Err(format!("Unknown cheat command: {}", cmd)),
+                }
+                let lines: Vec<String> = cheats
+                    .list()
+                    .iter()
+                    .enumerate()
+                    .map(|(n, cheat)| {
+                        let state = if cheat.enabled { "on " } else { "off" };
+                        format!("#{} {} {:8} {}", n, state, cheat.code, cheat.description)
+                            .trim_end()
+                            .to_string()
+                    })
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            "rs" => {
+                if args.get(1) == Some(&"new") {
+                    let (region, value_type) = region_and_type(&args[2..])?;
+                    let search = RamSearch::new(&cpu.bus, region, value_type);
+                    let out = format!("Searching {} addresses", search.len());
+                    self.ram_search = Some(search);
+                    return Ok(out);
+                }
+
+                let search = self
+                    .ram_search
+                    .as_mut()
+                    .ok_or("No RAM search, start one with rs new")?;
+                let comparison = match args.get(1).copied().unwrap_or("") {
+                    "" => None,
+                    "snap" => {
+                        search.snapshot(&cpu.bus);
+                        return Ok(String::new());
+                    }
+                    "eq" => Some(Comparison::Equal),
+                    "ch" => Some(Comparison::Changed),
+                    "inc" => Some(Comparison::Increased),
+                    "dec" => Some(Comparison::Decreased),
+                    "=" => Some(Comparison::Value(parse_value(arg(2)?)?)),
+                    cmd => return Err(format!("Unknown search command: {}", cmd)),
+                };
+                if let Some(comparison) = comparison {
+                    search.filter(&cpu.bus, comparison);
+                }
+
+                let results = search.results(&cpu.bus);
+                let mut lines = vec![format!("{} candidates", results.len())];
+                for result in results.iter().take(MAX_SEARCH_RESULTS) {
+                    lines.push(format!(
+                        "{}: {} (was {})",
+                        self.describe(result.addr),
+                        result.current,
+                        result.previous
+                    ));
+                }
+                Ok(lines.join("\n"))
+            }
+            "rw" => match args.get(1).copied().unwrap_or("") {
+                "" => Ok(self.watch_list(cpu)),
+                "del" => {
+                    let n = arg(2)?;
+                    let index: usize = n.parse().map_err(|_| format!("Invalid watch: {}", n))?;
+                    if index >= self.ram_watches.len() {
+                        return Err(format!("No watch #{}", index));
+                    }
+                    self.ram_watches.remove(index);
+                    Ok(self.watch_list(cpu))
+                }
+                addr => {
+                    let addr = self.addr(addr)?;
+                    let (region, value_type) = region_and_type(&args[2..])?;
+                    self.ram_watches.push(Watch::new(region, addr, value_type)?);
+                    Ok(self.watch_list(cpu))
+                }
+            },
+            cmd => Err(format!("Unknown command: {} (h for help)", cmd)),
+        }
+    }
+
+    fn watch_list(&self, cpu: &CPU) -> String {
+        self.ram_watches
+            .iter()
+            .enumerate()
+            .map(|(n, watch)| {
+                format!(
+                    "#{} {:?} {} = {}",
+                    n,
+                    watch.region,
+                    self.describe(watch.addr),
+                    watch.value(&cpu.bus)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn disassembly(&self, cpu: &CPU, start: u16, count: usize) -> String {
+        let mut addr = start;
+        let mut lines = vec![];
+        for _ in 0..count {
+            if let Some(name) = self.labels.get(&addr) {
+                lines.push(format!("{}:", name));
+            }
+            let (text, len) = disassemble(&cpu.bus, addr, &self.labels);
+            let bytes = (0..len)
+                .map(|i| format!("{:02X}", cpu.bus.peek(addr.wrapping_add(i))))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let marker = match (
+                addr == cpu.program_counter,
+                self.breakpoints.contains(&addr),
+            ) {
+                (true, _) => '>',
+                (false, true) => '*',
+                (false, false) => ' ',
+            };
+            lines.push(format!("{} {:04X}  {:8}  {}", marker, addr, bytes, text));
+            addr = addr.wrapping_add(len);
+        }
+        lines.join("\n")
+    }
+}
+
+// Find an address a few instructions before `pc` that decodes into an
+// instruction stream landing exactly on `pc`
+fn start_before(bus: &Bus, pc: u16) -> u16 {
+    const MAX_INSTRUCTIONS: usize = 4;
+
+    for back in (1..=MAX_INSTRUCTIONS as u16 * 3).rev() {
+        let start = pc.wrapping_sub(back);
+        let mut addr = start;
+        let mut count = 0;
+        while addr != pc && count < MAX_INSTRUCTIONS && pc.wrapping_sub(addr) <= back {
+            addr = addr.wrapping_add(disassemble(bus, addr, &Labels::new()).1);
+            count += 1;
+        }
+        if addr == pc {
+            return start;
+        }
+    }
+    pc
+}
+
+fn registers(cpu: &CPU) -> String {
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        cpu.program_counter
+    )
+}
+
+fn hexdump(bus: &Bus, start: u16, len: u16) -> String {
+    let mut lines = vec![];
+    let mut offset = 0u16;
+    while offset < len {
+        let row = offset.wrapping_add(start);
+        let count = (len - offset).min(16);
+        let bytes = (0..count)
+            .map(|i| format!("{:02X}", bus.peek(row.wrapping_add(i))))
+            .collect::<Vec<String>>()
+            .join(" ");
+        lines.push(format!("{:04X}: {}", row, bytes));
+        offset += count;
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    // JSR $0010; INX; BRK ... $0010: INY; INY; RTS
+    fn test_cpu<'a>() -> CPU<'a> {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        for (i, byte) in [0x20, 0x10, 0x00, 0xe8, 0x00].iter().enumerate() {
+            bus.mem_write(i as u16, *byte);
+        }
+        for (i, byte) in [0xc8, 0xc8, 0x60].iter().enumerate() {
+            bus.mem_write(0x10 + i as u16, *byte);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0;
+        cpu
+    }
+
+    // Run under the debugger until it pauses again
+    fn run(debugger: &mut Debugger, cpu: &mut CPU) {
+        while cpu.step() {
+            if debugger.check_stop(cpu).is_some() {
+                debugger.pause();
+                return;
+            }
+            debugger.last_opcode = cpu.bus.peek(cpu.program_counter);
+        }
+    }
+
+    fn resume(debugger: &mut Debugger, cpu: &mut CPU, command: &str) {
+        debugger.command(cpu, command).unwrap();
+        debugger.last_opcode = cpu.bus.peek(cpu.program_counter);
+        run(debugger, cpu);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let cpu = test_cpu();
+        assert_eq!(
+            disassemble(&cpu.bus, 0x00, &Labels::new()),
+            ("JSR $0010".to_string(), 3)
+        );
+        assert_eq!(
+            disassemble(&cpu.bus, 0x03, &Labels::new()),
+            ("INX".to_string(), 1)
+        );
+        assert_eq!(start_before(&cpu.bus, 0x12), 0x0e);
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+
+        resume(&mut debugger, &mut cpu, "s");
+        assert_eq!(cpu.program_counter, 0x10);
+
+        let mut cpu = test_cpu();
+        resume(&mut debugger, &mut cpu, "n");
+        assert!(debugger.is_paused());
+        assert_eq!(cpu.program_counter, 0x03);
+        assert_eq!(cpu.register_y, 2);
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        resume(&mut debugger, &mut cpu, "s 2");
+        assert_eq!(cpu.program_counter, 0x11);
+
+        resume(&mut debugger, &mut cpu, "o");
+        assert_eq!(cpu.program_counter, 0x03);
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut cpu, "b 0012").unwrap();
+        resume(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.program_counter, 0x12);
+
+        debugger.command(&mut cpu, "bd 0012").unwrap();
+        let mut cpu = test_cpu();
+        debugger.command(&mut cpu, "w 01fd w").unwrap();
+        resume(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.program_counter, 0x10);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.set_labels(Labels::from([(0x10, "bump".to_string())]));
+
+        assert_eq!(
+            debugger.command(&mut cpu, "b bump"),
+            Ok("Breakpoint at $0010 (bump)".to_string())
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, "d 0 1"),
+            Ok("> 0000  20 10 00  JSR bump".to_string())
+        );
+        resume(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.program_counter, 0x10);
+        assert!(debugger.location(&cpu).starts_with("bump:\n$0010: INY"));
+    }
+
+    #[test]
+    fn test_edit_registers_and_memory() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut cpu, "r a 42").unwrap();
+        debugger.command(&mut cpu, "f c 1").unwrap();
+        debugger.command(&mut cpu, "e 0200 de ad").unwrap();
+
+        assert_eq!(cpu.register_a, 0x42);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert_eq!(cpu.mem_read(0x201), 0xad);
+        assert_eq!(
+            debugger.command(&mut cpu, "m 0200 2"),
+            Ok("0200: DE AD".to_string())
+        );
+        assert!(debugger.command(&mut cpu, "r q 1").is_err());
+    }
+
+    #[test]
+    fn test_cheat_commands() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger
+            .command(&mut cpu, "ch add sxiopo Infinite lives")
+            .unwrap();
+        debugger.command(&mut cpu, "ch add 0000756A").unwrap();
+        assert_eq!(
+            debugger.command(&mut cpu, "ch off 0"),
+            Ok("#0 off SXIOPO   Infinite lives\n#1 on  0000756A".to_string())
+        );
+        assert!(!cpu.bus.cheats().list()[0].enabled);
+        debugger.command(&mut cpu, "ch del 1").unwrap();
+        assert_eq!(cpu.bus.cheats().list().len(), 1);
+
+        assert!(debugger.command(&mut cpu, "ch add XYZ").is_err());
+        assert!(debugger.command(&mut cpu, "ch on 7").is_err());
+        assert!(debugger.command(&mut cpu, "ch flip 0").is_err());
+    }
+
+    #[test]
+    fn test_ram_search_commands() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        assert!(debugger.command(&mut cpu, "rs ch").is_err());
+        assert_eq!(
+            debugger.command(&mut cpu, "rs new ram 8"),
+            Ok("Searching 2048 addresses".to_string())
+        );
+        cpu.mem_write(0x0300, 5);
+        assert_eq!(
+            debugger.command(&mut cpu, "rs inc"),
+            Ok("1 candidates\n$0300: 5 (was 0)".to_string())
+        );
+        cpu.mem_write(0x0300, 4);
+        assert_eq!(
+            debugger.command(&mut cpu, "rs = $4"),
+            Ok("1 candidates\n$0300: 4 (was 5)".to_string())
+        );
+
+        debugger.command(&mut cpu, "rw 0300").unwrap();
+        assert_eq!(
+            debugger.command(&mut cpu, "rw 3F00 palette s8"),
+            Ok("#0 Ram $0300 = 4\n#1 Palette $3F00 = 0".to_string())
+        );
+        debugger.command(&mut cpu, "rw del 0").unwrap();
+        assert_eq!(debugger.ram_watches.len(), 1);
+        assert!(debugger.command(&mut cpu, "rw 0300 rom").is_err());
+        assert!(debugger.command(&mut cpu, "rs new oam 32").is_err());
+    }
+}
@@ -0,0 +1,572 @@
+use crate::cartridge::Rom;
+use crate::cdl::{self, CodeDataLog};
+use crate::cpu::AddressingMode;
+use crate::opcodes::{self, OpCode};
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+pub type Labels = HashMap<u16, String>;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+const BANK_SIZE: usize = 0x4000;
+const BYTES_PER_LINE: usize = 8;
+
+pub struct Instruction {
+    pub addr: u16,
+    pub op: &'static OpCode,
+    // Raw operand: the byte for 2 byte instructions, the word for 3 byte ones
+    pub operand: u16,
+}
+
+impl Instruction {
+    // Decode the instruction at the start of `bytes`, located at `addr`.
+    // Returns None for unknown opcodes or when the operand is cut off.
+    pub fn decode(bytes: &[u8], addr: u16) -> Option<Instruction> {
+        let op = *opcodes::OPCODES_MAP.get(bytes.first()?)?;
+        if bytes.len() < op.len as usize {
+            return None;
+        }
+        let operand = match op.len {
+            2 => bytes[1] as u16,
+            3 => u16::from_le_bytes([bytes[1], bytes[2]]),
+            _ => 0,
+        };
+        Some(Instruction { addr, op, operand })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.op.len as u16
+    }
+
+    pub fn is_official(&self) -> bool {
+        !self.op.name.starts_with('*')
+    }
+
+    fn is_branch(&self) -> bool {
+        self.op.len == 2 && matches!(self.op.mode, AddressingMode::NoneAddressing)
+    }
+
+    // Jump, call or branch destination
+    pub fn target(&self) -> Option<u16> {
+        match self.op.code {
+            0x20 | 0x4c => Some(self.operand),
+            _ if self.is_branch() => Some(
+                self.addr
+                    .wrapping_add(2)
+                    .wrapping_add((self.operand as u8 as i8) as u16),
+            ),
+            _ => None,
+        }
+    }
+
+    // Whether execution can continue with the next instruction
+    pub fn falls_through(&self) -> bool {
+        !matches!(self.op.code, 0x00 | 0x40 | 0x4c | 0x60 | 0x6c)
+    }
+
+    // Memory address the operand refers to, if any
+    pub fn referenced_addr(&self) -> Option<u16> {
+        match self.op.mode {
+            AddressingMode::Immediate => None,
+            AddressingMode::NoneAddressing if self.op.len == 1 => None,
+            AddressingMode::NoneAddressing => self.target().or(Some(self.operand)),
+            _ => Some(self.operand),
+        }
+    }
+
+    pub fn format(&self, labels: &Labels) -> String {
+        self.format_with(labels, false)
+    }
+
+    // With `ca65` set, absolute addressing of zero page addresses gets an `a:`
+    // prefix so the assembler doesn't shrink it to zero page addressing
+    fn format_with(&self, labels: &Labels, ca65: bool) -> String {
+        let zp = |addr: u16| {
+            labels
+                .get(&addr)
+                .cloned()
+                .unwrap_or_else(|| format!("${:02X}", addr))
+        };
+        let abs = |addr: u16| {
+            let name = labels
+                .get(&addr)
+                .cloned()
+                .unwrap_or_else(|| format!("${:04X}", addr));
+            if ca65 && addr < 0x100 {
+                format!("a:{}", name)
+            } else {
+                name
+            }
+        };
+
+        let operand = match (self.op.len, &self.op.mode) {
+            (1, _) => match self.op.code {
+                0x0a | 0x4a | 0x2a | 0x6a => "A".to_string(),
+                _ => String::new(),
+            },
+            (_, AddressingMode::Immediate) => format!("#${:02X}", self.operand),
+            (_, AddressingMode::ZeroPage) => zp(self.operand),
+            (_, AddressingMode::ZeroPageX) => format!("{},X", zp(self.operand)),
+            (_, AddressingMode::ZeroPageY) => format!("{},Y", zp(self.operand)),
+            (_, AddressingMode::Absolute) => abs(self.operand),
+            (_, AddressingMode::AbsoluteX) => format!("{},X", abs(self.operand)),
+            (_, AddressingMode::AbsoluteY) => format!("{},Y", abs(self.operand)),
+            (_, AddressingMode::IndirectX) => format!("({},X)", zp(self.operand)),
+            (_, AddressingMode::IndirectY) => format!("({}),Y", zp(self.operand)),
+            (_, AddressingMode::NoneAddressing) if self.op.code == 0x6c => {
+                let name = labels
+                    .get(&self.operand)
+                    .cloned()
+                    .unwrap_or_else(|| format!("${:04X}", self.operand));
+                format!("({})", name)
+            }
+            (_, AddressingMode::NoneAddressing) => {
+                let target = self.target().unwrap_or(self.operand);
+                labels
+                    .get(&target)
+                    .cloned()
+                    .unwrap_or_else(|| format!("${:04X}", target))
+            }
+        };
+
+        format!("{} {}", self.op.name, operand).trim().to_string()
+    }
+}
+
+// Recursive descent from the entry points, returns the addresses of all
+// instructions reachable as code. With CDL flags for `data`, every logged run of
+// code is an entry point too and bytes only ever read as data are never decoded.
+pub fn find_code(data: &[u8], base: u16, entries: &[u16], cdl: Option<&[u8]>) -> BTreeSet<u16> {
+    let mut code_bytes = vec![false; data.len()];
+    let mut starts = BTreeSet::new();
+    let mut pending: Vec<u16> = entries.to_vec();
+
+    let is_code = |offset: usize| cdl.is_some_and(|f| f[offset] & cdl::PRG_CODE != 0);
+    let data_only = |offset: usize| {
+        cdl.is_some_and(|f| f[offset] & (cdl::PRG_CODE | cdl::PRG_DATA) == cdl::PRG_DATA)
+    };
+    let offset_of = |addr: u16| {
+        let offset = addr.wrapping_sub(base) as usize;
+        (addr >= base && offset < data.len()).then_some(offset)
+    };
+    let mut cdl_cursor = 0;
+
+    loop {
+        let addr = match pending.pop() {
+            Some(addr) => addr,
+            None => {
+                // resume at the next logged code byte nothing has reached yet
+                while cdl_cursor < data.len() && (code_bytes[cdl_cursor] || !is_code(cdl_cursor)) {
+                    cdl_cursor += 1;
+                }
+                if cdl_cursor == data.len() {
+                    break;
+                }
+                cdl_cursor += 1;
+                base.wrapping_add(cdl_cursor as u16 - 1)
+            }
+        };
+        let offset = match offset_of(addr) {
+            Some(offset) if !code_bytes[offset] => offset,
+            _ => continue,
+        };
+        let ins = match Instruction::decode(&data[offset..], addr) {
+            Some(ins) => ins,
+            None => continue,
+        };
+        let end = offset + ins.size() as usize;
+        if (offset..end).any(data_only) {
+            continue;
+        }
+        if code_bytes[offset..end].iter().any(|b| *b) {
+            // overlaps an instruction decoded through another path
+            continue;
+        }
+
+        code_bytes[offset..end].iter_mut().for_each(|b| *b = true);
+        starts.insert(addr);
+
+        if let Some(target) = ins.target() {
+            pending.push(target);
+        }
+        if ins.falls_through() {
+            pending.push(addr.wrapping_add(ins.size()));
+        }
+    }
+    starts
+}
+
+// Disassemble `data` loaded at `base` into ca65 source. Code is found by
+// recursive descent from `entries`, everything else is emitted as `.byte` data.
+pub fn listing(
+    data: &[u8],
+    base: u16,
+    entries: &[(u16, &str)],
+    labels: &Labels,
+    cdl: Option<&[u8]>,
+) -> String {
+    let mut out = String::new();
+    writeln!(out, ".setcpu \"6502\"").unwrap();
+    writeln!(out).unwrap();
+    out + &listing_body(data, base, entries, labels, cdl)
+}
+
+// A listing without the CPU directive, so several can share one file
+fn listing_body(
+    data: &[u8],
+    base: u16,
+    entries: &[(u16, &str)],
+    labels: &Labels,
+    cdl: Option<&[u8]>,
+) -> String {
+    let entry_addrs: Vec<u16> = entries.iter().map(|(addr, _)| *addr).collect();
+    let code = find_code(data, base, &entry_addrs, cdl);
+    let end = base as usize + data.len();
+    let in_range = |addr: u16| (addr as usize) >= base as usize && (addr as usize) < end;
+
+    let decode = |addr: u16| {
+        let offset = (addr - base) as usize;
+        Instruction::decode(&data[offset..], addr).unwrap()
+    };
+
+    // Name every referenced address inside the range that a label can be placed on
+    let mut all_labels = labels.clone();
+    for (addr, name) in entries {
+        if !all_labels.values().any(|label| label == name) {
+            all_labels.entry(*addr).or_insert_with(|| name.to_string());
+        }
+    }
+    for addr in &code {
+        if let Some(target) = decode(*addr).referenced_addr() {
+            let placeable = code.contains(&target) || !covered_by_code(&code, target, &decode);
+            if in_range(target) && placeable {
+                all_labels
+                    .entry(target)
+                    .or_insert_with(|| format!("L_{:04X}", target));
+            }
+        }
+    }
+
+    let mut out = String::new();
+
+    // Labels outside this block become constants
+    let external: BTreeMap<&u16, &String> = all_labels
+        .iter()
+        .filter(|(addr, _)| !in_range(**addr))
+        .collect();
+    if !external.is_empty() {
+        for (addr, name) in external {
+            writeln!(out, "{} = ${:04X}", name, addr).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+
+    writeln!(out, ".org ${:04X}", base).unwrap();
+
+    let mut offset = 0;
+    let mut data_line: Vec<u8> = vec![];
+    let mut data_start = base;
+    while offset < data.len() {
+        let addr = base.wrapping_add(offset as u16);
+        let label = all_labels.get(&addr);
+        let is_code = code.contains(&addr);
+        let is_vectors = addr == NMI_VECTOR && data.len() - offset == 6 && !is_code;
+
+        if !data_line.is_empty()
+            && (label.is_some() || is_code || is_vectors || data_line.len() == BYTES_PER_LINE)
+        {
+            write_data(&mut out, data_start, &data_line);
+            data_line.clear();
+        }
+
+        if let Some(label) = label {
+            writeln!(out, "{}:", label).unwrap();
+        }
+
+        if is_vectors {
+            let names: Vec<String> = (0..3)
+                .map(|i| {
+                    let offset = offset + i * 2;
+                    let target = u16::from_le_bytes([data[offset], data[offset + 1]]);
+                    all_labels
+                        .get(&target)
+                        .cloned()
+                        .unwrap_or_else(|| format!("${:04X}", target))
+                })
+                .collect();
+            writeln!(
+                out,
+                "        .word {:<18}; {:04X}  vectors",
+                names.join(", "),
+                addr
+            )
+            .unwrap();
+            break;
+        } else if is_code {
+            let ins = decode(addr);
+            let bytes = &data[offset..offset + ins.size() as usize];
+            let hex = bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<String>>()
+                .join(" ");
+
+            if ins.is_official() {
+                let text = ins.format_with(&all_labels, true);
+                writeln!(out, "        {}; {:04X}  {}", pad(&text), addr, hex).unwrap();
+            } else {
+                // ca65 only accepts unofficial opcodes with .setcpu "6502X"
+                let bytes = bytes
+                    .iter()
+                    .map(|b| format!("${:02X}", b))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                let text = ins.format(&all_labels);
+                writeln!(
+                    out,
+                    "        {}; {:04X}  {}",
+                    pad(&format!(".byte {}", bytes)),
+                    addr,
+                    text
+                )
+                .unwrap();
+            }
+            offset += ins.size() as usize;
+        } else {
+            if data_line.is_empty() {
+                data_start = addr;
+            }
+            data_line.push(data[offset]);
+            offset += 1;
+        }
+    }
+    if !data_line.is_empty() {
+        write_data(&mut out, data_start, &data_line);
+    }
+    out
+}
+
+fn covered_by_code(code: &BTreeSet<u16>, addr: u16, decode: &dyn Fn(u16) -> Instruction) -> bool {
+    match code.range(..=addr).next_back() {
+        Some(start) => addr < start.wrapping_add(decode(*start).size()),
+        None => false,
+    }
+}
+
+// Pad to the comment column, keeping at least one space before it
+fn pad(text: &str) -> String {
+    format!("{:<23} ", text)
+}
+
+fn write_data(out: &mut String, addr: u16, bytes: &[u8]) {
+    let values = bytes
+        .iter()
+        .map(|b| format!("${:02X}", b))
+        .collect::<Vec<String>>()
+        .join(",");
+    writeln!(
+        out,
+        "        {}; {:04X}",
+        pad(&format!(".byte {}", values)),
+        addr
+    )
+    .unwrap();
+}
+
+fn read_vector(data: &[u8], base: u16, vector: u16) -> Option<u16> {
+    let offset = vector.checked_sub(base)? as usize;
+    Some(u16::from_le_bytes([
+        *data.get(offset)?,
+        *data.get(offset + 1)?,
+    ]))
+}
+
+// Disassemble the PRG ROM as mapped at $8000-$FFFF. ROMs larger than 32 KiB are
+// split into 16 KiB banks with the last one fixed at $C000, which is where the
+// vectors are followed from. Each bank gets its own segment and scope, as
+// banks share addresses and so label names.
+pub fn disassemble_rom(rom: &Rom, symbols: &SymbolTable, cdl: Option<&CodeDataLog>) -> String {
+    let prg = &rom.prg_rom;
+    let banks: Vec<(u16, &[u8])> = if prg.len() <= 2 * BANK_SIZE {
+        vec![((0x10000 - prg.len()) as u16, &prg[..])]
+    } else {
+        prg.chunks(BANK_SIZE)
+            .enumerate()
+            .map(|(i, bank)| {
+                let last = (i + 1) * BANK_SIZE >= prg.len();
+                (if last { 0xC000 } else { 0x8000 }, bank)
+            })
+            .collect()
+    };
+
+    let mut out = String::new();
+    writeln!(out, ".setcpu \"6502\"").unwrap();
+    for (i, (base, data)) in banks.iter().enumerate() {
+        let mut entries = vec![];
+        for (vector, name) in [
+            (RESET_VECTOR, "reset"),
+            (NMI_VECTOR, "nmi"),
+            (IRQ_VECTOR, "irq"),
+        ] {
+            if let Some(addr) = read_vector(data, *base, vector) {
+                if !entries.iter().any(|(a, _)| *a == addr) {
+                    entries.push((addr, name));
+                }
+            }
+        }
+
+        let labels = symbols.bank_labels(i * BANK_SIZE, *base, data.len());
+        let offset = i * BANK_SIZE;
+        let flags = cdl.map(|cdl| &cdl.prg()[offset..offset + data.len()]);
+        let body = listing_body(data, *base, &entries, &labels, flags);
+        if banks.len() > 1 {
+            writeln!(out, "\n.segment \"BANK{}\"\n.scope bank{}\n", i, i).unwrap();
+            out += &body;
+            writeln!(out, ".endscope").unwrap();
+        } else {
+            writeln!(out).unwrap();
+            out += &body;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_and_format() {
+        let labels = Labels::from([(0x2002, "PPUSTATUS".to_string())]);
+
+        let ins = Instruction::decode(&[0xad, 0x02, 0x20], 0x8000).unwrap();
+        assert_eq!(ins.format(&labels), "LDA PPUSTATUS");
+        assert_eq!(ins.referenced_addr(), Some(0x2002));
+
+        let ins = Instruction::decode(&[0xd0, 0xfe], 0x8010).unwrap();
+        assert_eq!(ins.target(), Some(0x8010));
+        assert_eq!(ins.format(&Labels::new()), "BNE $8010");
+
+        let ins = Instruction::decode(&[0xb1, 0x10], 0x8000).unwrap();
+        assert_eq!(ins.format(&Labels::new()), "LDA ($10),Y");
+
+        let ins = Instruction::decode(&[0xad, 0x10, 0x00], 0x8000).unwrap();
+        assert_eq!(ins.format_with(&Labels::new(), true), "LDA a:$0010");
+
+        assert!(Instruction::decode(&[0xad, 0x10], 0x8000).is_none());
+    }
+
+    #[test]
+    fn test_find_code_follows_flow() {
+        // $8000: JSR $8007; JMP $8000; .byte $ff; $8007: RTS
+        let data = [0x20, 0x07, 0x80, 0x4c, 0x00, 0x80, 0xff, 0x60];
+        let code = find_code(&data, 0x8000, &[0x8000], None);
+        assert_eq!(
+            code.into_iter().collect::<Vec<u16>>(),
+            vec![0x8000, 0x8003, 0x8007]
+        );
+    }
+
+    #[test]
+    fn test_find_code_with_cdl() {
+        // $8000: RTS; $8001: INX (only reached through a jump table); $8002: LDA #$60
+        // where the CDL saw $8002 read as data
+        let data = [0x60, 0xe8, 0xa9, 0x60];
+        let flags = [cdl::PRG_CODE, cdl::PRG_CODE, cdl::PRG_DATA, 0];
+        let code = find_code(&data, 0x8000, &[0x8000], Some(&flags));
+        assert_eq!(code.into_iter().collect::<Vec<u16>>(), vec![0x8000, 0x8001]);
+
+        let flags = [cdl::PRG_CODE, 0, cdl::PRG_CODE, cdl::PRG_CODE];
+        let code = find_code(&data, 0x8000, &[0x8000], Some(&flags));
+        assert_eq!(code.into_iter().collect::<Vec<u16>>(), vec![0x8000, 0x8002]);
+    }
+
+    #[test]
+    fn test_listing() {
+        // reset: LDX #$00; loop: INX; BNE loop; JMP reset; data: $12 $34
+        let data = [0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0x4c, 0x00, 0xc0, 0x12, 0x34];
+        let labels = Labels::from([(0x0010, "counter".to_string())]);
+        let out = listing(&data, 0xC000, &[(0xC000, "reset")], &labels, None);
+
+        let expected = "\
+.setcpu \"6502\"
+
+counter = $0010
+
+.org $C000
+reset:
+        LDX #$00                ; C000  A2 00
+L_C002:
+        INX                     ; C002  E8
+        BNE L_C002              ; C003  D0 FD
+        JMP reset               ; C005  4C 00 C0
+        .byte $12,$34           ; C008
+";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut prg = vec![0xea; 0x4000];
+        prg[0] = 0x40; // RTI
+        prg[1] = 0x4c; // JMP $C001
+        prg[2] = 0x01;
+        prg[3] = 0xc0;
+        prg[0x3ffa..].copy_from_slice(&[0x00, 0xc0, 0x01, 0xc0, 0x00, 0xc0]);
+        let out = listing(
+            &prg,
+            0xC000,
+            &[(0xC001, "reset"), (0xC000, "nmi")],
+            &Labels::new(),
+            None,
+        );
+        assert!(out.contains("nmi:\n        RTI"));
+        assert!(out.ends_with("        .word nmi, reset, nmi   ; FFFA  vectors\n"));
+    }
+
+    #[test]
+    fn test_banks_have_no_duplicate_symbols() {
+        // 48 KiB, so two switchable banks at $8000 and the fixed one at $C000,
+        // each starting with the same loop: LDA counter; BNE loop; RTS
+        let code = [0xa5, 0x10, 0xd0, 0xfc, 0x60];
+        let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut flags = vec![0; 3 * BANK_SIZE];
+        for bank in 0..3 {
+            let mut prg = vec![0xea; BANK_SIZE];
+            prg[..code.len()].copy_from_slice(&code);
+            raw.extend(prg);
+            flags[bank * BANK_SIZE..][..code.len()].fill(cdl::PRG_CODE);
+        }
+        raw[16 + 3 * BANK_SIZE - 6..].copy_from_slice(&[0x00, 0xc0, 0x00, 0xc0, 0x00, 0xc0]);
+        let rom = Rom::new(&raw).unwrap();
+        let cdl = CodeDataLog::from_bytes(&flags, rom.prg_rom.len(), 0).unwrap();
+        let mut symbols = SymbolTable::new();
+        symbols.insert(crate::symbols::Location::Cpu(0x0010), "counter");
+
+        let out = disassemble_rom(&rom, &symbols, Some(&cdl));
+        assert_eq!(out.matches(".setcpu").count(), 1);
+        assert_eq!(out.matches(".segment").count(), 3);
+
+        // Every symbol is defined once per scope
+        let mut scopes: Vec<Vec<&str>> = vec![vec![]];
+        for line in out.lines() {
+            if line.starts_with(".scope") {
+                scopes.push(vec![]);
+            } else if let Some(name) = line
+                .strip_suffix(':')
+                .or_else(|| line.split_once(" = $").map(|(name, _)| name))
+            {
+                let scope = scopes.last_mut().unwrap();
+                assert!(!scope.contains(&name), "{} defined twice:\n{}", name, out);
+                scope.push(name);
+            }
+        }
+        assert_eq!(scopes.len(), 4);
+        assert!(scopes[1].contains(&"L_8000") && scopes[2].contains(&"L_8000"));
+        assert!(scopes[3].contains(&"reset"));
+    }
+}
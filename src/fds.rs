@@ -0,0 +1,724 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::game_db;
+use crate::patch;
+use crate::region::Region;
+use std::path::{Path, PathBuf};
+
+// Famicom Disk System: the RAM adapter plugs into the cartridge slot and
+// gives the BIOS ($E000-$FFFF) 32K of PRG RAM ($6000-$DFFF), 8K of CHR RAM,
+// a timer IRQ and a serial interface to the disk drive at $4020-$4033.
+//
+// .fds images hold each disk side as its blocks back to back. The drive
+// sees gaps between them, a start mark before each and a CRC after, so
+// sides are expanded to that on load and packed back on save.
+
+pub const FDS_TAG: [u8; 4] = *b"FDS\x1a";
+const HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+// The iNES mapper number for FDS conversions
+pub const FDS_MAPPER: u8 = 20;
+
+pub const BIOS_NAME: &str = "disksys.rom";
+pub const BIOS_SIZE: usize = 0x2000;
+const RAM_START: u16 = 0x6000;
+const RAM_SIZE: usize = 0x8000;
+
+// Gaps on the disk, in bytes: the lead-in before the first block and the
+// gap between blocks
+const LEAD_IN: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const START_MARK: u8 = 0x80;
+// Room left on an expanded side for gaps, CRCs and files the game saves
+const RAW_SIDE_SIZE: usize = 80000;
+
+// CPU cycles per byte at the drive's 96.4 kbit/s
+const BYTE_CYCLES: u32 = 149;
+// For the head to return to the start of the disk
+const HEAD_RETURN_CYCLES: u32 = 50000;
+// The disk stays out this long when switching sides, so the BIOS notices
+const SIDE_SWITCH_CYCLES: u32 = 1_000_000;
+
+// $4025 bits
+const MOTOR_ON: u8 = 1 << 0;
+const TRANSFER_RESET: u8 = 1 << 1;
+const READ_MODE: u8 = 1 << 2;
+const HORIZONTAL_MIRRORING: u8 = 1 << 3;
+const CRC_CONTROL: u8 = 1 << 4;
+const DISK_READY: u8 = 1 << 6;
+const DISK_IRQ: u8 = 1 << 7;
+
+pub fn is_fds(raw: &[u8]) -> bool {
+    raw.starts_with(&FDS_TAG) || raw.starts_with(DISK_INFO)
+}
+
+// Length of a block from its type byte, file data takes its size from the
+// file header block before it
+fn block_len(kind: u8, file_size: usize) -> Option<usize> {
+    match kind {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn file_size(header: &[u8]) -> usize {
+    u16::from_le_bytes([header[13], header[14]]) as usize
+}
+
+// CRC-16 as the RAM adapter computes it, bit by bit, LSB first
+fn crc_step(mut crc: u16, byte: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if byte & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// The CRC written after a block, covering its start mark
+fn block_crc(block: &[u8]) -> u16 {
+    let crc = block
+        .iter()
+        .fold(crc_step(0, START_MARK), |crc, b| crc_step(crc, *b));
+    crc_step(crc_step(crc, 0), 0)
+}
+
+// A side as the drive reads it
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN];
+    let (mut at, mut size) = (0, 0);
+    while let Some(block) = side
+        .get(at)
+        .and_then(|kind| block_len(*kind, size))
+        .and_then(|len| side.get(at..at + len))
+    {
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        raw.push(START_MARK);
+        raw.extend(block);
+        raw.extend(block_crc(block).to_le_bytes());
+        raw.extend([0; BLOCK_GAP]);
+        at += block.len();
+    }
+    raw.resize(raw.len().max(RAW_SIDE_SIZE), 0);
+    raw
+}
+
+// A side as it's stored in an .fds image
+fn strip_gaps(raw: &[u8]) -> Vec<u8> {
+    let mut side = vec![];
+    let (mut at, mut size) = (0, 0);
+    // Skip the gap to each start mark
+    while let Some(start) = raw
+        .get(at..)
+        .and_then(|rest| rest.iter().position(|b| *b != 0))
+    {
+        at += start;
+        if raw[at] != START_MARK {
+            break;
+        }
+        let Some(block) = raw
+            .get(at + 1)
+            .and_then(|kind| block_len(*kind, size))
+            .and_then(|len| raw.get(at + 1..at + 1 + len))
+        else {
+            break;
+        };
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        side.extend(block);
+        at += 1 + block.len() + 2;
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskImage {
+    pub sides: Vec<Vec<u8>>,
+    // fwNES header before the sides
+    header: bool,
+}
+
+impl DiskImage {
+    pub fn parse(raw: &[u8]) -> Result<Self, String> {
+        let header = raw.starts_with(&FDS_TAG);
+        let data = if header {
+            raw.get(HEADER_SIZE..).unwrap_or_default()
+        } else {
+            raw
+        };
+        let sides: Vec<Vec<u8>> = data
+            .chunks(SIDE_SIZE)
+            .map(|side| {
+                let mut side = side.to_vec();
+                side.resize(SIDE_SIZE, 0);
+                side
+            })
+            .collect();
+        if sides.is_empty() {
+            return Err("FDS image has no disk sides".to_string());
+        }
+        if let Some(n) = sides.iter().position(|side| !side.starts_with(DISK_INFO)) {
+            return Err(format!("FDS disk side {} has no disk info block", n + 1));
+        }
+        Ok(DiskImage { sides, header })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = vec![];
+        if self.header {
+            raw.extend(FDS_TAG);
+            raw.push(self.sides.len() as u8);
+            raw.resize(HEADER_SIZE, 0);
+        }
+        raw.extend(self.sides.concat());
+        raw
+    }
+}
+
+// A disk image as a cartridge: CHR RAM, and no PRG ROM until the BIOS is
+// loaded into it
+pub fn parse_rom(raw: &[u8]) -> Result<Rom, String> {
+    let disk = DiskImage::parse(raw)?;
+    let sides = disk.sides.concat();
+    Ok(Rom {
+        prg_rom: vec![],
+        chr_rom: vec![],
+        mapper: FDS_MAPPER,
+        mirroring: Mirroring::Horizontal,
+        expansion_device: 0,
+        region: Region::Ntsc,
+        battery: false,
+        crc32: game_db::crc32(&sides),
+        sha1: game_db::sha1(&sides),
+        corrections: vec![],
+        disk: Some(disk),
+    })
+}
+
+// The BIOS given, or disksys.rom next to the image or in the working
+// directory
+pub fn load_bios(rom_path: &Path, bios: Option<&Path>) -> Result<Vec<u8>, String> {
+    let path = match bios {
+        Some(path) => path.to_path_buf(),
+        None => [rom_path.with_file_name(BIOS_NAME), PathBuf::from(BIOS_NAME)]
+            .into_iter()
+            .find(|path| path.exists())
+            .ok_or_else(|| {
+                format!(
+                    "FDS games need the BIOS: put {} next to the image or pass --fds-bios",
+                    BIOS_NAME
+                )
+            })?,
+    };
+    let bios = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if bios.len() != BIOS_SIZE {
+        return Err(format!(
+            "{}: the FDS BIOS is {} bytes, not {}",
+            path.display(),
+            BIOS_SIZE,
+            bios.len()
+        ));
+    }
+    Ok(bios)
+}
+
+// Disk writes go to an IPS diff next to the image unless written back into it
+pub fn save_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("fds.ips")
+}
+
+// Bring back disk writes from an earlier session
+pub fn load_save(fds: &mut Fds, rom_path: &Path) -> Result<(), String> {
+    let path = save_path(rom_path);
+    if !path.exists() {
+        return Ok(());
+    }
+    let err = |e: String| format!("{}: {}", path.display(), e);
+    let diff = std::fs::read(&path).map_err(|e| err(e.to_string()))?;
+    fds.load_diff(&diff).map_err(err)
+}
+
+// Keep the disk writes, in the image itself with `write_back` (only for
+// plain .fds files, not archives)
+pub fn save(fds: &Fds, rom_path: &Path, write_back: bool) -> Result<(), String> {
+    if !fds.is_modified() {
+        return Ok(());
+    }
+    let is_image = rom_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fds"));
+    let diff_path = save_path(rom_path);
+    let (path, bytes) = if write_back && is_image {
+        // The old diff would undo later writes to the same bytes
+        if diff_path.exists() {
+            std::fs::remove_file(&diff_path)
+                .map_err(|e| format!("{}: {}", diff_path.display(), e))?;
+        }
+        (rom_path.to_path_buf(), fds.image().to_bytes())
+    } else {
+        (diff_path, fds.save_diff())
+    };
+    std::fs::write(&path, bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub struct Fds {
+    ram: Vec<u8>,
+    // The image as loaded, saves are a diff against it
+    base: DiskImage,
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    // Side to insert once `insert_delay` runs out
+    next_side: usize,
+    insert_delay: u32,
+    modified: bool,
+
+    disk_io: bool,
+    control: u8,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+    disk_irq: bool,
+    crc: u16,
+    previous_crc_control: bool,
+}
+
+impl Fds {
+    pub fn new(disk: DiskImage) -> Self {
+        Fds {
+            ram: vec![0; RAM_SIZE],
+            sides: disk.sides.iter().map(|side| add_gaps(side)).collect(),
+            base: disk,
+            side: Some(0),
+            next_side: 0,
+            insert_delay: 0,
+            modified: false,
+            disk_io: false,
+            control: 0,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            read_data: 0,
+            write_data: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            crc: 0,
+            previous_crc_control: false,
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match self.control & HORIZONTAL_MIRRORING {
+            0 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    // Eject the disk and insert the next side (or the next disk) a moment
+    // later. Returns the side that goes in.
+    pub fn switch_side(&mut self) -> usize {
+        if let Some(side) = self.side.take() {
+            self.next_side = (side + 1) % self.sides.len();
+        }
+        self.insert_delay = SIDE_SWITCH_CYCLES;
+        self.next_side
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    // The disk as it is now, writes included
+    pub fn image(&self) -> DiskImage {
+        DiskImage {
+            sides: self.sides.iter().map(|raw| strip_gaps(raw)).collect(),
+            header: self.base.header,
+        }
+    }
+
+    pub fn save_diff(&self) -> Vec<u8> {
+        patch::create_ips(&self.base.to_bytes(), &self.image().to_bytes())
+    }
+
+    // Bring back writes saved with `save_diff`
+    pub fn load_diff(&mut self, diff: &[u8]) -> Result<(), String> {
+        let image = DiskImage::parse(&patch::apply(&self.base.to_bytes(), diff)?)?;
+        if image.sides.len() != self.base.sides.len() {
+            return Err("FDS save has a different number of disk sides".to_string());
+        }
+        self.sides = image.sides.iter().map(|side| add_gaps(side)).collect();
+        Ok(())
+    }
+
+    // RAM only, for tooling
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0xDFFF => self.ram[(addr - RAM_START) as usize],
+            _ => 0,
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            // Disk status, reading acknowledges the IRQs
+            0x4030 => {
+                let mut status = self.timer_irq as u8 | (self.transfer_complete as u8) << 1;
+                if self.crc != 0 {
+                    status |= 1 << 4;
+                }
+                if self.end_of_head {
+                    status |= 1 << 6;
+                }
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                status
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.read_data
+            }
+            // Drive status: no disk, not ready, write protected
+            0x4032 => match self.side {
+                None => 0x47,
+                Some(_) if !self.scanning => 0x42,
+                Some(_) => 0x40,
+            },
+            // Expansion port, bit 7 is the battery being good
+            0x4033 => 0x80,
+            0x6000..=0xDFFF => self.ram[(addr - RAM_START) as usize],
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = self.timer_reload & 0xff00 | data as u16,
+            0x4021 => self.timer_reload = self.timer_reload & 0x00ff | (data as u16) << 8,
+            0x4022 if self.disk_io => {
+                self.timer_repeat = data & 1 != 0;
+                self.timer_enabled = data & 2 != 0;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io = data & 1 != 0;
+                if !self.disk_io {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 if self.disk_io => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 if self.disk_io => {
+                self.control = data;
+                self.disk_irq = false;
+            }
+            0x6000..=0xDFFF => self.ram[(addr - RAM_START) as usize] = data,
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+        }
+        if self.side.is_none() && self.insert_delay > 0 {
+            self.insert_delay = self.insert_delay.saturating_sub(cycles as u32);
+            if self.insert_delay == 0 {
+                self.side = Some(self.next_side);
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled || !self.disk_io {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            self.timer_enabled = self.timer_repeat;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        let Some(side) = self.side else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if !self.disk_io || self.control & MOTOR_ON == 0 {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.control & TRANSFER_RESET != 0 && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let ready = self.control & DISK_READY != 0;
+        let crc_control = self.control & CRC_CONTROL != 0;
+        let mut irq = self.control & DISK_IRQ != 0;
+        if self.control & READ_MODE != 0 {
+            let byte = self.sides[side][self.position];
+            if !self.previous_crc_control {
+                self.crc = crc_step(self.crc, byte);
+            }
+            if !ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if byte != 0 && !self.gap_ended {
+                // The start mark, data follows
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = byte;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let mut byte = 0;
+            if !crc_control {
+                self.transfer_complete = true;
+                byte = self.write_data;
+                self.disk_irq |= irq;
+            }
+            if !ready {
+                byte = 0;
+                self.crc = 0;
+            }
+            if !crc_control {
+                self.crc = crc_step(self.crc, byte);
+            } else {
+                if !self.previous_crc_control {
+                    self.crc = crc_step(crc_step(self.crc, 0), 0);
+                }
+                byte = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.sides[side][self.position] = byte;
+            self.modified = true;
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = crc_control;
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            // Off the end of the disk, the head goes back
+            self.end_of_head = true;
+            self.control &= !MOTOR_ON;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // One side with the disk info, file count, and a 3 byte file
+    fn side() -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.resize(56, 0);
+        side.extend([2, 1]);
+        let mut header = vec![3, 0, 0];
+        header.extend(b"FILE0001");
+        header.extend([0x00, 0x60, 3, 0, 0]);
+        side.extend(header);
+        side.extend([4, 0xAA, 0xBB, 0xCC]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    // Two sided image with one file per side
+    pub fn image() -> Vec<u8> {
+        let mut raw = FDS_TAG.to_vec();
+        raw.push(2);
+        raw.resize(HEADER_SIZE, 0);
+        raw.extend(side());
+        raw.extend(side());
+        raw
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = image();
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, FDS_MAPPER);
+        let disk = rom.disk.unwrap();
+        assert_eq!(disk.sides.len(), 2);
+        assert_eq!(disk.to_bytes(), raw);
+        // Headerless images work too
+        assert_eq!(
+            DiskImage::parse(&raw[HEADER_SIZE..]).unwrap().to_bytes(),
+            raw[HEADER_SIZE..]
+        );
+        assert!(DiskImage::parse(&[FDS_TAG.as_slice(), &[0; 60]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_gaps() {
+        let raw = add_gaps(&side());
+        assert!(raw[..LEAD_IN].iter().all(|b| *b == 0));
+        assert_eq!(raw[LEAD_IN], START_MARK);
+        // The CRC checks out when run over the block and the CRC itself
+        let block = &raw[LEAD_IN..LEAD_IN + 1 + 56 + 2];
+        assert_eq!(block.iter().fold(0, |crc, b| crc_step(crc, *b)), 0);
+        assert_eq!(strip_gaps(&raw), side());
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = Fds::new(DiskImage::parse(&image()).unwrap());
+        fds.write(0x4020, 10);
+        fds.write(0x4021, 0);
+        // Ignored until disk I/O is enabled
+        fds.write(0x4022, 2);
+        fds.tick(20);
+        assert!(!fds.irq());
+
+        fds.write(0x4023, 1);
+        fds.write(0x4022, 3);
+        fds.tick(10);
+        assert!(!fds.irq());
+        fds.tick(1);
+        assert!(fds.irq());
+        assert_eq!(fds.read(0x4030) & 1, 1);
+        assert!(!fds.irq());
+        // Repeats
+        fds.tick(11);
+        assert!(fds.irq());
+    }
+
+    // Bytes handed over with disk IRQs, reading the side from the start
+    fn read_bytes(fds: &mut Fds, count: usize) -> Vec<u8> {
+        fds.write(0x4023, 1);
+        fds.write(0x4025, MOTOR_ON | READ_MODE | DISK_READY | DISK_IRQ);
+        let mut bytes = vec![];
+        while bytes.len() < count {
+            fds.tick(1);
+            if fds.irq() {
+                bytes.push(fds.read(0x4031));
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_disk() {
+        let mut fds = Fds::new(DiskImage::parse(&image()).unwrap());
+        assert_eq!(fds.read(0x4032) & 1, 0);
+        assert_eq!(read_bytes(&mut fds, 15), DISK_INFO);
+
+        assert_eq!(fds.switch_side(), 1);
+        assert_eq!(fds.read(0x4032) & 1, 1);
+        fds.tick(255);
+        assert_eq!(fds.side(), None);
+        while fds.side().is_none() {
+            fds.tick(255);
+        }
+        assert_eq!(fds.side(), Some(1));
+    }
+
+    #[test]
+    fn test_write_and_save() {
+        let mut fds = Fds::new(DiskImage::parse(&image()).unwrap());
+        assert!(!fds.is_modified());
+        // Skip to the file count block, then write over it
+        fds.write(0x4023, 1);
+        fds.write(0x4025, MOTOR_ON | READ_MODE);
+        let start = LEAD_IN + 1 + 56 + 2 + BLOCK_GAP;
+        while fds.position < start {
+            fds.tick(100);
+        }
+        fds.write(0x4025, MOTOR_ON | DISK_READY);
+        for byte in [START_MARK, 2, 7] {
+            fds.write(0x4024, byte);
+            let position = fds.position;
+            while fds.position == position {
+                fds.tick(1);
+            }
+        }
+        fds.write(0x4025, MOTOR_ON | DISK_READY | CRC_CONTROL);
+        let position = fds.position;
+        while fds.position < position + 2 {
+            fds.tick(1);
+        }
+        assert!(fds.is_modified());
+        let image = fds.image();
+        assert_eq!(image.sides[0][57], 7);
+        assert_eq!(image.sides[0][58..], side()[58..]);
+
+        let diff = fds.save_diff();
+        let mut reloaded = Fds::new(DiskImage::parse(&self::image()).unwrap());
+        reloaded.load_diff(&diff).unwrap();
+        assert_eq!(reloaded.image(), image);
+    }
+}
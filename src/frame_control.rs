@@ -0,0 +1,306 @@
+use std::time::{Duration, Instant};
+
+// Pause, frame advance, fast forward, slow motion and frame pacing for the
+// frontend. None of it touches emulation: the same frames run with the same
+// input whatever the speed, only how often they are shown and how long the
+// frontend waits between them changes.
+
+// Frames per second of the real consoles
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+pub const PAL_FRAME_RATE: f64 = 50.007;
+
+// While fast forwarding only every Nth frame is drawn
+pub const FAST_FORWARD_RENDER_EVERY: usize = 8;
+// Slowest slow motion, 1/N speed
+pub const MAX_SLOW_MOTION: u32 = 8;
+
+pub struct FrameControl {
+    paused: bool,
+    // Frames to run before pausing again
+    advance: usize,
+    fast_forward: bool,
+    slow_motion: u32,
+    frame: usize,
+}
+
+impl Default for FrameControl {
+    fn default() -> Self {
+        FrameControl::new()
+    }
+}
+
+impl FrameControl {
+    pub fn new() -> Self {
+        FrameControl {
+            paused: false,
+            advance: 0,
+            fast_forward: false,
+            slow_motion: 1,
+            frame: 0,
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Pause if running, then run exactly one more frame
+    pub fn advance(&mut self) {
+        self.paused = true;
+        self.advance += 1;
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn is_fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    // 1 (full speed), 1/2, 1/4 ... then back to full speed
+    pub fn cycle_slow_motion(&mut self) {
+        self.slow_motion = match self.slow_motion * 2 {
+            n if n > MAX_SLOW_MOTION => 1,
+            n => n,
+        };
+    }
+
+    pub fn slow_motion(&self) -> u32 {
+        self.slow_motion
+    }
+
+    // Called once a frame is done, true when it should be drawn
+    pub fn end_frame(&mut self) -> bool {
+        self.frame += 1;
+        !self.fast_forward || self.frame.is_multiple_of(FAST_FORWARD_RENDER_EVERY)
+    }
+
+    // Whether to hold the next frame back, after input for it has been
+    // handled. Uses up a pending frame advance.
+    pub fn hold(&mut self) -> bool {
+        if !self.paused {
+            return false;
+        }
+        if self.advance > 0 {
+            self.advance -= 1;
+            return false;
+        }
+        true
+    }
+}
+
+// Sleeping gives up the rest of the wait to the OS, which may wake us late.
+// The last bit is spun instead.
+const SPIN_TIME: Duration = Duration::from_millis(1);
+// Further behind than this the pacer gives up catching up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now + SPIN_TIME {
+        std::thread::sleep(deadline - now - SPIN_TIME);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+// Runs frames at the console's rate whatever the monitor refreshes at, and
+// measures the rate actually reached
+pub struct FramePacer {
+    frame_rate: f64,
+    // When the next frame is due
+    deadline: Option<Instant>,
+    window_start: Option<Instant>,
+    window_frames: u32,
+    fps: Option<f64>,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: f64) -> Self {
+        FramePacer {
+            frame_rate,
+            deadline: None,
+            window_start: None,
+            window_frames: 0,
+            fps: None,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    // Length of a frame at 1/`slowdown` speed
+    pub fn frame_time(&self, slowdown: u32) -> Duration {
+        Duration::from_secs_f64(slowdown as f64 / self.frame_rate)
+    }
+
+    // When the frame that ended at `now` should be followed by the next one.
+    // Deadlines advance by exact frame times so rounding doesn't add up;
+    // after a long stall (paused, debugger, slow host) pacing starts over.
+    pub fn schedule(&mut self, now: Instant, slowdown: u32) -> Instant {
+        let frame_time = self.frame_time(slowdown);
+        let deadline = match self.deadline {
+            Some(deadline) if now < deadline + MAX_LAG => deadline + frame_time,
+            _ => now + frame_time,
+        };
+        self.deadline = Some(deadline);
+        deadline
+    }
+
+    // Whether emulation ended a frame at `now` more than a frame behind
+    // schedule. Such frames are not presented, so a display slower than the
+    // console (or a present blocking on vsync) can't slow the game down.
+    pub fn is_behind(&self, now: Instant, slowdown: u32) -> bool {
+        self.deadline
+            .is_some_and(|deadline| now > deadline + self.frame_time(slowdown))
+    }
+
+    // Wait for the next frame
+    pub fn wait(&mut self, slowdown: u32) {
+        let deadline = self.schedule(Instant::now(), slowdown);
+        sleep_until(deadline);
+    }
+
+    // Don't hold the next frame back, as when fast forwarding
+    pub fn resync(&mut self) {
+        self.deadline = None;
+    }
+
+    // Count a frame that ran at `now`, the rate is updated every second
+    pub fn count_frame(&mut self, now: Instant) {
+        let start = *self.window_start.get_or_insert(now);
+        self.window_frames += 1;
+        let elapsed = now - start;
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = Some((self.window_frames - 1) as f64 / elapsed.as_secs_f64());
+            self.window_start = Some(now);
+            self.window_frames = 1;
+        }
+    }
+
+    // Frames per second over the last second
+    pub fn fps(&self) -> Option<f64> {
+        self.fps
+    }
+
+    // Percent of the console's speed
+    pub fn speed(&self) -> Option<f64> {
+        self.fps.map(|fps| fps * 100.0 / self.frame_rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_advance() {
+        let mut control = FrameControl::new();
+        assert!(!control.hold());
+        control.toggle_pause();
+        assert!(control.hold());
+
+        control.advance();
+        control.advance();
+        assert!(!control.hold());
+        assert!(!control.hold());
+        assert!(control.hold());
+
+        // Frame advance while running pauses after one frame
+        control.toggle_pause();
+        control.advance();
+        assert!(control.is_paused());
+        assert!(!control.hold());
+        assert!(control.hold());
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut control = FrameControl::new();
+        assert!((0..4).all(|_| control.end_frame()));
+
+        control.set_fast_forward(true);
+        let drawn = (0..32).filter(|_| control.end_frame()).count();
+        assert_eq!(drawn, 32 / FAST_FORWARD_RENDER_EVERY);
+
+        control.set_fast_forward(false);
+        let speeds: Vec<u32> = (0..5)
+            .map(|_| {
+                control.cycle_slow_motion();
+                control.slow_motion()
+            })
+            .collect();
+        assert_eq!(speeds, [2, 4, 8, 1, 2]);
+    }
+
+    #[test]
+    fn test_pacer_schedule() {
+        let mut pacer = FramePacer::new(NTSC_FRAME_RATE);
+        let start = Instant::now();
+        let frame_time = pacer.frame_time(1);
+        assert_eq!(frame_time.as_micros(), 16639);
+
+        // Deadlines don't drift with how late each frame finished
+        assert_eq!(pacer.schedule(start, 1), start + frame_time);
+        let late = start + frame_time + Duration::from_millis(3);
+        assert_eq!(pacer.schedule(late, 1), start + frame_time * 2);
+        // Slow motion makes the next frame longer
+        assert_eq!(
+            pacer.schedule(late, 2),
+            start + frame_time * 2 + pacer.frame_time(2)
+        );
+
+        // Way behind, start over
+        let stalled = start + Duration::from_secs(2);
+        assert_eq!(pacer.schedule(stalled, 1), stalled + frame_time);
+        pacer.resync();
+        assert_eq!(pacer.schedule(start, 1), start + frame_time);
+    }
+
+    #[test]
+    fn test_slow_presentation_keeps_full_speed() {
+        // A 50 Hz display with vsync: presenting blocks for a whole refresh
+        let present_time = Duration::from_secs_f64(1.0 / 50.0);
+        let emulate_time = Duration::from_millis(2);
+        let mut pacer = FramePacer::new(NTSC_FRAME_RATE);
+        let start = Instant::now();
+        let mut now = start;
+        let mut presented = 0;
+        while now < start + Duration::from_secs(5) {
+            now += emulate_time;
+            pacer.count_frame(now);
+            if !pacer.is_behind(now, 1) {
+                now += present_time;
+                presented += 1;
+            }
+            now = now.max(pacer.schedule(now, 1));
+        }
+        let speed = pacer.speed().unwrap();
+        assert!((speed - 100.0).abs() < 1.0, "{}", speed);
+        // Frames were dropped, not slowed down
+        assert!(presented < 5 * 50 + 1, "{}", presented);
+    }
+
+    #[test]
+    fn test_pacer_fps() {
+        let mut pacer = FramePacer::new(PAL_FRAME_RATE);
+        let start = Instant::now();
+        let frame_time = pacer.frame_time(1);
+        for frame in 0..=50 {
+            pacer.count_frame(start + frame_time * frame);
+        }
+        assert!(pacer.fps().is_none());
+        pacer.count_frame(start + frame_time * 51);
+        let fps = pacer.fps().unwrap();
+        assert!((fps - PAL_FRAME_RATE).abs() < 0.01, "{}", fps);
+        assert!((pacer.speed().unwrap() - 100.0).abs() < 0.01);
+    }
+}
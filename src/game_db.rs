@@ -0,0 +1,388 @@
+use crate::cartridge::Mirroring;
+use crate::region::Region;
+use std::path::Path;
+
+// Known dumps and what their headers should say, keyed by the CRC32 or SHA-1
+// of PRG and CHR ROM together (no header, no trainer):
+//
+//   # key     mapper  mirroring   battery  region  name
+//   158B0388  0       horizontal  no       ntsc    nestest
+//
+// `-` leaves a field as the header has it. A SHA-1 match wins over a CRC32
+// one. Larger databases can be imported from nes20db's XML, and build.rs
+// embeds one behind these entries when it's given at build time.
+const EMBEDDED: &str = include_str!("game_db.txt");
+const EMBEDDED_NES20DB: &str = include_str!(concat!(env!("OUT_DIR"), "/nes20db.xml"));
+
+lazy_static! {
+    static ref EMBEDDED_DB: GameDb = {
+        let mut db = GameDb::parse(EMBEDDED).unwrap();
+        let nes20db = GameDb::parse_nes20db(EMBEDDED_NES20DB).unwrap();
+        db.games.extend(nes20db.games);
+        db.skipped = nes20db.skipped;
+        db
+    };
+}
+
+// CRC-32 (IEEE), as used by zip, No-Intro and patch formats
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// SHA-1, as used by nes20db and No-Intro
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GameKey {
+    Crc32(u32),
+    Sha1([u8; 20]),
+}
+
+impl GameKey {
+    fn parse(hex: &str) -> Option<Self> {
+        match hex.len() {
+            8 => u32::from_str_radix(hex, 16).ok().map(GameKey::Crc32),
+            _ => parse_sha1(hex).map(GameKey::Sha1),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameInfo {
+    pub key: GameKey,
+    pub name: String,
+    pub mapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    pub region: Option<Region>,
+}
+
+pub struct GameDb {
+    games: Vec<GameInfo>,
+    // Entries that couldn't be imported, and why
+    skipped: Vec<String>,
+}
+
+fn field<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    match value {
+        "-" => Ok(None),
+        _ => parse(value)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid value: {}", value)),
+    }
+}
+
+impl GameDb {
+    // The database built into the emulator
+    pub fn embedded() -> &'static GameDb {
+        &EMBEDDED_DB
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut games = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: String| format!("Game database line {}: {}", n + 1, e);
+            let mut parts = line.split_whitespace();
+            let columns: Vec<&str> = parts.by_ref().take(5).collect();
+            if columns.len() < 5 {
+                return Err(err(format!("expected 5 or 6 columns: {}", line)));
+            }
+            let key = GameKey::parse(columns[0])
+                .ok_or_else(|| err(format!("Invalid CRC32 or SHA-1: {}", columns[0])))?;
+            games.push(GameInfo {
+                key,
+                mapper: field(columns[1], |v| v.parse().ok()).map_err(err)?,
+                mirroring: field(columns[2], |v| match v {
+                    "horizontal" => Some(Mirroring::Horizontal),
+                    "vertical" => Some(Mirroring::Vertical),
+                    "four-screen" => Some(Mirroring::FourScreen),
+                    _ => None,
+                })
+                .map_err(err)?,
+                battery: field(columns[3], |v| match v {
+                    "yes" => Some(true),
+                    "no" => Some(false),
+                    _ => None,
+                })
+                .map_err(err)?,
+                region: field(columns[4], |v| v.parse().ok()).map_err(err)?,
+                name: parts.collect::<Vec<_>>().join(" "),
+            });
+        }
+        Ok(GameDb {
+            games,
+            skipped: vec![],
+        })
+    }
+
+    // nes20db.xml: each <game> has a <rom> with the hashes of PRG and CHR
+    // together, a <pcb> with the mapper, mirroring and battery and a
+    // <console> with the region
+    pub fn parse_nes20db(xml: &str) -> Result<Self, String> {
+        let mut games = vec![];
+        let mut skipped = vec![];
+        for (n, game) in xml.split("<game>").skip(1).enumerate() {
+            let err = |e: &str| format!("nes20db game {}: {}", n + 1, e);
+            let game = game.split("</game>").next().unwrap_or(game);
+            let rom = xml_tag(game, "rom").ok_or_else(|| err("no <rom>"))?;
+            let key = xml_attr(rom, "sha1")
+                .and_then(parse_sha1)
+                .map(GameKey::Sha1)
+                .or_else(|| xml_attr(rom, "crc32").and_then(GameKey::parse))
+                .ok_or_else(|| err("<rom> has no sha1 or crc32"))?;
+            let pcb = xml_tag(game, "pcb").unwrap_or_default();
+            let console = xml_tag(game, "console").unwrap_or_default();
+            let name = xml_name(game).unwrap_or_else(|| format!("game {}", n + 1));
+            // NES 2.0 mappers go up to 4095, iNES ones (and ours) stop at 255
+            let mapper = match xml_attr(pcb, "mapper").map(|v| v.parse::<u16>()) {
+                Some(Ok(mapper)) if mapper > u8::MAX as u16 => {
+                    skipped.push(format!("{}: mapper {}", name, mapper));
+                    continue;
+                }
+                Some(Ok(mapper)) => Some(mapper as u8),
+                Some(Err(_)) => return Err(err("invalid mapper")),
+                None => None,
+            };
+            games.push(GameInfo {
+                key,
+                mapper,
+                mirroring: xml_attr(pcb, "mirroring").and_then(|v| match v {
+                    "H" => Some(Mirroring::Horizontal),
+                    "V" => Some(Mirroring::Vertical),
+                    "4" => Some(Mirroring::FourScreen),
+                    _ => None,
+                }),
+                battery: xml_attr(pcb, "battery").map(|v| v == "1"),
+                // 2 is multi-region, which runs as NTSC
+                region: xml_attr(console, "region")
+                    .and_then(|v| v.parse().ok())
+                    .map(Region::from_nes2),
+                name,
+            });
+        }
+        Ok(GameDb { games, skipped })
+    }
+
+    // A text or nes20db XML database file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let db = if text.trim_start().starts_with('<') {
+            GameDb::parse_nes20db(&text)
+        } else {
+            GameDb::parse(&text)
+        };
+        db.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    pub fn lookup(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&GameInfo> {
+        let by_sha1 = self
+            .games
+            .iter()
+            .find(|game| game.key == GameKey::Sha1(*sha1));
+        by_sha1.or_else(|| {
+            self.games
+                .iter()
+                .find(|game| game.key == GameKey::Crc32(crc32))
+        })
+    }
+}
+
+// The attributes of the first <name ...> tag
+fn xml_tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{} ", name))? + name.len() + 2;
+    let end = xml[start..].find('>')?;
+    Some(&xml[start..start + end])
+}
+
+fn xml_attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = tag[start..].find('"')?;
+    Some(&tag[start..start + end])
+}
+
+// nes20db puts the game's name in a comment at the top of its entry
+fn xml_name(game: &str) -> Option<String> {
+    let start = game.find("<!--")? + 4;
+    let end = game[start..].find("-->")?;
+    Some(game[start..start + end].trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            sha1(b"abc"),
+            parse_sha1("A9993E364706816ABA3E25717850C26C9CD0D89D").unwrap()
+        );
+        // Padding spills into a second block
+        assert_eq!(
+            sha1(&[b'a'; 56]),
+            parse_sha1("C2DB330F6083854C99D4B5BFB6E8F29F201BE699").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let db =
+            GameDb::parse("# test\n0000ABCD 4 vertical yes pal Some Game (E)\n12345678 - - - -\n")
+                .unwrap();
+        let none = [0; 20];
+        let game = db.lookup(0xABCD, &none).unwrap();
+        assert_eq!(game.name, "Some Game (E)");
+        assert_eq!(game.mapper, Some(4));
+        assert_eq!(game.mirroring, Some(Mirroring::Vertical));
+        assert_eq!(game.battery, Some(true));
+        assert_eq!(game.region, Some(Region::Pal));
+        assert_eq!(db.lookup(0x12345678, &none).unwrap().mapper, None);
+        assert!(db.lookup(1, &none).is_none());
+
+        assert!(GameDb::parse("ABCD 4 vertical yes").is_err());
+        assert!(GameDb::parse("XYZ 4 vertical yes pal").is_err());
+        assert!(GameDb::parse("ABCD 4 diagonal yes pal").is_err());
+        assert!(GameDb::embedded().lookup(0x158B_0388, &none).is_some());
+    }
+
+    #[test]
+    fn test_sha1_wins_over_crc32() {
+        let sha1 = sha1(b"rom");
+        let db = GameDb::parse(&format!(
+            "{:08X} 1 - - - By CRC32\n{} 4 - - - By SHA-1\n",
+            crc32(b"rom"),
+            hex(&sha1)
+        ))
+        .unwrap();
+        assert_eq!(db.lookup(crc32(b"rom"), &sha1).unwrap().mapper, Some(4));
+        assert_eq!(db.lookup(crc32(b"rom"), &[0; 20]).unwrap().mapper, Some(1));
+    }
+
+    #[test]
+    fn test_parse_nes20db() {
+        let xml = r#"<?xml version="1.0"?>
+<nes20db>
+  <game>
+    <!-- Some Game (Europe).nes -->
+    <prgrom size="131072" crc32="11111111" sha1="0000000000000000000000000000000000000000"/>
+    <rom size="262144" crc32="0000ABCD" sha1="A9993E364706816ABA3E25717850C26C9CD0D89D"/>
+    <pcb mapper="4" submapper="0" mirroring="V" battery="1"/>
+    <console type="0" region="1"/>
+  </game>
+  <game>
+    <rom size="32768" crc32="12345678"/>
+    <pcb mapper="0" submapper="0" mirroring="H" battery="0"/>
+    <console type="0" region="0"/>
+  </game>
+  <game>
+    <!-- Big Multicart.nes -->
+    <rom size="65536" crc32="87654321"/>
+    <pcb mapper="260" submapper="0" mirroring="H" battery="0"/>
+  </game>
+</nes20db>"#;
+        let db = GameDb::parse_nes20db(xml).unwrap();
+        let game = db.lookup(0, &sha1(b"abc")).unwrap();
+        assert_eq!(game.name, "Some Game (Europe).nes");
+        assert_eq!(game.mapper, Some(4));
+        assert_eq!(game.mirroring, Some(Mirroring::Vertical));
+        assert_eq!(game.battery, Some(true));
+        assert_eq!(game.region, Some(Region::Pal));
+        let game = db.lookup(0x1234_5678, &[0; 20]).unwrap();
+        assert_eq!(game.mirroring, Some(Mirroring::Horizontal));
+        assert!(db.lookup(0x8765_4321, &[0; 20]).is_none());
+        assert_eq!(db.skipped(), ["Big Multicart.nes: mapper 260"]);
+        assert!(GameDb::parse_nes20db("<game><pcb mapper=\"0\"/></game>").is_err());
+        let bad_mapper = "<game><rom crc32=\"12345678\"/><pcb mapper=\"x\"/></game>";
+        assert!(GameDb::parse_nes20db(bad_mapper).is_err());
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+}
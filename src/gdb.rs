@@ -0,0 +1,398 @@
+use crate::cpu::CpuFlags;
+use crate::cpu::CPU;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+// Register layout exposed to the client, one byte each except PC (little endian):
+// 0: A, 1: X, 2: Y, 3: P, 4: SP, 5: PC
+const REG_PC: usize = 5;
+const REG_COUNT: usize = 6;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const INTERRUPT: u8 = 0x03;
+
+// How many instructions run between checks for a client interrupt while continuing
+const POLL_INTERVAL: usize = 1000;
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    breakpoints: HashSet<u16>,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Works on bytes, packets can hold anything the client sent
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(value: &str) -> Option<u16> {
+    u16::from_str_radix(value, 16).ok()
+}
+
+// "addr,len" as used by m/M/Z/z packets
+fn parse_addr_len(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn read_register(cpu: &CPU, reg: usize) -> Vec<u8> {
+    match reg {
+        0 => vec![cpu.register_a],
+        1 => vec![cpu.register_x],
+        2 => vec![cpu.register_y],
+        3 => vec![cpu.status.bits()],
+        4 => vec![cpu.stack_pointer],
+        _ => cpu.program_counter.to_le_bytes().to_vec(),
+    }
+}
+
+fn write_register(cpu: &mut CPU, reg: usize, bytes: &[u8]) -> Option<()> {
+    match (reg, bytes) {
+        (0, [value]) => cpu.register_a = *value,
+        (1, [value]) => cpu.register_x = *value,
+        (2, [value]) => cpu.register_y = *value,
+        (3, [value]) => cpu.status = CpuFlags::from_bits_truncate(*value),
+        (4, [value]) => cpu.stack_pointer = *value,
+        (REG_PC, [lo, hi]) => cpu.program_counter = u16::from_le_bytes([*lo, *hi]),
+        _ => return None,
+    }
+    Some(())
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> Self {
+        // packets are tiny request/response pairs, don't let Nagle delay them
+        stream.set_nodelay(true).ok();
+        GdbStub {
+            stream,
+            buffer: vec![],
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    // Serve the client until it kills or detaches the session
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(Packet::Command(packet)) => packet,
+                Some(Packet::Interrupt) => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ => {
+                    let reply = self.handle(cpu, &packet)?;
+                    self.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> io::Result<String> {
+        if packet.is_empty() {
+            return Ok(String::new());
+        }
+        let error = "E01".to_string();
+        // Every packet we handle is ASCII, anything else can't be split safely
+        if !packet.is_ascii() {
+            return Ok(error);
+        }
+        let (cmd, args) = packet.split_at(1);
+
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..REG_COUNT)
+                .map(|reg| hex_bytes(&read_register(cpu, reg)))
+                .collect(),
+            "G" => {
+                let bytes = match parse_hex_bytes(args) {
+                    Some(bytes) if bytes.len() == REG_COUNT + 1 => bytes,
+                    _ => return Ok(error),
+                };
+                for reg in 0..REG_PC {
+                    write_register(cpu, reg, &bytes[reg..reg + 1]);
+                }
+                write_register(cpu, REG_PC, &bytes[REG_PC..]);
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < REG_COUNT => hex_bytes(&read_register(cpu, reg)),
+                _ => error,
+            },
+            "P" => {
+                let written = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    write_register(cpu, reg, &parse_hex_bytes(value)?)
+                });
+                match written {
+                    Some(()) => "OK".to_string(),
+                    None => error,
+                }
+            }
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) => {
+                    let bytes: Vec<u8> = (0..len)
+                        .map(|i| cpu.bus.peek(addr.wrapping_add(i)))
+                        .collect();
+                    hex_bytes(&bytes)
+                }
+                None => error,
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_addr_len(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    (bytes.len() == len as usize).then_some((addr, bytes))
+                });
+                match parsed {
+                    Some((addr, bytes)) => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            cpu.bus.poke(addr.wrapping_add(i as u16), *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => error,
+                }
+            }
+            "Z" | "z" => {
+                // only software breakpoints (type 0) are supported
+                let addr = match args.strip_prefix("0,").and_then(parse_addr_len) {
+                    Some((addr, _kind)) => addr,
+                    None => return Ok(String::new()),
+                };
+                if cmd == "Z" {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                "OK".to_string()
+            }
+            "s" => {
+                if !cpu.step() {
+                    return Ok("W00".to_string());
+                }
+                format!("S{:02x}", SIGTRAP)
+            }
+            "c" => self.resume(cpu)?,
+            "q" if args.starts_with("Supported") => "PacketSize=1000".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "H" => "OK".to_string(),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    // Continue until a breakpoint, BRK or an interrupt from the client
+    fn resume(&mut self, cpu: &mut CPU) -> io::Result<String> {
+        let mut executed = 0;
+        loop {
+            if !cpu.step() {
+                return Ok("W00".to_string());
+            }
+            if self.breakpoints.contains(&cpu.program_counter) {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+
+            executed += 1;
+            if executed % POLL_INTERVAL == 0 && self.poll_interrupt()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut chunk = [0u8; 64];
+        let result = self.stream.read(&mut chunk);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Ok(true),
+            Ok(n) => {
+                self.buffer.extend_from_slice(&chunk[..n]);
+                match self.buffer.iter().position(|b| *b == INTERRUPT) {
+                    Some(idx) => {
+                        self.buffer.remove(idx);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Read the next `$data#cs` packet (acknowledging it) or interrupt byte.
+    // Returns None once the client disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            while let Some(&byte) = self.buffer.first() {
+                match byte {
+                    INTERRUPT => {
+                        self.buffer.remove(0);
+                        return Ok(Some(Packet::Interrupt));
+                    }
+                    b'$' => break,
+                    _ => {
+                        // acks and line noise
+                        self.buffer.remove(0);
+                    }
+                }
+            }
+
+            if let Some(end) = self.buffer.iter().position(|b| *b == b'#') {
+                if self.buffer.len() >= end + 3 {
+                    let data = String::from_utf8_lossy(&self.buffer[1..end]).to_string();
+                    let sum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                        .ok()
+                        .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+                    self.buffer.drain(..end + 3);
+
+                    if sum == Some(checksum(&data)) {
+                        self.stream.write_all(b"+")?;
+                        return Ok(Some(Packet::Command(data)));
+                    }
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 1024];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Minimal client side of the protocol
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+
+            let mut reply = vec![];
+            let mut byte = [0u8; 1];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'+' && reply.is_empty() {
+                    continue;
+                }
+                reply.push(byte[0]);
+                if reply.len() >= 3 && reply[reply.len() - 3] == b'#' {
+                    break;
+                }
+            }
+            self.stream.write_all(b"+").unwrap();
+
+            let reply = String::from_utf8(reply).unwrap();
+            reply[1..reply.len() - 3].to_string()
+        }
+    }
+
+    #[test]
+    fn test_parse_hex_bytes() {
+        assert_eq!(parse_hex_bytes("e84C"), Some(vec![0xe8, 0x4c]));
+        assert_eq!(parse_hex_bytes("e8e"), None);
+        assert_eq!(parse_hex_bytes("zz"), None);
+        assert_eq!(parse_hex_bytes("\u{e9}\u{e9}"), None);
+        assert_eq!(parse_hex_bytes("a\u{fffd}"), None);
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream };
+            assert_eq!(client.request("qSupported:swbreak+"), "PacketSize=1000");
+            assert_eq!(client.request("?"), "S05");
+
+            // INX; INX; INX; JMP $0200
+            assert_eq!(client.request("M0200,6:e8e8e84c0002"), "OK");
+            assert_eq!(client.request("m0200,3"), "e8e8e8");
+            // Viewing PPU registers doesn't read them
+            assert_eq!(client.request("m2006,2"), "0000");
+            assert_eq!(client.request("\u{e9}"), "E01");
+            assert_eq!(client.request("M0200,1:\u{e9}"), "E01");
+            assert_eq!(client.request("P5=0002"), "OK");
+            assert_eq!(client.request("g"), "00000024fd0002");
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p1"), "01");
+            assert_eq!(client.request("p5"), "0102");
+
+            assert_eq!(client.request("Z0,0203,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "0302");
+            assert_eq!(client.request("p1"), "03");
+
+            assert_eq!(client.request("z0,0203,1"), "OK");
+            assert_eq!(client.request("P0=7f"), "OK");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut cpu = CPU::new(Bus::new(test_rom(), |_, _| {}));
+        GdbStub::new(stream).run(&mut cpu).unwrap();
+        client.join().unwrap();
+
+        assert_eq!(cpu.register_a, 0x7f);
+        assert_eq!(cpu.program_counter, 0x0203);
+        assert_eq!(cpu.bus.ppu().addr.get(), 0);
+    }
+}
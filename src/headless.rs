@@ -0,0 +1,258 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::input::multitap::Multitap;
+use crate::input::{Controllers, DeviceKind};
+use crate::joypad::{self, button_from_name, Joypad, JoypadButtons};
+use crate::ppu::NesPPU;
+use crate::render;
+use crate::render::frame::Frame;
+use crate::render::palette;
+use crate::render::viewer;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Scripted joypad input, one change per line: `<frame> <buttons>...` with a
+// column per player. Buttons are joined with `+` (`a+right`) and `-` releases
+// everything; players without a column release everything too. Buttons stay
+// held until the next line.
+pub struct InputScript {
+    changes: Vec<(usize, [JoypadButtons; 4])>,
+}
+
+impl InputScript {
+    pub fn empty() -> Self {
+        InputScript { changes: vec![] }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut changes = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = || format!("Invalid input script line {}: {}", n + 1, line);
+
+            let mut parts = line.split_whitespace();
+            let frame = parts.next().and_then(|f| f.parse().ok()).ok_or_else(err)?;
+            let mut buttons = [JoypadButtons::empty(); 4];
+            let columns: Vec<&str> = parts.collect();
+            if columns.is_empty() || columns.len() > buttons.len() {
+                return Err(err());
+            }
+            for (player, column) in columns.into_iter().enumerate() {
+                if column != "-" {
+                    buttons[player] = column
+                        .split('+')
+                        .map(|name| button_from_name(name).ok_or_else(err))
+                        .collect::<Result<JoypadButtons, String>>()?;
+                }
+            }
+            changes.push((frame, buttons));
+        }
+        changes.sort_by_key(|(frame, _)| *frame);
+        Ok(InputScript { changes })
+    }
+
+    // Buttons each player holds during `frame`
+    pub fn buttons_at(&self, frame: usize) -> [JoypadButtons; 4] {
+        self.changes
+            .iter()
+            .take_while(|(at, _)| *at <= frame)
+            .last()
+            .map(|(_, buttons)| *buttons)
+            .unwrap_or([JoypadButtons::empty(); 4])
+    }
+}
+
+fn set_buttons(joypad: &mut Joypad, buttons: JoypadButtons) {
+    for button in joypad::buttons() {
+        joypad.set_button_pressed_status(button, buttons.contains(button));
+    }
+}
+
+// 64-bit FNV-1a, stable across platforms and runs
+pub fn frame_hash(frame: &Frame) -> u64 {
+    frame.data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub struct HeadlessOptions {
+    pub frames: usize,
+    pub input: InputScript,
+    // Devices to plug in, the ROM header decides the rest
+    pub multitap: Option<Multitap>,
+    pub ports: [Option<DeviceKind>; 2],
+    // Frame numbers (1-based) to save as PNG into `screenshot_dir`
+    pub screenshots: Vec<usize>,
+    pub screenshot_dir: PathBuf,
+    // Dump the PPU debug views here after the last frame
+    pub ppu_dump: Option<PathBuf>,
+}
+
+// Run `rom` for `options.frames` frames without a display. `on_frame` gets the
+// frame number and the rendered picture after every frame.
+pub fn run<F>(rom: Rom, options: &HeadlessOptions, mut on_frame: F) -> Result<(), String>
+where
+    F: FnMut(usize, &Frame),
+{
+    let expansion_device = rom.expansion_device;
+    let state = Rc::new(RefCell::new((0usize, Frame::new(), None::<String>)));
+    let callback_state = state.clone();
+
+    let mut bus = Bus::new(rom, move |ppu: &NesPPU, controllers: &mut Controllers| {
+        let (count, frame, error) = &mut *callback_state.borrow_mut();
+        *count += 1;
+        render::render(ppu, frame);
+        on_frame(*count, frame);
+
+        if options.screenshots.contains(count) {
+            let path = options
+                .screenshot_dir
+                .join(format!("frame_{:05}.png", count));
+            if let Err(err) = frame.save_png(&path) {
+                error.get_or_insert(err);
+            }
+        }
+        if *count == options.frames {
+            if let Some(dir) = &options.ppu_dump {
+                if let Err(err) = viewer::save_all(ppu, &palette::SYSTEM_PALETE, dir, 0) {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        let buttons = options.input.buttons_at(*count + 1);
+        for (player, buttons) in buttons.into_iter().enumerate() {
+            if let Some(joypad) = controllers.joypad_mut(player) {
+                set_buttons(joypad, buttons);
+            }
+        }
+    });
+
+    if !options.screenshots.is_empty() {
+        std::fs::create_dir_all(&options.screenshot_dir)
+            .map_err(|e| format!("{}: {}", options.screenshot_dir.display(), e))?;
+    }
+
+    bus.controllers_mut()
+        .configure(options.multitap, options.ports, expansion_device);
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    while state.borrow().0 < options.frames {
+        if !cpu.step() {
+            return Err(format!("BRK at {:04X}", cpu.program_counter - 1));
+        }
+        if let Some(err) = state.borrow_mut().2.take() {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// Load an input script file, or no input at all
+pub fn load_input(path: Option<&Path>) -> Result<InputScript, String> {
+    match path {
+        Some(path) => {
+            let text =
+                std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            InputScript::parse(&text)
+        }
+        None => Ok(InputScript::empty()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_input_script() {
+        let script = InputScript::parse("# boot\n10 start\n12 -\n20 a+Right - b\n").unwrap();
+        let none = JoypadButtons::empty();
+        assert_eq!(script.buttons_at(9), [none; 4]);
+        assert_eq!(
+            script.buttons_at(10),
+            [JoypadButtons::Start, none, none, none]
+        );
+        assert_eq!(script.buttons_at(15), [none; 4]);
+        assert_eq!(
+            script.buttons_at(100),
+            [
+                JoypadButtons::ButtonA | JoypadButtons::Right,
+                none,
+                JoypadButtons::ButtonB,
+                none
+            ]
+        );
+
+        assert!(InputScript::parse("10 turbo").is_err());
+        assert!(InputScript::parse("soon start").is_err());
+        assert!(InputScript::parse("10").is_err());
+        assert!(InputScript::parse("10 a a a a a").is_err());
+    }
+
+    fn nestest_frames(options: &HeadlessOptions) -> Vec<(u64, Vec<u8>)> {
+        let raw = std::fs::read("roms/nestest.nes").unwrap();
+        let mut frames = vec![];
+        run(Rom::new(&raw).unwrap(), options, |_, frame| {
+            frames.push((frame_hash(frame), frame.data.clone()));
+        })
+        .unwrap();
+        frames
+    }
+
+    fn options(frames: usize, input: &str) -> HeadlessOptions {
+        HeadlessOptions {
+            frames,
+            input: InputScript::parse(input).unwrap(),
+            multitap: None,
+            ports: [None; 2],
+            screenshots: vec![],
+            screenshot_dir: PathBuf::new(),
+            ppu_dump: None,
+        }
+    }
+
+    #[test]
+    fn test_runs_are_deterministic() {
+        let first = nestest_frames(&options(10, ""));
+        let second = nestest_frames(&options(10, ""));
+        assert_eq!(first.len(), 10);
+        let hashes = |frames: &[(u64, Vec<u8>)]| frames.iter().map(|f| f.0).collect::<Vec<_>>();
+        assert_eq!(hashes(&first), hashes(&second));
+
+        // Down moves the menu cursor
+        let moved = nestest_frames(&options(10, "5 down\n6 -"));
+        assert_ne!(moved.last().unwrap().0, first.last().unwrap().0);
+    }
+
+    // Compares against a golden PNG under logs/golden. Set UPDATE_GOLDEN=1 to
+    // rewrite it after an intended rendering change.
+    #[test]
+    fn test_nestest_menu_golden() {
+        let golden = Path::new("logs/golden/nestest_menu.png");
+        let frames = nestest_frames(&options(30, ""));
+        let (_, data) = frames.last().unwrap();
+
+        let mut actual = Frame::new();
+        actual.data = data.clone();
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+            actual.save_png(golden).unwrap();
+        }
+
+        let decoder = png::Decoder::new(std::fs::File::open(golden).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut expected = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut expected).unwrap();
+        assert!(
+            expected == actual.data,
+            "frame differs from {}",
+            golden.display()
+        );
+    }
+}
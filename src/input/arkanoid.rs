@@ -0,0 +1,91 @@
+use crate::input::InputDevice;
+use std::any::Any;
+
+// Knob range the Vaus reports, from left to right
+const MIN_POSITION: u8 = 0x62;
+const MAX_POSITION: u8 = 0xf2;
+
+// Arkanoid "Vaus" controller on the NES port: D3 is the fire button, D4
+// shifts out the knob position inverted, MSB first, latched on the strobe
+pub struct ArkanoidPaddle {
+    position: u8,
+    button: bool,
+    strobe: bool,
+    latch: u8,
+}
+
+impl Default for ArkanoidPaddle {
+    fn default() -> Self {
+        ArkanoidPaddle::new()
+    }
+}
+
+impl ArkanoidPaddle {
+    pub fn new() -> Self {
+        ArkanoidPaddle {
+            position: (MIN_POSITION as u16 + MAX_POSITION as u16).div_ceil(2) as u8,
+            button: false,
+            strobe: false,
+            latch: 0,
+        }
+    }
+
+    // Set the knob from a screen column, 0 is the far left
+    pub fn set_x(&mut self, x: u8) {
+        let range = (MAX_POSITION - MIN_POSITION) as u16;
+        self.position = MIN_POSITION + (x as u16 * range / 255) as u8;
+    }
+
+    pub fn set_button(&mut self, pressed: bool) {
+        self.button = pressed;
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch = !self.position;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let bit = self.latch >> 7;
+        if !self.strobe {
+            // Ones shift in behind the value, reading 0 once the knob bits are out
+            self.latch = self.latch << 1 | 1;
+        }
+        (self.button as u8) << 3 | bit << 4
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_position_and_button() {
+        let mut paddle = ArkanoidPaddle::new();
+        paddle.set_x(0);
+        paddle.set_button(true);
+        paddle.write(1);
+        paddle.write(0);
+
+        let mut value = 0;
+        for _ in 0..8 {
+            let bits = paddle.read();
+            assert_eq!(bits & 0b1000, 0b1000);
+            value = value << 1 | bits >> 4 & 1;
+        }
+        assert_eq!(!value, MIN_POSITION);
+
+        paddle.set_x(255);
+        paddle.write(1);
+        assert_eq!(paddle.read() >> 4, (!MAX_POSITION) >> 7);
+        assert_eq!(paddle.latch, !MAX_POSITION);
+    }
+}
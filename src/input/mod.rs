@@ -0,0 +1,250 @@
+use crate::joypad::Joypad;
+use crate::ppu::NesPPU;
+use std::any::Any;
+
+pub mod arkanoid;
+pub mod multitap;
+pub mod power_pad;
+pub mod snes_mouse;
+pub mod zapper;
+
+use arkanoid::ArkanoidPaddle;
+use multitap::{Multitap, MultitapPort};
+use power_pad::PowerPad;
+use snes_mouse::SnesMouse;
+use zapper::Zapper;
+
+// Something plugged into a controller port
+pub trait InputDevice {
+    // $4016 writes, bit 0 is the strobe shared by both ports
+    fn write(&mut self, data: u8);
+
+    // Data lines D0-D4 of the port; reading clocks the device
+    fn read(&mut self) -> u8;
+
+    // Called with the PPU right before every read, for devices that look at
+    // the screen
+    fn watch(&mut self, _ppu: &NesPPU) {}
+
+    // Called at the start of every frame, before the frontend sets the input
+    fn next_frame(&mut self) {}
+
+    // So the frontend can reach the concrete device to feed it input
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+// An empty port reads back 0
+pub struct Unplugged;
+
+impl InputDevice for Unplugged {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self) -> u8 {
+        0
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    None,
+    Joypad,
+    Arkanoid,
+    PowerPad,
+    SnesMouse,
+    Zapper,
+}
+
+impl std::str::FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Ok(DeviceKind::None),
+            "joypad" => Ok(DeviceKind::Joypad),
+            "arkanoid" => Ok(DeviceKind::Arkanoid),
+            "powerpad" | "power-pad" => Ok(DeviceKind::PowerPad),
+            "snes-mouse" | "mouse" => Ok(DeviceKind::SnesMouse),
+            "zapper" => Ok(DeviceKind::Zapper),
+            _ => Err(format!(
+                "Unknown input device: {} (none, joypad, arkanoid, powerpad, snes-mouse, zapper)",
+                value
+            )),
+        }
+    }
+}
+
+impl DeviceKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::None => "none",
+            DeviceKind::Joypad => "joypad",
+            DeviceKind::Arkanoid => "arkanoid",
+            DeviceKind::PowerPad => "powerpad",
+            DeviceKind::SnesMouse => "snes-mouse",
+            DeviceKind::Zapper => "zapper",
+        }
+    }
+
+    fn create(&self) -> Box<dyn InputDevice> {
+        match self {
+            DeviceKind::None => Box::new(Unplugged),
+            DeviceKind::Joypad => Box::new(Joypad::new()),
+            DeviceKind::Arkanoid => Box::new(ArkanoidPaddle::new()),
+            DeviceKind::PowerPad => Box::new(PowerPad::new()),
+            DeviceKind::SnesMouse => Box::new(SnesMouse::new()),
+            DeviceKind::Zapper => Box::new(Zapper::new()),
+        }
+    }
+}
+
+// The devices a NES 2.0 "default expansion device" asks for: the multitap and
+// what goes into each port. None if unspecified or not emulated.
+pub fn expansion_device(id: u8) -> Option<(Multitap, [DeviceKind; 2])> {
+    let pads = [DeviceKind::Joypad, DeviceKind::Joypad];
+    match id {
+        0x01 => Some((Multitap::None, pads)),
+        0x02 => Some((Multitap::FourScore, pads)),
+        0x08 => Some((Multitap::None, [DeviceKind::Joypad, DeviceKind::Zapper])),
+        0x09 => Some((Multitap::None, [DeviceKind::Zapper, DeviceKind::Zapper])),
+        0x0B | 0x0C => Some((Multitap::None, [DeviceKind::Joypad, DeviceKind::PowerPad])),
+        0x0F => Some((Multitap::None, [DeviceKind::Joypad, DeviceKind::Arkanoid])),
+        _ => None,
+    }
+}
+
+// Controller ports $4016 and $4017
+pub struct Controllers {
+    ports: [Box<dyn InputDevice>; 2],
+}
+
+impl Default for Controllers {
+    fn default() -> Self {
+        Controllers::new()
+    }
+}
+
+impl Controllers {
+    pub fn new() -> Self {
+        Controllers {
+            ports: [Box::new(Joypad::new()), Box::new(Joypad::new())],
+        }
+    }
+
+    // Plug in what was asked for on the command line or in the config, then
+    // what the ROM header asks for, then standard pads
+    pub fn configure(
+        &mut self,
+        multitap: Option<Multitap>,
+        ports: [Option<DeviceKind>; 2],
+        header_device: u8,
+    ) {
+        let (default_multitap, default_ports) = expansion_device(header_device)
+            .unwrap_or((Multitap::None, [DeviceKind::Joypad, DeviceKind::Joypad]));
+        let multitap = multitap.unwrap_or(default_multitap);
+        self.set_multitap(multitap);
+        if multitap == Multitap::None {
+            for (port, device) in ports.into_iter().enumerate() {
+                self.plug(port, device.unwrap_or(default_ports[port]));
+            }
+        }
+    }
+
+    pub fn plug(&mut self, port: usize, device: DeviceKind) {
+        self.ports[port] = device.create();
+    }
+
+    // A multitap takes both ports, `Multitap::None` goes back to two pads
+    pub fn set_multitap(&mut self, multitap: Multitap) {
+        for (port, device) in self.ports.iter_mut().enumerate() {
+            *device = match multitap {
+                Multitap::None => Box::new(Joypad::new()),
+                _ => Box::new(MultitapPort::new(multitap, port)),
+            };
+        }
+    }
+
+    pub fn device_mut<T: InputDevice + 'static>(&mut self, port: usize) -> Option<&mut T> {
+        self.ports[port].as_any_mut().downcast_mut()
+    }
+
+    // Pad of player 1-4 (0-3), wherever it's plugged in
+    pub fn joypad_mut(&mut self, player: usize) -> Option<&mut Joypad> {
+        let (port, index) = (player % 2, player / 2);
+        let device = self.ports.get_mut(port)?.as_any_mut();
+        if device.is::<Joypad>() {
+            return device.downcast_mut().filter(|_| index == 0);
+        }
+        device
+            .downcast_mut::<MultitapPort>()
+            .and_then(|multitap| multitap.joypads.get_mut(index))
+    }
+
+    // $4016 writes strobe both ports at once
+    pub fn write(&mut self, data: u8) {
+        for device in &mut self.ports {
+            device.write(data);
+        }
+    }
+
+    pub fn next_frame(&mut self) {
+        for device in &mut self.ports {
+            device.next_frame();
+        }
+    }
+
+    pub fn watch(&mut self, port: usize, ppu: &NesPPU) {
+        self.ports[port].watch(ppu);
+    }
+
+    // Read $4016 (port 0) or $4017 (port 1)
+    pub fn read(&mut self, port: usize) -> u8 {
+        self.ports[port].read()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plug_devices() {
+        let mut controllers = Controllers::new();
+        assert!(controllers.joypad_mut(1).is_some());
+
+        controllers.plug(1, DeviceKind::Arkanoid);
+        assert!(controllers.joypad_mut(1).is_none());
+        assert!(controllers.device_mut::<Joypad>(1).is_none());
+        controllers
+            .device_mut::<ArkanoidPaddle>(1)
+            .unwrap()
+            .set_button(true);
+        assert_eq!(controllers.read(1) & 0b1000, 0b1000);
+
+        controllers.plug(0, DeviceKind::None);
+        assert_eq!(controllers.read(0), 0);
+        assert!(controllers.joypad_mut(0).is_none());
+    }
+
+    #[test]
+    fn test_expansion_device() {
+        let (multitap, ports) = expansion_device(0x0F).unwrap();
+        assert_eq!(multitap, Multitap::None);
+        assert_eq!(ports, [DeviceKind::Joypad, DeviceKind::Arkanoid]);
+        assert_eq!(expansion_device(0x02).unwrap().0, Multitap::FourScore);
+        assert!(expansion_device(0x00).is_none());
+
+        // The header only fills in what wasn't asked for
+        let mut controllers = Controllers::new();
+        controllers.configure(None, [Some(DeviceKind::SnesMouse), None], 0x0F);
+        assert!(controllers.device_mut::<SnesMouse>(0).is_some());
+        assert!(controllers.device_mut::<ArkanoidPaddle>(1).is_some());
+        controllers.configure(None, [None, None], 0x02);
+        assert!(controllers.joypad_mut(3).is_some());
+        assert!("paddle".parse::<DeviceKind>().is_err());
+        assert_eq!("snes-mouse".parse(), Ok(DeviceKind::SnesMouse));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::input::InputDevice;
+use crate::joypad::Joypad;
+use std::any::Any;
+
+// 4 player adapters. Both shift out 8 bits of one pad, 8 bits of another and
+// an 8 bit signature on each port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Multitap {
+    None,
+    // NES Four Score / Satellite, on D0
+    FourScore,
+    // Hori 4 Players Adapter on the Famicom expansion port, on D1
+    Famicom,
+}
+
+impl std::str::FromStr for Multitap {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Ok(Multitap::None),
+            "fourscore" | "four-score" => Ok(Multitap::FourScore),
+            "famicom" | "hori" => Ok(Multitap::Famicom),
+            _ => Err(format!(
+                "Unknown multitap: {} (none, fourscore, famicom)",
+                value
+            )),
+        }
+    }
+}
+
+impl Multitap {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Multitap::None => "none",
+            Multitap::FourScore => "fourscore",
+            Multitap::Famicom => "famicom",
+        }
+    }
+
+    // Signature bits 16-23 of $4016 and $4017, in the order they're read
+    fn signature(&self, port: usize) -> u8 {
+        match (self, port) {
+            (Multitap::FourScore, 0) => 0b0000_1000,
+            (Multitap::FourScore, _) => 0b0000_0100,
+            (_, 0) => 0b0000_0100,
+            (_, _) => 0b0000_1000,
+        }
+    }
+}
+
+// One port of a multitap: pads 1 and 3 on $4016, 2 and 4 on $4017
+pub struct MultitapPort {
+    multitap: Multitap,
+    port: usize,
+    pub joypads: [Joypad; 2],
+    strobe: bool,
+    // Bits shifted out since the strobe
+    reads: u8,
+}
+
+impl MultitapPort {
+    pub fn new(multitap: Multitap, port: usize) -> Self {
+        MultitapPort {
+            multitap,
+            port,
+            joypads: [Joypad::new(), Joypad::new()],
+            strobe: false,
+            reads: 0,
+        }
+    }
+}
+
+impl InputDevice for MultitapPort {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.reads = 0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let index = self.reads;
+        let bits = match index / 8 {
+            0 => self.joypads[0].buttons().bits(),
+            1 => self.joypads[1].buttons().bits(),
+            2 => self.multitap.signature(self.port),
+            // Like a standard pad, 1s once everything is shifted out
+            _ => 0xff,
+        };
+        if !self.strobe && index < 24 {
+            self.reads += 1;
+        }
+        let bit = bits >> (index % 8) & 1;
+        match self.multitap {
+            Multitap::Famicom => bit << 1,
+            _ => bit,
+        }
+    }
+
+    fn next_frame(&mut self) {
+        for joypad in &mut self.joypads {
+            joypad.next_frame();
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::Controllers;
+    use crate::joypad::JoypadButtons;
+
+    fn read_bits(controllers: &mut Controllers, port: usize, count: usize) -> Vec<u8> {
+        (0..count).map(|_| controllers.read(port)).collect()
+    }
+
+    fn setup(multitap: Multitap) -> Controllers {
+        let mut controllers = Controllers::new();
+        controllers.set_multitap(multitap);
+        let buttons = [
+            JoypadButtons::ButtonA,
+            JoypadButtons::Start,
+            JoypadButtons::ButtonB,
+            JoypadButtons::Right,
+        ];
+        // Without a multitap pads 3 and 4 have nowhere to go
+        for (player, button) in buttons.into_iter().enumerate() {
+            if let Some(joypad) = controllers.joypad_mut(player) {
+                joypad.set_button_pressed_status(button, true);
+            }
+        }
+        controllers.write(1);
+        controllers.write(0);
+        controllers
+    }
+
+    #[test]
+    fn test_two_pads() {
+        let mut controllers = setup(Multitap::None);
+        assert!(controllers.joypad_mut(2).is_none());
+        assert_eq!(
+            read_bits(&mut controllers, 0, 9),
+            [1, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            read_bits(&mut controllers, 1, 9),
+            [0, 0, 0, 1, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_four_score() {
+        let mut controllers = setup(Multitap::FourScore);
+        let port0 = read_bits(&mut controllers, 0, 25);
+        assert_eq!(port0[..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(port0[8..16], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(port0[16..], [0, 0, 0, 1, 0, 0, 0, 0, 1]);
+
+        let port1 = read_bits(&mut controllers, 1, 24);
+        assert_eq!(port1[8..16], [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(port1[16..], [0, 0, 1, 0, 0, 0, 0, 0]);
+
+        // Strobing restarts both ports
+        controllers.write(1);
+        controllers.write(0);
+        assert_eq!(controllers.read(0), 1);
+    }
+
+    #[test]
+    fn test_famicom_adapter() {
+        let mut controllers = setup(Multitap::Famicom);
+        let port0 = read_bits(&mut controllers, 0, 24);
+        assert_eq!(port0[0], 0b10);
+        assert_eq!(port0[9], 0b10);
+        assert_eq!(port0[16..], [0, 0, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            read_bits(&mut controllers, 1, 24)[16..],
+            [0, 0, 0, 2, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::input::InputDevice;
+use std::any::Any;
+
+// Order the Power Pad shifts its buttons out on D3 and D4
+const D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+// Bandai / Nintendo Power Pad (Family Trainer) with its 12 buttons numbered
+// as printed on side B:
+//    1  2  3  4
+//    5  6  7  8
+//    9 10 11 12
+pub struct PowerPad {
+    // Bit n - 1 set while button n is pressed
+    buttons: u16,
+    strobe: bool,
+    latch: u16,
+    reads: usize,
+}
+
+impl Default for PowerPad {
+    fn default() -> Self {
+        PowerPad::new()
+    }
+}
+
+impl PowerPad {
+    pub fn new() -> Self {
+        PowerPad {
+            buttons: 0,
+            strobe: false,
+            latch: 0,
+            reads: 0,
+        }
+    }
+
+    // `button` is 1-12
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if (1..=12).contains(&button) {
+            let mask = 1 << (button - 1);
+            if pressed {
+                self.buttons |= mask;
+            } else {
+                self.buttons &= !mask;
+            }
+        }
+    }
+
+    fn latched(&self, button: Option<&u8>) -> u8 {
+        match button {
+            Some(button) => (self.latch >> (button - 1) & 1) as u8,
+            None => 1,
+        }
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch = self.buttons;
+            self.reads = 0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let d3 = self.latched(D3_ORDER.get(self.reads));
+        let d4 = self.latched(D4_ORDER.get(self.reads));
+        if !self.strobe && self.reads < D3_ORDER.len() {
+            self.reads += 1;
+        }
+        d3 << 3 | d4 << 4
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serial_order() {
+        let mut pad = PowerPad::new();
+        pad.set_button(1, true);
+        pad.set_button(12, true);
+        pad.set_button(13, true);
+        pad.write(1);
+        pad.write(0);
+
+        let reads: Vec<u8> = (0..9).map(|_| pad.read()).collect();
+        // button 1 is the second D3 bit, button 12 the third D4 bit, then D4
+        // runs out after 4 bits and D3 after 8
+        assert_eq!(
+            reads,
+            [0, 0b01000, 0b10000, 0, 0b10000, 0b10000, 0b10000, 0b10000, 0b11000]
+        );
+    }
+}
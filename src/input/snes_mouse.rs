@@ -0,0 +1,135 @@
+use crate::input::InputDevice;
+use std::any::Any;
+
+// Largest movement one report can carry on each axis
+const MAX_DELTA: i32 = 127;
+
+// Super Famicom mouse through an adapter on the NES port. Each strobe latches
+// a 32 bit report shifted out MSB first on D0:
+//   byte 0  $00
+//   byte 1  right, left, sensitivity (2 bits), signature %0001
+//   byte 2  Y: sign (1 = up), 7 bit magnitude
+//   byte 3  X: sign (1 = left), 7 bit magnitude
+pub struct SnesMouse {
+    dx: i32,
+    dy: i32,
+    left: bool,
+    right: bool,
+    sensitivity: u8,
+    strobe: bool,
+    latch: u32,
+    reads: u8,
+}
+
+fn axis(delta: i32) -> u32 {
+    let magnitude = delta.unsigned_abs().min(MAX_DELTA as u32);
+    if delta < 0 {
+        0x80 | magnitude
+    } else {
+        magnitude
+    }
+}
+
+impl Default for SnesMouse {
+    fn default() -> Self {
+        SnesMouse::new()
+    }
+}
+
+impl SnesMouse {
+    pub fn new() -> Self {
+        SnesMouse {
+            dx: 0,
+            dy: 0,
+            left: false,
+            right: false,
+            sensitivity: 0,
+            strobe: false,
+            latch: 0,
+            reads: 0,
+        }
+    }
+
+    // Movement since the last report, in mouse counts
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        self.dx += dx;
+        self.dy += dy;
+    }
+
+    pub fn set_left(&mut self, pressed: bool) {
+        self.left = pressed;
+    }
+
+    pub fn set_right(&mut self, pressed: bool) {
+        self.right = pressed;
+    }
+
+    fn report(&mut self) -> u32 {
+        let (dx, dy) = (
+            self.dx.clamp(-MAX_DELTA, MAX_DELTA),
+            self.dy.clamp(-MAX_DELTA, MAX_DELTA),
+        );
+        // Whatever didn't fit goes out with the next report
+        self.dx -= dx;
+        self.dy -= dy;
+
+        let status = (self.right as u32) << 7
+            | (self.left as u32) << 6
+            | (self.sensitivity as u32) << 4
+            | 0b0001;
+        status << 16 | axis(dy) << 8 | axis(dx)
+    }
+}
+
+impl InputDevice for SnesMouse {
+    fn write(&mut self, data: u8) {
+        let strobe = data & 1 == 1;
+        if strobe && !self.strobe {
+            self.latch = self.report();
+            self.reads = 0;
+        }
+        self.strobe = strobe;
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.reads >= 32 {
+            return 1;
+        }
+        let bit = (self.latch >> (31 - self.reads) & 1) as u8;
+        if self.strobe {
+            // Clocking while strobed cycles the sensitivity instead
+            self.sensitivity = (self.sensitivity + 1) % 3;
+        } else {
+            self.reads += 1;
+        }
+        bit
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_report(mouse: &mut SnesMouse) -> u32 {
+        mouse.write(1);
+        mouse.write(0);
+        (0..32).fold(0, |report, _| report << 1 | mouse.read() as u32)
+    }
+
+    #[test]
+    fn test_report() {
+        let mut mouse = SnesMouse::new();
+        mouse.move_by(-3, 200);
+        mouse.set_left(true);
+        assert_eq!(read_report(&mut mouse), 0x0041_7f83);
+        assert_eq!(mouse.read(), 1);
+
+        // The rest of the Y movement comes with the next report
+        mouse.set_left(false);
+        assert_eq!(read_report(&mut mouse), 0x0001_4900);
+    }
+}
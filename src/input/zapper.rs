@@ -0,0 +1,177 @@
+use crate::input::InputDevice;
+use crate::ppu::NesPPU;
+use crate::render;
+use crate::render::frame::Frame;
+use std::any::Any;
+
+// The photodiode keeps seeing a bright spot for a while after the beam
+// passed it
+const LIGHT_SCANLINES: u16 = 20;
+// Pixels around the cursor averaged for brightness
+const SENSE_RADIUS: i32 = 2;
+// Average luma (0-255) that counts as light
+const LIGHT_THRESHOLD: u32 = 0x80;
+
+// Zapper light gun: D3 is 0 while the sensor sees light, D4 is the trigger.
+// The picture is rendered from the PPU once the beam reaches the cursor each
+// frame, so what the game draws for the hit test is what the sensor sees.
+pub struct Zapper {
+    // Screen pixel the gun points at, None when aimed off screen
+    cursor: Option<(i32, i32)>,
+    trigger: bool,
+    light: bool,
+    frame: Frame,
+    // `frame` shows the frame being drawn now
+    rendered: bool,
+    last_scanline: u16,
+}
+
+fn luma((r, g, b): (u8, u8, u8)) -> u32 {
+    (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Zapper::new()
+    }
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            cursor: None,
+            trigger: false,
+            light: false,
+            frame: Frame::new(),
+            rendered: false,
+            last_scanline: 0,
+        }
+    }
+
+    pub fn set_cursor(&mut self, cursor: Option<(i32, i32)>) {
+        self.cursor = cursor;
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+
+    fn brightness(&self, x: i32, y: i32) -> u32 {
+        let (mut total, mut count) = (0, 0);
+        for py in y - SENSE_RADIUS..=y + SENSE_RADIUS {
+            for px in x - SENSE_RADIUS..=x + SENSE_RADIUS {
+                if px < 0
+                    || py < 0
+                    || px >= self.frame.width as i32
+                    || py >= self.frame.height as i32
+                {
+                    continue;
+                }
+                let base = (py as usize * self.frame.width + px as usize) * 3;
+                let data = &self.frame.data;
+                total += luma((data[base], data[base + 1], data[base + 2]));
+                count += 1;
+            }
+        }
+        total.checked_div(count).unwrap_or(0)
+    }
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self) -> u8 {
+        (!self.light as u8) << 3 | (self.trigger as u8) << 4
+    }
+
+    fn watch(&mut self, ppu: &NesPPU) {
+        let (scanline, dot) = (ppu.scanline(), ppu.dot() as i32);
+        if scanline < self.last_scanline {
+            self.rendered = false;
+        }
+        self.last_scanline = scanline;
+
+        self.light = match self.cursor {
+            Some((x, y)) if y >= 0 && (0..256).contains(&x) => {
+                let y = y as u16;
+                let passed = scanline > y || (scanline == y && dot >= x);
+                if passed && scanline < y + LIGHT_SCANLINES {
+                    if !self.rendered {
+                        render::render(ppu, &mut self.frame);
+                        self.rendered = true;
+                    }
+                    self.brightness(x, y as i32) >= LIGHT_THRESHOLD
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    // Background all black except a white 8x8 tile at column 4, row 10
+    fn test_ppu() -> NesPPU {
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xff);
+        chr[24..32].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::Horizontal);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[3] = 0x30;
+        ppu.vram[10 * 32 + 4] = 1;
+        ppu.oam.fill(0xff);
+        ppu
+    }
+
+    fn run_to_scanline(ppu: &mut NesPPU, scanline: u16) {
+        while ppu.scanline() != scanline {
+            ppu.tick(100);
+        }
+    }
+
+    fn sense(zapper: &mut Zapper, ppu: &NesPPU) -> u8 {
+        zapper.watch(ppu);
+        zapper.read()
+    }
+
+    #[test]
+    fn test_light_follows_beam() {
+        let mut ppu = test_ppu();
+        let mut zapper = Zapper::new();
+        zapper.set_cursor(Some((36, 84)));
+
+        // Before the beam gets to the cursor there's no light
+        run_to_scanline(&mut ppu, 60);
+        assert_eq!(sense(&mut zapper, &ppu), 0b01000);
+
+        run_to_scanline(&mut ppu, 86);
+        assert_eq!(sense(&mut zapper, &ppu), 0);
+        zapper.set_trigger(true);
+        assert_eq!(sense(&mut zapper, &ppu), 0b10000);
+
+        // The spot fades once the beam has moved on
+        run_to_scanline(&mut ppu, 84 + LIGHT_SCANLINES);
+        assert_eq!(sense(&mut zapper, &ppu), 0b11000);
+    }
+
+    #[test]
+    fn test_dark_or_off_screen() {
+        let mut ppu = test_ppu();
+        let mut zapper = Zapper::new();
+        zapper.set_cursor(Some((100, 84)));
+        run_to_scanline(&mut ppu, 86);
+        assert_eq!(sense(&mut zapper, &ppu), 0b01000);
+
+        zapper.set_cursor(None);
+        assert_eq!(sense(&mut zapper, &ppu), 0b01000);
+    }
+}
@@ -1,5 +1,8 @@
+use crate::input::InputDevice;
+use std::any::Any;
+
 bitflags! {
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub struct JoypadButtons: u8 {
         const Right = 0b10000000;
         const Left = 0b01000000;
@@ -12,10 +15,122 @@ bitflags! {
     }
 }
 
+// Every button in the order the shift register reports them, with the
+// lowercase names config files and input scripts use
+const BUTTON_NAMES: [(JoypadButtons, &str); 8] = [
+    (JoypadButtons::ButtonA, "a"),
+    (JoypadButtons::ButtonB, "b"),
+    (JoypadButtons::Select, "select"),
+    (JoypadButtons::Start, "start"),
+    (JoypadButtons::Up, "up"),
+    (JoypadButtons::Down, "down"),
+    (JoypadButtons::Left, "left"),
+    (JoypadButtons::Right, "right"),
+];
+
+pub fn buttons() -> impl Iterator<Item = JoypadButtons> {
+    BUTTON_NAMES.into_iter().map(|(button, _)| button)
+}
+
+pub fn button_name(button: JoypadButtons) -> &'static str {
+    BUTTON_NAMES
+        .iter()
+        .find(|(b, _)| *b == button)
+        .map_or("?", |(_, name)| name)
+}
+
+pub fn button_from_name(name: &str) -> Option<JoypadButtons> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(_, n)| n.eq_ignore_ascii_case(name))
+        .map(|(button, _)| *button)
+}
+
+pub const DEFAULT_TURBO_RATE: u8 = 2;
+
+// Buttons held for each frame in turn
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputMacro {
+    frames: Vec<JoypadButtons>,
+}
+
+impl InputMacro {
+    pub fn new(frames: Vec<JoypadButtons>) -> Self {
+        InputMacro { frames }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Comma separated steps of `buttons[*frames]`, like `down+b*3,-,a*2`,
+    // where `-` is no buttons
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut frames = vec![];
+        for step in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let err = || format!("Invalid macro step: {}", step);
+            let (names, count) = match step.split_once('*') {
+                Some((names, count)) => (names, count.trim().parse().map_err(|_| err())?),
+                None => (step, 1),
+            };
+            let buttons = match names.trim() {
+                "-" => JoypadButtons::empty(),
+                names => names
+                    .split('+')
+                    .map(|name| button_from_name(name.trim()).ok_or_else(err))
+                    .collect::<Result<JoypadButtons, String>>()?,
+            };
+            frames.extend(std::iter::repeat_n(buttons, count));
+        }
+        Ok(InputMacro { frames })
+    }
+}
+
+impl std::fmt::Display for InputMacro {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut steps = vec![];
+        let mut frames = self.frames.iter().peekable();
+        while let Some(buttons) = frames.next() {
+            let mut count = 1;
+            while frames.next_if_eq(&buttons).is_some() {
+                count += 1;
+            }
+            let names = if buttons.is_empty() {
+                "-".to_string()
+            } else {
+                self::buttons()
+                    .filter(|b| buttons.contains(*b))
+                    .map(button_name)
+                    .collect::<Vec<_>>()
+                    .join("+")
+            };
+            steps.push(match count {
+                1 => names,
+                _ => format!("{}*{}", names, count),
+            });
+        }
+        write!(f, "{}", steps.join(","))
+    }
+}
+
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: JoypadButtons,
+
+    // Held turbo buttons alternate between pressed and released every
+    // `turbo_rate` frames
+    turbo: JoypadButtons,
+    turbo_rate: u8,
+    frame: usize,
+    // Pressing left+right or up+down at once, which a real pad can't do
+    allow_opposing: bool,
+    playback: Option<(InputMacro, usize)>,
+    recording: Option<Vec<JoypadButtons>>,
 }
 
 impl Joypad {
@@ -24,6 +139,12 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButtons::from_bits_truncate(0),
+            turbo: JoypadButtons::empty(),
+            turbo_rate: DEFAULT_TURBO_RATE,
+            frame: 0,
+            allow_opposing: false,
+            playback: None,
+            recording: None,
         }
     }
 
@@ -39,7 +160,7 @@ impl Joypad {
             return 1;
         }
 
-        let response = (self.button_status.bits() & (1 << self.button_index)) >> self.button_index;
+        let response = (self.buttons().bits() & (1 << self.button_index)) >> self.button_index;
         if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
@@ -50,4 +171,158 @@ impl Joypad {
     pub fn set_button_pressed_status(&mut self, button: JoypadButtons, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn set_turbo_pressed_status(&mut self, button: JoypadButtons, pressed: bool) {
+        self.turbo.set(button, pressed);
+    }
+
+    // Frames a turbo button stays pressed and then released, at least 1
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.turbo_rate = frames.max(1);
+    }
+
+    pub fn set_allow_opposing(&mut self, allow: bool) {
+        self.allow_opposing = allow;
+    }
+
+    pub fn play_macro(&mut self, input_macro: InputMacro) {
+        self.playback = Some((input_macro, 0));
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    // Record the buttons held each frame until `stop_recording`
+    pub fn start_recording(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<InputMacro> {
+        self.recording.take().map(InputMacro::new)
+    }
+
+    // Buttons as the game sees them this frame
+    pub fn buttons(&self) -> JoypadButtons {
+        let mut buttons = self.button_status;
+        if (self.frame / self.turbo_rate as usize).is_multiple_of(2) {
+            buttons |= self.turbo;
+        }
+        if let Some((input_macro, step)) = &self.playback {
+            buttons |= input_macro.frames[*step];
+        }
+
+        if !self.allow_opposing {
+            for pair in [
+                JoypadButtons::Left | JoypadButtons::Right,
+                JoypadButtons::Up | JoypadButtons::Down,
+            ] {
+                if buttons.contains(pair) {
+                    buttons.remove(pair);
+                }
+            }
+        }
+        buttons
+    }
+
+    // Called at the start of every frame
+    pub fn next_frame(&mut self) {
+        if let Some(recording) = &mut self.recording {
+            recording.push(self.button_status | self.turbo);
+        }
+        self.frame = self.frame.wrapping_add(1);
+        if let Some((input_macro, step)) = &mut self.playback {
+            *step += 1;
+            if *step >= input_macro.len() {
+                self.playback = None;
+            }
+        }
+    }
+}
+
+impl InputDevice for Joypad {
+    fn write(&mut self, data: u8) {
+        Joypad::write(self, data)
+    }
+
+    fn read(&mut self) -> u8 {
+        Joypad::read(self)
+    }
+
+    fn next_frame(&mut self) {
+        Joypad::next_frame(self)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_turbo_and_opposing() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo_pressed_status(JoypadButtons::ButtonA, true);
+        let pressed: Vec<bool> = (0..6)
+            .map(|_| {
+                let pressed = joypad.buttons().contains(JoypadButtons::ButtonA);
+                joypad.next_frame();
+                pressed
+            })
+            .collect();
+        assert_eq!(pressed, [true, true, false, false, true, true]);
+
+        joypad.set_turbo_pressed_status(JoypadButtons::ButtonA, false);
+        joypad.set_button_pressed_status(JoypadButtons::Left, true);
+        joypad.set_button_pressed_status(JoypadButtons::Right, true);
+        joypad.set_button_pressed_status(JoypadButtons::Up, true);
+        assert_eq!(joypad.buttons(), JoypadButtons::Up);
+        joypad.set_allow_opposing(true);
+        assert!(joypad
+            .buttons()
+            .contains(JoypadButtons::Left | JoypadButtons::Right));
+    }
+
+    #[test]
+    fn test_record_and_play_macro() {
+        let mut joypad = Joypad::new();
+        joypad.start_recording();
+        for buttons in [
+            JoypadButtons::Down,
+            JoypadButtons::Down,
+            JoypadButtons::empty(),
+        ] {
+            joypad.button_status = buttons;
+            joypad.next_frame();
+        }
+        joypad.button_status = JoypadButtons::ButtonB;
+        joypad.next_frame();
+        let recorded = joypad.stop_recording().unwrap();
+        assert_eq!(recorded.to_string(), "down*2,-,b");
+        assert_eq!(InputMacro::parse("down*2, -,b").unwrap(), recorded);
+
+        joypad.button_status = JoypadButtons::empty();
+        joypad.play_macro(recorded);
+        let mut played = vec![];
+        while joypad.is_playing() {
+            played.push(joypad.buttons());
+            joypad.next_frame();
+        }
+        assert_eq!(
+            played,
+            [
+                JoypadButtons::Down,
+                JoypadButtons::Down,
+                JoypadButtons::empty(),
+                JoypadButtons::ButtonB
+            ]
+        );
+
+        assert_eq!(InputMacro::parse("a+b*2").unwrap().to_string(), "a+b*2");
+        assert!(InputMacro::parse("jump").is_err());
+        assert!(InputMacro::parse("a*many").is_err());
+    }
 }
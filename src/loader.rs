@@ -0,0 +1,134 @@
+use crate::archive::{self, ArchiveError};
+use crate::cartridge::Rom;
+use crate::game_db::GameDb;
+use crate::patch;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// Why a ROM didn't load. Kept typed so a bad archive or patch can be told
+// apart from a bad ROM.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, std::io::Error),
+    Archive(PathBuf, ArchiveError),
+    Patch(PathBuf, String),
+    Rom(String),
+    Database(String),
+    Bios(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            LoadError::Archive(path, err) => write!(f, "{}: {}", path.display(), err),
+            LoadError::Patch(path, err) => write!(f, "{}: {}", path.display(), err),
+            LoadError::Rom(err) | LoadError::Database(err) | LoadError::Bios(err) => {
+                write!(f, "{}", err)
+            }
+        }
+    }
+}
+
+// How a ROM file becomes a `Rom`, the defaults load it as it is
+#[derive(Default)]
+pub struct LoadOptions<'a> {
+    // ROM to pick from a zip holding several
+    pub entry: Option<&'a str>,
+    // Patch to apply instead of the ROM's same-named sibling
+    pub patch: Option<&'a Path>,
+    // Game database to use instead of the built-in one
+    pub game_db: Option<&'a Path>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, LoadError> {
+    std::fs::read(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))
+}
+
+// The ROM file, unpacked if it's an archive and with its patch applied if it
+// has one
+pub fn read_rom(
+    path: &Path,
+    entry: Option<&str>,
+    patch: Option<&Path>,
+) -> Result<Vec<u8>, LoadError> {
+    let bytes = archive::unpack(read(path)?, entry)
+        .map_err(|err| LoadError::Archive(path.to_path_buf(), err))?;
+    let patch_path = match patch {
+        Some(patch) => patch.to_path_buf(),
+        None => match patch::find_sibling(path) {
+            Some(path) => path,
+            None => return Ok(bytes),
+        },
+    };
+    let bytes = patch::apply(&bytes, &read(&patch_path)?)
+        .map_err(|err| LoadError::Patch(patch_path.clone(), err))?;
+    eprintln!("Applied patch {}", patch_path.display());
+    Ok(bytes)
+}
+
+pub fn load_rom(path: &Path, options: &LoadOptions) -> Result<Rom, LoadError> {
+    let bytes = read_rom(path, options.entry, options.patch)?;
+    match options.game_db {
+        Some(db_path) => {
+            let db = GameDb::load(db_path).map_err(LoadError::Database)?;
+            for entry in db.skipped() {
+                eprintln!(
+                    "Warning: {}: skipped {}, not an iNES mapper",
+                    db_path.display(),
+                    entry
+                );
+            }
+            Rom::with_database(&bytes, &db)
+        }
+        None => Rom::new(&bytes),
+    }
+    .map_err(LoadError::Rom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_errors_stay_typed() {
+        let missing = Path::new("roms/missing.nes");
+        assert!(matches!(
+            read_rom(missing, None, None),
+            Err(LoadError::Io(path, _)) if path == missing
+        ));
+
+        // A zip end record with no entries
+        let path = std::env::temp_dir().join("nes_loader_test_empty.zip");
+        let mut zip = 0x0605_4b50u32.to_le_bytes().to_vec();
+        zip.resize(22, 0);
+        std::fs::write(&path, zip).unwrap();
+        let result = read_rom(&path, None, None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(LoadError::Archive(_, ArchiveError::NoRom))
+        ));
+
+        let rom = Path::new("roms/nestest.nes");
+        let result = read_rom(rom, None, Some(Path::new("roms/nestest.nes")));
+        assert!(matches!(result, Err(LoadError::Patch(_, _))));
+
+        let options = LoadOptions {
+            game_db: Some(Path::new("roms/missing.txt")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            load_rom(rom, &options),
+            Err(LoadError::Database(_))
+        ));
+        assert!(matches!(
+            load_rom(
+                Path::new("roms/tests/cpu_basics.s"),
+                &LoadOptions::default()
+            ),
+            Err(LoadError::Rom(_))
+        ));
+        assert!(load_rom(rom, &LoadOptions::default()).is_ok());
+    }
+}
@@ -1,27 +1,65 @@
+pub mod archive;
 pub mod bus;
 pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod cli;
+pub mod config;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod fds;
+pub mod frame_control;
+pub mod game_db;
+pub mod gdb;
+pub mod headless;
+pub mod input;
 pub mod joypad;
+pub mod loader;
 pub mod opcodes;
+pub mod patch;
 pub mod ppu;
+pub mod ppu_viewer;
+pub mod ram_search;
+pub mod region;
 pub mod render;
+pub mod symbols;
+pub mod testrom;
 pub mod trace;
+pub mod unif;
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use bus::Bus;
 use cartridge::Rom;
+use cdl::CodeDataLog;
+use cheats::Cheats;
+use config::{Config, Hotkey};
 use cpu::Mem;
 use cpu::CPU;
-use joypad::Joypad;
-use joypad::JoypadButtons;
+use debugger::Debugger;
+use frame_control::{FrameControl, FramePacer};
+use input::arkanoid::ArkanoidPaddle;
+use input::power_pad::PowerPad;
+use input::snes_mouse::SnesMouse;
+use input::zapper::Zapper;
+use input::{Controllers, DeviceKind};
+use joypad::{InputMacro, JoypadButtons};
+use loader::{LoadError, LoadOptions};
 use ppu::NesPPU;
+use ppu_viewer::PpuViewer;
 use render::frame::Frame;
+use render::overlay;
 use render::palette;
-use trace::trace;
+use symbols::SymbolTable;
+use trace::TraceWriter;
 
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
@@ -32,117 +70,736 @@ extern crate lazy_static;
 #[macro_use]
 extern crate bitflags;
 
+// Where F3 writes the PPU viewer PNGs
+const PPU_DUMP_DIR: &str = "ppu_dump";
+const SCREENSHOT_DIR: &str = "screenshots";
+// How often input is polled while paused
+const PAUSE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+// Missing or broken settings fall back to the defaults. A missing file is
+// created so there's something to edit. Also returns whether settings can be
+// written back, which they can't over a file that didn't load.
+fn load_config(path: Option<&Path>) -> (Config, bool) {
+    let path = match path {
+        Some(path) => path,
+        None => return (Config::default(), false),
+    };
+    if !path.exists() {
+        let config = Config::default();
+        if let Err(err) = config.save(path) {
+            eprintln!("Warning: {}", err);
+        }
+        return (config, true);
+    }
+    match Config::load(path) {
+        Ok(config) => (config, true),
+        Err(err) => {
+            eprintln!(
+                "Warning: {}, using default settings and leaving the file as is",
+                err
+            );
+            (Config::default(), false)
+        }
+    }
+}
+
+fn keycode(name: &str) -> Option<Keycode> {
+    if name.is_empty() {
+        return None;
+    }
+    let keycode = Keycode::from_name(name);
+    if keycode.is_none() {
+        eprintln!("Warning: unknown key name in config: {}", name);
+    }
+    keycode
+}
+
+// Key to (player, button) for every bound controller button
+fn joypad_keymap(
+    joypads: &[Vec<(JoypadButtons, String)>; 4],
+) -> HashMap<Keycode, (usize, JoypadButtons)> {
+    let mut keymap = HashMap::new();
+    for (player, bindings) in joypads.iter().enumerate() {
+        for (button, name) in bindings {
+            if let Some(keycode) = keycode(name) {
+                keymap.insert(keycode, (player, *button));
+            }
+        }
+    }
+    keymap
+}
+
+fn power_pad_keymap(config: &Config) -> HashMap<Keycode, u8> {
+    (1..)
+        .zip(&config.power_pad)
+        .filter_map(|(button, name)| Some((keycode(name)?, button)))
+        .collect()
+}
+
+fn hotkey_map(config: &Config) -> HashMap<Keycode, Hotkey> {
+    config
+        .hotkeys
+        .iter()
+        .filter_map(|(hotkey, name)| Some((keycode(name)?, *hotkey)))
+        .collect()
+}
+
+fn macro_keymap(config: &Config) -> HashMap<Keycode, InputMacro> {
+    config
+        .macros
+        .iter()
+        .filter_map(|(name, input_macro)| Some((keycode(name)?, input_macro.clone())))
+        .collect()
+}
+
+fn log_corrections(rom: &Rom) {
+    for correction in &rom.corrections {
+        eprintln!("Game database fixed the header, {}", correction);
+    }
+}
+
+// First screenshot_NNNN.png that doesn't exist yet
+fn screenshot_path(dir: &Path) -> PathBuf {
+    (0..)
+        .map(|n| dir.join(format!("screenshot_{:04}.png", n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn run_disasm(args: &cli::DisasmArgs) -> Result<(), String> {
+    let options = LoadOptions {
+        entry: args.entry.as_deref(),
+        patch: args.patch.as_deref().map(Path::new),
+        ..Default::default()
+    };
+    let rom = loader::load_rom(Path::new(&args.rom), &options).map_err(|err| err.to_string())?;
+    if rom.disk.is_some() {
+        return Err(format!(
+            "{}: FDS images load their code from disk, there's no PRG ROM to disassemble",
+            args.rom
+        ));
+    }
+    let cdl = match &args.cdl {
+        Some(path) => {
+            let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            Some(CodeDataLog::from_bytes(
+                &bytes,
+                rom.prg_rom.len(),
+                rom.chr_rom.len(),
+            )?)
+        }
+        None => None,
+    };
+    let mut symbols = SymbolTable::new();
+    for path in &args.symbols {
+        symbols.load(Path::new(path))?;
+    }
+
+    let source = disasm::disassemble_rom(&rom, &symbols, cdl.as_ref());
+    match &args.output {
+        Some(path) => std::fs::write(path, source).map_err(|e| format!("{}: {}", path, e)),
+        None => {
+            print!("{}", source);
+            Ok(())
+        }
+    }
+}
+
+fn load_rom(args: &cli::Args) -> Result<Rom, LoadError> {
+    let rom_path = Path::new(&args.rom);
+    let options = LoadOptions {
+        entry: args.entry.as_deref(),
+        patch: args.patch.as_deref().map(Path::new),
+        game_db: args.game_db.as_deref().map(Path::new),
+    };
+    let mut rom = loader::load_rom(rom_path, &options)?;
+    log_corrections(&rom);
+    rom.region = args.region.unwrap_or(rom.region);
+    if rom.disk.is_some() {
+        let bios = args.fds_bios.as_deref().map(Path::new);
+        rom.prg_rom = fds::load_bios(rom_path, bios).map_err(LoadError::Bios)?;
+    }
+    Ok(rom)
+}
+
+fn run_headless(args: &cli::Args, headless: &cli::HeadlessArgs) -> Result<(), String> {
+    let rom = load_rom(args).map_err(|err| err.to_string())?;
+    let options = headless::HeadlessOptions {
+        frames: headless.frames,
+        input: headless::load_input(headless.input.as_deref().map(Path::new))?,
+        multitap: args.multitap,
+        ports: args.ports,
+        screenshots: headless.screenshots.clone(),
+        screenshot_dir: headless.screenshot_dir.clone().into(),
+        ppu_dump: headless.ppu_dump.clone().map(Into::into),
+    };
+    headless::run(rom, &options, |n, frame| {
+        println!("frame {}: {:016x}", n, headless::frame_hash(frame));
+    })
+}
+
 fn main() {
+    let args = cli::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    if let Some(disasm) = &args.disasm {
+        if let Err(err) = run_disasm(disasm) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(headless) = &args.headless {
+        if let Err(err) = run_headless(&args, headless) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(dir) = &args.test_roms {
+        let results =
+            testrom::run_directory(Path::new(dir), args.timeout_frames).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+        print!("{}", testrom::format_table(&results));
+
+        let all_passed = results
+            .iter()
+            .all(|r| r.status == testrom::TestStatus::Passed);
+        std::process::exit(if all_passed { 0 } else { 1 });
+    }
+
+    let config_path = args
+        .config
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(config::default_path);
+    let (config, config_writable) = load_config(config_path.as_deref());
+    let config_path = config_path.filter(|_| config_writable);
+    let system_palette = match &config.video.palette {
+        Some(path) => palette::load(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("Warning: {}, using the default palette", err);
+            palette::SYSTEM_PALETE
+        }),
+        None => palette::SYSTEM_PALETE,
+    };
+
+    // load game
+    let rom = load_rom(&args).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let region = rom.region;
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let scale = Rc::new(Cell::new(config.video.scale));
     let window = video_subsystem
-        .window("Tile viewer", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .window("Tile viewer", 256 * scale.get(), 240 * scale.get())
         .position_centered()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // Frames that fall behind aren't presented, but vsync on a display that
+    // doesn't refresh at about the console's rate would drop them all the time
+    let display_rate = window
+        .display_index()
+        .and_then(|index| video_subsystem.current_display_mode(index))
+        .map(|mode| mode.refresh_rate as f64);
+    let vsync = match display_rate {
+        Ok(rate) if config.video.vsync && (rate - region.frame_rate()).abs() > 1.0 => {
+            eprintln!(
+                "vsync off: the display refreshes at {} Hz, the game runs at {:.2}",
+                rate,
+                region.frame_rate()
+            );
+            false
+        }
+        _ => config.video.vsync,
+    };
+    let mut canvas_builder = window.into_canvas();
+    if vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+    canvas
+        .set_scale(scale.get() as f32, scale.get() as f32)
+        .unwrap();
+    let window_scale = scale.clone();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
 
-    // load game
-    let bytes = std::fs::read("roms/games/pacman.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
-    let keymap = get_jp1_keymap();
+    let expansion_device = rom.expansion_device;
+    let keymap = joypad_keymap(&config.joypads);
+    let turbo_keymap = joypad_keymap(&config.turbo);
+    let power_pad_keys = power_pad_keymap(&config);
+    let hotkeys = hotkey_map(&config);
+    let mut macro_keys = macro_keymap(&config);
+    // Recorded with the record hotkey, bound to the next key pressed
+    let mut unbound_macro: Option<InputMacro> = None;
+    let bound_keys = config.clone();
+    let recorded_macros = Rc::new(RefCell::new(Vec::new()));
+    let bound_macros = recorded_macros.clone();
+
+    // F12 breaks into the debugger on the next instruction
+    let debug_requested = Rc::new(Cell::new(args.debug));
+    let debug_hotkey = debug_requested.clone();
+    let quit_requested = Rc::new(Cell::new(false));
+    let quit_hotkey = quit_requested.clone();
+    let cheats_toggled = Rc::new(Cell::new(false));
+    let cheats_hotkey = cheats_toggled.clone();
+    let disk_side_switched = Rc::new(Cell::new(false));
+    let disk_side_hotkey = disk_side_switched.clone();
 
     // Run game
     let mut frame = Frame::new();
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
-
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => std::process::exit(0),
-
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = keymap.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
-                    }
+    let mut symbols = SymbolTable::new();
+    for path in &args.symbols {
+        symbols.load(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+    }
+    let labels = symbols.labels(rom.prg_rom.len());
+
+    let cheats_path = args
+        .cheats
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(&args.rom).with_extension("cht"));
+    let cheats = Cheats::load(&cheats_path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    if !cheats.list().is_empty() {
+        println!(
+            "Loaded {} cheats from {}",
+            cheats.list().len(),
+            cheats_path.display()
+        );
+    }
+
+    let cdl = args.cdl.as_ref().map(|path| {
+        CodeDataLog::load_or_new(Path::new(path), rom.prg_rom.len(), rom.chr_rom.len())
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            })
+    });
+
+    let main_window_id = canvas.window().id();
+    let mut ppu_viewer = if args.ppu_viewer {
+        Some(PpuViewer::open(&video_subsystem, system_palette).unwrap())
+    } else {
+        None
+    };
+
+    let press_key = move |controllers: &mut Controllers, keycode: Keycode, pressed: bool| {
+        if let Some((player, button)) = keymap.get(&keycode) {
+            if let Some(joypad) = controllers.joypad_mut(*player) {
+                joypad.set_button_pressed_status(*button, pressed);
+            }
+        }
+        if let Some((player, button)) = turbo_keymap.get(&keycode) {
+            if let Some(joypad) = controllers.joypad_mut(*player) {
+                joypad.set_turbo_pressed_status(*button, pressed);
+            }
+        }
+        if let Some(button) = power_pad_keys.get(&keycode) {
+            for port in 0..2 {
+                if let Some(pad) = controllers.device_mut::<PowerPad>(port) {
+                    pad.set_button(*button, pressed);
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = keymap.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
+            }
+        }
+    };
+    let press_mouse_button = |controllers: &mut Controllers, button: MouseButton, pressed| {
+        for port in 0..2 {
+            if let Some(paddle) = controllers.device_mut::<ArkanoidPaddle>(port) {
+                if button == MouseButton::Left {
+                    paddle.set_button(pressed);
+                }
+            }
+            if let Some(zapper) = controllers.device_mut::<Zapper>(port) {
+                if button == MouseButton::Left {
+                    zapper.set_trigger(pressed);
+                }
+            }
+            if let Some(mouse) = controllers.device_mut::<SnesMouse>(port) {
+                match button {
+                    MouseButton::Left => mouse.set_left(pressed),
+                    MouseButton::Right => mouse.set_right(pressed),
+                    _ => {}
+                }
+            }
+        }
+    };
+
+    let mut frame_control = FrameControl::new();
+    let mut pacer = FramePacer::new(region.frame_rate());
+    let mut show_fps = config.video.show_fps;
+    let mut bus = Bus::new(rom, move |ppu: &NesPPU, controllers: &mut Controllers| {
+        let now = std::time::Instant::now();
+        pacer.count_frame(now);
+        let behind = pacer.is_behind(now, frame_control.slow_motion());
+        if frame_control.end_frame() && !behind {
+            render::render_with_palette(ppu, &mut frame, &system_palette);
+            if let (true, Some(fps), Some(speed)) = (show_fps, pacer.fps(), pacer.speed()) {
+                overlay::draw_text(&mut frame, 2, 2, &format!("{:.1} {:.0}%", fps, speed));
+            }
+            texture.update(None, &frame.data, frame.pitch()).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+
+            if let Some(viewer) = &mut ppu_viewer {
+                viewer.update(ppu);
+            }
+        }
+
+        // Input only changes between frames, paused or not
+        loop {
+            for event in event_pump.poll_iter() {
+                match event {
+                    Event::Quit { .. }
+                    | Event::KeyDown {
+                        keycode: Some(Keycode::Escape),
+                        ..
+                    } => quit_hotkey.set(true),
+
+                    // A Zapper aimed away from the screen sees no light
+                    Event::Window {
+                        win_event: WindowEvent::Leave,
+                        ..
+                    } => {
+                        for port in 0..2 {
+                            if let Some(zapper) = controllers.device_mut::<Zapper>(port) {
+                                zapper.set_cursor(None);
+                            }
+                        }
+                    }
+
+                    // Closing a viewer only closes that window
+                    Event::Window {
+                        window_id,
+                        win_event: WindowEvent::Close,
+                        ..
+                    } => {
+                        let closed = ppu_viewer.as_mut().is_some_and(|v| v.close(window_id));
+                        if !closed && window_id == main_window_id {
+                            quit_hotkey.set(true);
+                        }
+                    }
+
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F2),
+                        ..
+                    } => {
+                        if let Some(viewer) = &mut ppu_viewer {
+                            viewer.next_palette();
+                        }
+                    }
+
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F3),
+                        ..
+                    } => {
+                        if let Some(viewer) = &ppu_viewer {
+                            match viewer.save(ppu, Path::new(PPU_DUMP_DIR)) {
+                                Ok(()) => println!("Saved PPU views to {}", PPU_DUMP_DIR),
+                                Err(err) => eprintln!("{}", err),
+                            }
+                        }
+                    }
+
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        repeat: false,
+                        ..
+                    } if hotkeys.contains_key(&keycode) => match hotkeys[&keycode] {
+                        Hotkey::Debugger => debug_hotkey.set(true),
+                        Hotkey::Screenshot => {
+                            let dir = Path::new(SCREENSHOT_DIR);
+                            let saved = std::fs::create_dir_all(dir)
+                                .map_err(|e| format!("{}: {}", dir.display(), e))
+                                .and_then(|_| {
+                                    // Without the FPS overlay
+                                    render::render_with_palette(ppu, &mut frame, &system_palette);
+                                    let path = screenshot_path(dir);
+                                    frame.save_png(&path).map(|_| path)
+                                });
+                            match saved {
+                                Ok(path) => println!("Saved {}", path.display()),
+                                Err(err) => eprintln!("{}", err),
+                            }
+                        }
+                        Hotkey::ScaleUp | Hotkey::ScaleDown => {
+                            let new_scale = if hotkeys[&keycode] == Hotkey::ScaleUp {
+                                (window_scale.get() + 1).min(config::MAX_SCALE)
+                            } else {
+                                (window_scale.get() - 1).max(1)
+                            };
+                            window_scale.set(new_scale);
+                            canvas
+                                .window_mut()
+                                .set_size(256 * new_scale, 240 * new_scale)
+                                .unwrap();
+                            canvas
+                                .set_scale(new_scale as f32, new_scale as f32)
+                                .unwrap();
+                        }
+                        Hotkey::ToggleCheats => cheats_hotkey.set(true),
+                        Hotkey::SwitchDiskSide => disk_side_hotkey.set(true),
+                        Hotkey::Pause => {
+                            frame_control.toggle_pause();
+                            println!(
+                                "{}",
+                                if frame_control.is_paused() {
+                                    "Paused"
+                                } else {
+                                    "Resumed"
+                                }
+                            );
+                        }
+                        Hotkey::FrameAdvance => frame_control.advance(),
+                        Hotkey::ShowFps => show_fps = !show_fps,
+                        Hotkey::FastForward => frame_control.set_fast_forward(true),
+                        Hotkey::SlowMotion => {
+                            frame_control.cycle_slow_motion();
+                            println!("Speed 1/{}", frame_control.slow_motion());
+                        }
+                        // Records controller 1 until pressed again
+                        Hotkey::RecordMacro => {
+                            if let Some(joypad) = controllers.joypad_mut(0) {
+                                match joypad.stop_recording() {
+                                    Some(input_macro) => {
+                                        println!(
+                                            "Recorded {} frames, press a key to bind the macro to",
+                                            input_macro.len()
+                                        );
+                                        unbound_macro = Some(input_macro).filter(|m| !m.is_empty());
+                                    }
+                                    None => {
+                                        println!("Recording macro");
+                                        joypad.start_recording();
+                                    }
+                                }
+                            }
+                        }
+                    },
+
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        repeat: false,
+                        ..
+                    } if unbound_macro.is_some() => {
+                        // A key already bound would trip the duplicate check
+                        // the next time the config loads
+                        if let Some(setting) = bound_keys.bound_to(&keycode.name()) {
+                            println!(
+                                "{} is bound to {}, press another key",
+                                keycode.name(),
+                                setting
+                            );
+                            continue;
+                        }
+                        let input_macro = unbound_macro.take().unwrap();
+                        println!("Macro bound to {}: {}", keycode.name(), input_macro);
+                        bound_macros
+                            .borrow_mut()
+                            .push((keycode.name(), input_macro.clone()));
+                        macro_keys.insert(keycode, input_macro);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        repeat,
+                        ..
+                    } => {
+                        press_key(controllers, keycode, true);
+                        if let (Some(input_macro), false) = (macro_keys.get(&keycode), repeat) {
+                            if let Some(joypad) = controllers.joypad_mut(0) {
+                                joypad.play_macro(input_macro.clone());
+                            }
+                        }
+                    }
+                    Event::KeyUp {
+                        keycode: Some(keycode),
+                        ..
+                    } if hotkeys.get(&keycode) == Some(&Hotkey::FastForward) => {
+                        frame_control.set_fast_forward(false)
+                    }
+                    Event::KeyUp {
+                        keycode: Some(keycode),
+                        ..
+                    } => press_key(controllers, keycode, false),
+
+                    Event::MouseMotion {
+                        x, y, xrel, yrel, ..
+                    } => {
+                        let scale = window_scale.get() as i32;
+                        let column = (x / scale).clamp(0, 255) as u8;
+                        for port in 0..2 {
+                            if let Some(zapper) = controllers.device_mut::<Zapper>(port) {
+                                zapper.set_cursor(Some((x / scale, y / scale)));
+                            }
+                            if let Some(paddle) = controllers.device_mut::<ArkanoidPaddle>(port) {
+                                paddle.set_x(column);
+                            }
+                            if let Some(mouse) = controllers.device_mut::<SnesMouse>(port) {
+                                mouse.move_by(xrel, yrel);
+                            }
+                        }
                     }
+                    Event::MouseButtonDown { mouse_btn, .. } => {
+                        press_mouse_button(controllers, mouse_btn, true)
+                    }
+                    Event::MouseButtonUp { mouse_btn, .. } => {
+                        press_mouse_button(controllers, mouse_btn, false)
+                    }
+                    _ => { /* do nothing */ }
                 }
-                _ => { /* do nothing */ }
             }
+            if quit_hotkey.get() || debug_hotkey.get() || !frame_control.hold() {
+                break;
+            }
+            std::thread::sleep(PAUSE_POLL_INTERVAL);
+            pacer.resync();
+        }
+
+        // The console's frame rate sets the speed, not the monitor's
+        if frame_control.is_fast_forward() {
+            pacer.resync();
+        } else {
+            pacer.wait(frame_control.slow_motion());
         }
     });
 
+    let multitap = args.multitap.or(config.input.multitap);
+    let ports = [
+        args.ports[0].or(config.input.ports[0]),
+        args.ports[1].or(config.input.ports[1]),
+    ];
+    bus.controllers_mut()
+        .configure(multitap, ports, expansion_device);
+    for player in 0..4 {
+        if let Some(joypad) = bus.controllers_mut().joypad_mut(player) {
+            joypad.set_turbo_rate(config.input.turbo_rate);
+            joypad.set_allow_opposing(config.input.allow_opposing);
+        }
+    }
+    // The SNES mouse reports relative movement, keep the cursor in the window
+    if ports.contains(&Some(DeviceKind::SnesMouse)) {
+        sdl_context.mouse().set_relative_mouse_mode(true);
+    }
     let mut cpu = CPU::new(bus);
 
+    if let Some(cdl) = cdl {
+        cpu.bus.set_cdl(cdl);
+    }
+    cpu.bus.set_cheats(cheats);
+    if let Some(disk) = cpu.bus.fds_mut() {
+        if let Err(err) = fds::load_save(disk, Path::new(&args.rom)) {
+            eprintln!("{}", err);
+        }
+    }
     cpu.reset();
-    cpu.run();
-}
-
-fn get_jp1_keymap() -> HashMap<Keycode, JoypadButtons> {
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::W, JoypadButtons::Up);
-    key_map.insert(Keycode::S, JoypadButtons::Down);
-    key_map.insert(Keycode::A, JoypadButtons::Left);
-    key_map.insert(Keycode::D, JoypadButtons::Right);
-    key_map.insert(Keycode::Space, JoypadButtons::Select);
-    key_map.insert(Keycode::Return, JoypadButtons::Start);
-    key_map.insert(Keycode::J, JoypadButtons::ButtonA);
-    key_map.insert(Keycode::K, JoypadButtons::ButtonB);
-
-    key_map
-}
-
-fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
-    assert!(bank <= 1);
 
-    let mut frame = Frame::new();
-    let mut tile_y = 0;
-    let mut tile_x = 0;
-    let bank = (bank * 0x1000) as usize;
+    // Everything written out when the emulator stops, whichever way it stops
+    let shutdown = |cpu: &CPU| {
+        if let (Some(path), Some(cdl)) = (&args.cdl, cpu.bus.cdl()) {
+            if let Err(err) = cdl.save(Path::new(path)) {
+                eprintln!("{}", err);
+            }
+        }
+        if let Some(disk) = cpu.bus.fds() {
+            if let Err(err) = fds::save(disk, Path::new(&args.rom), args.fds_write_back) {
+                eprintln!("{}", err);
+            }
+        }
+        // Write back settings changed while running
+        if let Some(path) = &config_path {
+            let mut changed = config.clone();
+            changed.video.scale = scale.get();
+            for (key, input_macro) in recorded_macros.borrow_mut().drain(..) {
+                changed.macros.retain(|(k, _)| *k != key);
+                changed.macros.push((key, input_macro));
+            }
+            if changed != config {
+                if let Err(err) = changed.save(path) {
+                    eprintln!("{}", err);
+                }
+            }
+        }
+    };
 
-    for tile_n in 0..256 {
-        if tile_n != 0 && tile_n % 16 == 0 {
-            tile_y += 10;
-            tile_x = 0;
+    if let Some(port) = args.gdb_port {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+            eprintln!("Can't listen for GDB on 127.0.0.1:{}: {}", port, err);
+            std::process::exit(1);
+        });
+        println!("Waiting for GDB connection on 127.0.0.1:{}", port);
+        let session = listener
+            .accept()
+            .and_then(|(stream, _)| gdb::GdbStub::new(stream).run(&mut cpu));
+        if let Err(err) = session {
+            eprintln!("GDB connection lost: {}", err);
         }
-        let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
+        shutdown(&cpu);
+        return;
+    }
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+    let mut tracer = args.trace.map(|trace| {
+        let file = std::fs::File::create(&trace.path).unwrap();
+        TraceWriter::new(std::io::LineWriter::new(file), trace.format)
+            .with_range(trace.start, trace.stop)
+            .with_labels(labels.clone())
+    });
+    let mut debugger = Debugger::new();
+    debugger.set_labels(labels);
 
-            for x in (0..=7).rev() {
-                let value = (1 & upper) << 1 | (1 & lower);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALETE[0x01],
-                    1 => palette::SYSTEM_PALETE[0x23],
-                    2 => palette::SYSTEM_PALETE[0x27],
-                    3 => palette::SYSTEM_PALETE[0x30],
-                    _ => panic!("can't be"),
-                };
-                frame.set_pixel(tile_x + x, tile_y + y, rgb)
+    cpu.run_with_callback(|cpu| {
+        if quit_requested.get() {
+            shutdown(cpu);
+            std::process::exit(0);
+        }
+        if cheats_toggled.take() {
+            let enabled = !cpu.bus.cheats().is_enabled();
+            cpu.bus.cheats_mut().set_all_enabled(enabled);
+            println!("Cheats {}", if enabled { "on" } else { "off" });
+        }
+        if disk_side_switched.take() {
+            if let Some(disk) = cpu.bus.fds_mut() {
+                let side = disk.switch_side();
+                println!(
+                    "Disk ejected, inserting side {}/{}",
+                    side + 1,
+                    disk.side_count()
+                );
             }
         }
+        if debug_requested.take() {
+            debugger.pause();
+        }
+        debugger.hook(cpu);
+        if debugger.quit_requested() {
+            shutdown(cpu);
+            std::process::exit(0);
+        }
 
-        tile_x += 10;
-    }
-    frame
+        if let Some(tracer) = &mut tracer {
+            tracer.log(cpu);
+        }
+    });
+    // The program hit BRK
+    shutdown(&cpu);
 }
 
 fn color(byte: u8) -> Color {
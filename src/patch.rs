@@ -0,0 +1,432 @@
+use crate::cartridge::MAX_ROM_SIZE;
+use crate::game_db::crc32;
+use std::path::{Path, PathBuf};
+
+// Romhack and translation patches, applied to the whole ROM file (header
+// included) before it's parsed
+
+const EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+// A patch named like the ROM next to it: game.nes + game.ips
+pub fn find_sibling(rom_path: &Path) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.exists())
+}
+
+// Apply an IPS, BPS or UPS patch, picked by its signature
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else {
+        Err("Unknown patch format, expected IPS, BPS or UPS".to_string())
+    }
+}
+
+// An IPS patch turning `original` into `modified`
+pub fn create_ips(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut patch = b"PATCH".to_vec();
+    let differs = |at: usize| original.get(at) != Some(&modified[at]);
+    let mut at = 0;
+    while at < modified.len() {
+        if !differs(at) {
+            at += 1;
+            continue;
+        }
+        // An offset that reads as "EOF" would end the patch early
+        let start = if at == 0x454f46 { at - 1 } else { at };
+        let mut end = at;
+        while end < modified.len() && differs(end) && end - start < 0xffff {
+            end += 1;
+        }
+        patch.extend(&(start as u32).to_be_bytes()[1..]);
+        patch.extend(((end - start) as u16).to_be_bytes());
+        patch.extend(&modified[start..end]);
+        at = end;
+    }
+    patch.extend(b"EOF");
+    if modified.len() < original.len() {
+        patch.extend(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+    patch
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or("Patch is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as usize))
+    }
+
+    // BPS/UPS variable length number, 7 bits per byte, the last one has bit 7 set
+    fn number(&mut self) -> Result<usize, String> {
+        let (mut value, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.byte()?;
+            value = value
+                .checked_add((byte & 0x7f) as usize * shift)
+                .ok_or("Patch number overflows")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or("Patch number overflows")?;
+            value += shift;
+        }
+    }
+}
+
+// Sizes come from the patch, refuse ones no ROM has before allocating them
+fn check_size(size: usize) -> Result<(), String> {
+    if size > MAX_ROM_SIZE {
+        return Err(format!(
+            "Patched ROM would be {} bytes, over the {} MiB limit",
+            size,
+            MAX_ROM_SIZE / 1024 / 1024
+        ));
+    }
+    Ok(())
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader {
+        data: patch,
+        pos: 5,
+    };
+    loop {
+        if reader.data[reader.pos..].starts_with(b"EOF") {
+            reader.pos += 3;
+            break;
+        }
+        let offset = reader.big_endian(3)?;
+        let (len, fill) = match reader.big_endian(2)? {
+            // Run of one byte
+            0 => (reader.big_endian(2)?, Some(reader.byte()?)),
+            len => (len, None),
+        };
+        if out.len() < offset + len {
+            check_size(offset + len)?;
+            out.resize(offset + len, 0);
+        }
+        match fill {
+            Some(byte) => out[offset..offset + len].fill(byte),
+            None => out[offset..offset + len].copy_from_slice(reader.bytes(len)?),
+        }
+    }
+    // Optional new file size after EOF
+    if reader.data.len() >= reader.pos + 3 {
+        out.truncate(reader.big_endian(3)?);
+    }
+    Ok(out)
+}
+
+// BPS and UPS end with the CRC32s of the source, target and patch
+fn check_source(rom: &[u8], patch: &[u8]) -> Result<u32, String> {
+    if patch.len() < 12 {
+        return Err("Patch is truncated".to_string());
+    }
+    let footer = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+    let end = patch.len() - 12;
+    if crc32(&patch[..end + 8]) != footer(end + 8) {
+        return Err("Patch is corrupt (checksum mismatch)".to_string());
+    }
+    let expected = footer(end);
+    let actual = crc32(rom);
+    if actual != expected {
+        return Err(format!(
+            "Patch is for a different ROM (CRC32 {:08X}, this one is {:08X})",
+            expected, actual
+        ));
+    }
+    Ok(footer(end + 4))
+}
+
+fn check_target(out: Vec<u8>, expected: u32) -> Result<Vec<u8>, String> {
+    if crc32(&out) != expected {
+        return Err("Patched ROM doesn't match the patch's checksum".to_string());
+    }
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let target_crc = check_source(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut reader = Reader {
+        data: &patch[..end],
+        pos: 4,
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "Patch is for a different ROM ({} bytes, this one is {})",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    check_size(target_size)?;
+
+    let mut out = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0isize, 0isize);
+    let out_of_range = || "Patch reads outside of the ROM".to_string();
+    while reader.pos < end {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err("Patch writes past the end of the ROM".to_string());
+        }
+        match action & 3 {
+            // Source read: the same bytes as the source at this position
+            0 => {
+                let at = out.len();
+                out.extend_from_slice(rom.get(at..at + len).ok_or_else(out_of_range)?);
+            }
+            // Target read: bytes from the patch
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // Source/target copy: from a relative offset, target copies can
+            // overlap what they write
+            command => {
+                let data = reader.number()?;
+                let delta = (data >> 1) as isize * if data & 1 == 1 { -1 } else { 1 };
+                let offset = if command == 2 {
+                    &mut source_offset
+                } else {
+                    &mut target_offset
+                };
+                *offset += delta;
+                for _ in 0..len {
+                    let byte = if command == 2 {
+                        rom.get(*offset as usize)
+                    } else {
+                        out.get(*offset as usize)
+                    };
+                    out.push(*byte.ok_or_else(out_of_range)?);
+                    *offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err("Patch produced the wrong size".to_string());
+    }
+    check_target(out, target_crc)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let target_crc = check_source(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut reader = Reader {
+        data: &patch[..end],
+        pos: 4,
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "Patch is for a different ROM ({} bytes, this one is {})",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    check_size(target_size)?;
+
+    // Hunks XOR the source, each ends with a 0 byte
+    let mut out = rom.to_vec();
+    out.resize(source_size.max(target_size), 0);
+    let mut at = 0;
+    while reader.pos < end {
+        at += reader.number()?;
+        loop {
+            let xor = reader.byte()?;
+            if xor == 0 {
+                at += 1;
+                break;
+            }
+            *out.get_mut(at).ok_or("Patch writes outside of the ROM")? ^= xor;
+            at += 1;
+        }
+    }
+    out.truncate(target_size);
+    check_target(out, target_crc)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut out = vec![];
+        loop {
+            let bits = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(bits | 0x80);
+                return out;
+            }
+            out.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(crc32(source).to_le_bytes());
+        patch.extend(crc32(target).to_le_bytes());
+        patch.extend(crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_number() {
+        for value in [0, 127, 128, 300, 1 << 20] {
+            let bytes = number(value);
+            let mut reader = Reader {
+                data: &bytes,
+                pos: 0,
+            };
+            assert_eq!(reader.number(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        // Run of 3 bytes past the end grows the file
+        patch.extend([0, 0, 5, 0, 0, 0, 3, 0xCC]);
+        patch.extend(b"EOF");
+        let out = apply(&[0; 4], &patch).unwrap();
+        assert_eq!(out, [0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC]);
+
+        patch.extend([0, 0, 2]);
+        assert_eq!(apply(&[0; 4], &patch).unwrap(), [0, 0xAA]);
+        assert!(apply(&[0; 4], b"PATCH\x00\x00").is_err());
+        assert!(apply(&[0; 4], b"NOPE").is_err());
+
+        // 64K run at the highest offset, past the size limit
+        let mut huge = b"PATCH".to_vec();
+        huge.extend([0xFF, 0xFF, 0xFF, 0, 0, 0xFF, 0xFF, 0xCC]);
+        huge.extend(b"EOF");
+        let err = apply(&[0; 4], &huge).unwrap_err();
+        assert!(err.contains("over the 16 MiB limit"), "{}", err);
+    }
+
+    #[test]
+    fn test_create_ips() {
+        let original = [1, 2, 3, 4, 5, 6];
+        for modified in [&[1, 9, 9, 4, 5, 6, 7][..], &[1, 2, 3, 0], &original] {
+            let patch = create_ips(&original, modified);
+            assert_eq!(apply(&original, &patch).unwrap(), modified);
+        }
+        assert_eq!(create_ips(&original, &original), b"PATCHEOF");
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 9, 3, 4, 5];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(5));
+        patch.extend(number(1));
+        patch.extend([2 ^ 9, 0]);
+        patch.extend(number(1));
+        patch.extend([5, 0]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        let err = apply(&[1, 2, 3, 5], &patch).unwrap_err();
+        assert!(err.starts_with("Patch is for a different ROM"), "{}", err);
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 1;
+        assert_eq!(
+            apply(&source, &corrupt),
+            Err("Patch is corrupt (checksum mismatch)".to_string())
+        );
+
+        let mut huge = b"UPS1".to_vec();
+        huge.extend(number(4));
+        huge.extend(number(1 << 40));
+        let huge = with_footer(huge, &source, &target);
+        let err = apply(&source, &huge).unwrap_err();
+        assert!(err.contains("over the 16 MiB limit"), "{}", err);
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 2, 9, 9, 1, 2, 9, 9];
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(8));
+        patch.extend(number(0));
+        // Source read 2, target read 2, source copy 2 from 0, target copy 2 from 2
+        patch.extend(number(1 << 2));
+        patch.extend(number(1 << 2 | 1));
+        patch.extend([9, 9]);
+        patch.extend(number(1 << 2 | 2));
+        patch.extend(number(0));
+        patch.extend(number(1 << 2 | 3));
+        patch.extend(number(2 << 1));
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        let err = apply(&[4, 3, 2, 1], &patch).unwrap_err();
+        assert!(err.starts_with("Patch is for a different ROM"), "{}", err);
+        let wrong_target = with_footer(patch[..patch.len() - 12].to_vec(), &source, &[0]);
+        assert_eq!(
+            apply(&source, &wrong_target),
+            Err("Patched ROM doesn't match the patch's checksum".to_string())
+        );
+
+        let mut huge = b"BPS1".to_vec();
+        huge.extend(number(4));
+        huge.extend(number(1 << 40));
+        huge.extend(number(0));
+        let huge = with_footer(huge, &source, &target);
+        let err = apply(&source, &huge).unwrap_err();
+        assert!(err.contains("over the 16 MiB limit"), "{}", err);
+
+        // A target copy longer than the target it builds
+        let mut overlong = b"BPS1".to_vec();
+        overlong.extend(number(4));
+        overlong.extend(number(8));
+        overlong.extend(number(0));
+        overlong.extend(number(1 << 2 | 1));
+        overlong.extend([9, 9]);
+        overlong.extend(number(1 << 40 | 3));
+        overlong.extend(number(0));
+        let overlong = with_footer(overlong, &source, &target);
+        assert_eq!(
+            apply(&source, &overlong),
+            Err("Patch writes past the end of the ROM".to_string())
+        );
+    }
+}
@@ -3,11 +3,14 @@ use self::registers::{
     status::StatusRegister,
 };
 use crate::cartridge::Mirroring;
+use crate::region::Region;
 
 pub mod registers;
 
 pub struct NesPPU {
     pub chr_rom: Vec<u8>,
+    // Boards without CHR ROM have 8K of RAM in its place
+    chr_ram: bool,
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
     pub oam: [u8; 256],
@@ -25,6 +28,7 @@ pub struct NesPPU {
     scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+    region: Region,
 }
 
 pub trait PPU {
@@ -43,8 +47,10 @@ pub trait PPU {
 
 impl NesPPU {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_ram = chr_rom.is_empty();
         Self {
-            chr_rom,
+            chr_rom: if chr_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_ram,
             palette_table: [0; 32],
             vram: [0; 2048],
             oam: [0; 64 * 4],
@@ -60,6 +66,7 @@ impl NesPPU {
             cycles: 0,
             scanline: 0,
             nmi_interrupt: None,
+            region: Region::Ntsc,
         }
     }
 
@@ -67,6 +74,23 @@ impl NesPPU {
         NesPPU::new(vec![0; 2048], Mirroring::Horizontal)
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    // Dot (PPU cycle) within the current scanline
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
     fn increment_vram_addr(&mut self) {
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
@@ -99,7 +123,7 @@ impl NesPPU {
             self.cycles = self.cycles - 341;
             self.scanline += 1;
 
-            if self.scanline == 241 {
+            if self.scanline == self.region.vblank_scanline() {
                 // Triger NMI at VBlank
                 self.status.set_vblank_status(true);
                 self.status.set_sprite_zero_hit(false);
@@ -108,7 +132,7 @@ impl NesPPU {
                 }
             }
 
-            if self.scanline >= 262 {
+            if self.scanline >= self.region.scanlines() {
                 self.scanline = 0;
                 self.nmi_interrupt = None;
 
@@ -202,6 +226,7 @@ impl PPU for NesPPU {
         self.increment_vram_addr();
 
         match addr {
+            0x0000..=0x1FFF if self.chr_ram => self.chr_rom[addr as usize] = value,
             0x0000..=0x1FFF => println!("Cannot write to CHR ROM"),
             0x2000..=0x2FFF => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = value;
@@ -227,6 +252,31 @@ impl PPU for NesPPU {
 pub mod test {
     use super::*;
 
+    // PPU dots until the next frame starts, and the scanline vblank began on
+    fn frame_timing(region: Region) -> (usize, Option<u16>) {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.set_region(region);
+        let (mut dots, mut vblank) = (0, None);
+        while !ppu.tick(1) {
+            dots += 1;
+            if vblank.is_none() && ppu.status.is_in_vblank() {
+                vblank = Some(ppu.scanline());
+            }
+        }
+        (dots + 1, vblank)
+    }
+
+    #[test]
+    fn test_region_timing() {
+        assert_eq!(frame_timing(Region::Ntsc), (262 * 341, Some(241)));
+        assert_eq!(frame_timing(Region::Pal), (312 * 341, Some(241)));
+        assert_eq!(frame_timing(Region::Dendy), (312 * 341, Some(291)));
+
+        assert_eq!(Region::Ntsc.cpu_cycles_per_frame(), 29781);
+        assert_eq!(Region::Pal.cpu_cycles_per_frame(), 33248);
+        assert_eq!(Region::Dendy.cpu_cycles_per_frame(), 35464);
+    }
+
     #[test]
     fn test_ppu_vram_writes() {
         let mut ppu = NesPPU::new_empty_rom();
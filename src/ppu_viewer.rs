@@ -0,0 +1,112 @@
+use crate::ppu::NesPPU;
+use crate::render::frame::Frame;
+use crate::render::palette::Palette;
+use crate::render::viewer;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+use std::path::Path;
+
+const SCALE: u32 = 2;
+
+#[derive(Clone, Copy)]
+enum View {
+    PatternTables,
+    Nametables,
+    Oam,
+    Palette,
+}
+
+impl View {
+    fn title(&self) -> &'static str {
+        match self {
+            View::PatternTables => "Pattern tables",
+            View::Nametables => "Nametables",
+            View::Oam => "OAM",
+            View::Palette => "Palette",
+        }
+    }
+}
+
+// Extra SDL windows showing the PPU debug views, redrawn every frame
+pub struct PpuViewer {
+    windows: Vec<(View, Canvas<Window>)>,
+    palette: u8,
+    system_palette: Palette,
+}
+
+impl PpuViewer {
+    pub fn open(video: &VideoSubsystem, system_palette: Palette) -> Result<Self, String> {
+        let empty = NesPPU::new_empty_rom();
+        let views = [
+            View::PatternTables,
+            View::Nametables,
+            View::Oam,
+            View::Palette,
+        ];
+        let mut windows = vec![];
+        for view in views {
+            let frame = render(view, &empty, &system_palette, 0);
+            let window = video
+                .window(
+                    view.title(),
+                    frame.width as u32 * SCALE,
+                    frame.height as u32 * SCALE,
+                )
+                .build()
+                .map_err(|e| e.to_string())?;
+            let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+            canvas.set_scale(SCALE as f32, SCALE as f32)?;
+            windows.push((view, canvas));
+        }
+        Ok(PpuViewer {
+            windows,
+            palette: 0,
+            system_palette,
+        })
+    }
+
+    pub fn update(&mut self, ppu: &NesPPU) {
+        for (view, canvas) in &mut self.windows {
+            let frame = render(*view, ppu, &self.system_palette, self.palette);
+            let creator = canvas.texture_creator();
+            let mut texture = creator
+                .create_texture_streaming(
+                    PixelFormatEnum::RGB24,
+                    frame.width as u32,
+                    frame.height as u32,
+                )
+                .unwrap();
+            texture.update(None, &frame.data, frame.pitch()).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
+    }
+
+    // Cycle the palette the pattern tables are drawn with
+    pub fn next_palette(&mut self) {
+        self.palette = (self.palette + 1) % viewer::PALETTE_COUNT;
+    }
+
+    pub fn save(&self, ppu: &NesPPU, dir: &Path) -> Result<(), String> {
+        viewer::save_all(ppu, &self.system_palette, dir, self.palette)
+    }
+
+    // Close the viewer window with this id, returns false if it isn't one of ours
+    pub fn close(&mut self, window_id: u32) -> bool {
+        let before = self.windows.len();
+        self.windows
+            .retain(|(_, canvas)| canvas.window().id() != window_id);
+        self.windows.len() != before
+    }
+}
+
+fn render(view: View, ppu: &NesPPU, system_palette: &Palette, palette: u8) -> Frame {
+    match view {
+        View::PatternTables => viewer::pattern_tables(ppu, system_palette, palette),
+        View::Nametables => viewer::nametables(ppu, system_palette),
+        View::Oam => viewer::oam(ppu, system_palette),
+        View::Palette => viewer::palette_ram(ppu, system_palette),
+    }
+}
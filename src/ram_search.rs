@@ -0,0 +1,330 @@
+use crate::bus::Bus;
+
+// Memory that can be searched, with the address its first byte is shown at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    // 2 KiB internal RAM at $0000
+    Ram,
+    // Cartridge work RAM at $6000
+    PrgRam,
+    // PPU nametable RAM at $2000
+    Vram,
+    // PPU palette at $3F00
+    Palette,
+    // Sprite memory, by OAM offset
+    Oam,
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "ram" => Ok(Region::Ram),
+            "prgram" | "wram" => Ok(Region::PrgRam),
+            "vram" => Ok(Region::Vram),
+            "palette" => Ok(Region::Palette),
+            "oam" => Ok(Region::Oam),
+            _ => Err(format!(
+                "Unknown memory region: {} (ram, prgram, vram, palette, oam)",
+                value
+            )),
+        }
+    }
+}
+
+impl Region {
+    pub fn base(&self) -> u16 {
+        match self {
+            Region::Ram => 0x0000,
+            Region::PrgRam => 0x6000,
+            Region::Vram => 0x2000,
+            Region::Palette => 0x3F00,
+            Region::Oam => 0x00,
+        }
+    }
+
+    pub fn read(&self, bus: &Bus) -> Vec<u8> {
+        let ppu = bus.ppu();
+        match self {
+            Region::Ram => (0..0x800).map(|addr| bus.peek(addr)).collect(),
+            Region::PrgRam => (0x6000..=0x7FFF).map(|addr| bus.peek(addr)).collect(),
+            Region::Vram => ppu.vram.to_vec(),
+            Region::Palette => ppu.palette_table.to_vec(),
+            Region::Oam => ppu.oam.to_vec(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Region::Ram | Region::Vram => 0x800,
+            Region::PrgRam => 0x2000,
+            Region::Palette => 0x20,
+            Region::Oam => 0x100,
+        }
+    }
+
+    // Offset into the region of an address shown as `addr`
+    pub fn offset(&self, addr: u16) -> Result<usize, String> {
+        addr.checked_sub(self.base())
+            .map(|offset| offset as usize)
+            .filter(|offset| *offset < self.len())
+            .ok_or_else(|| format!("${:04X} is outside of {:?}", addr, self))
+    }
+}
+
+// How the bytes at a candidate are read: 8 or 16 bits (little endian),
+// unsigned or two's complement
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueType {
+    pub word: bool,
+    pub signed: bool,
+}
+
+impl std::str::FromStr for ValueType {
+    type Err = String;
+
+    // `8`, `16`, `s8` or `s16`
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (signed, bits) = match value.strip_prefix('s') {
+            Some(bits) => (true, bits),
+            None => (false, value),
+        };
+        match bits {
+            "8" => Ok(ValueType {
+                word: false,
+                signed,
+            }),
+            "16" => Ok(ValueType { word: true, signed }),
+            _ => Err(format!("Invalid value type: {} (8, 16, s8, s16)", value)),
+        }
+    }
+}
+
+impl ValueType {
+    fn size(&self) -> usize {
+        if self.word {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn get(&self, memory: &[u8], offset: usize) -> i32 {
+        match (self.word, self.signed) {
+            (false, false) => memory[offset] as i32,
+            (false, true) => memory[offset] as i8 as i32,
+            (true, signed) => {
+                let word = u16::from_le_bytes([memory[offset], memory[offset + 1]]);
+                if signed {
+                    word as i16 as i32
+                } else {
+                    word as i32
+                }
+            }
+        }
+    }
+}
+
+// Compares every candidate's value now against the last snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(i32),
+}
+
+impl Comparison {
+    fn matches(&self, previous: i32, current: i32) -> bool {
+        match self {
+            Comparison::Equal => current == previous,
+            Comparison::Changed => current != previous,
+            Comparison::Increased => current > previous,
+            Comparison::Decreased => current < previous,
+            Comparison::Value(value) => current == *value,
+        }
+    }
+}
+
+pub struct SearchResult {
+    pub addr: u16,
+    pub previous: i32,
+    pub current: i32,
+}
+
+// Narrow down where a game keeps a value: snapshot memory, play, then keep
+// the addresses that changed the way the value did
+pub struct RamSearch {
+    region: Region,
+    value_type: ValueType,
+    snapshot: Vec<u8>,
+    // The snapshot the last filter compared against, for showing what changed
+    previous: Vec<u8>,
+    candidates: Vec<usize>,
+}
+
+impl RamSearch {
+    // Start with every address of `region` as a candidate
+    pub fn new(bus: &Bus, region: Region, value_type: ValueType) -> Self {
+        let snapshot = region.read(bus);
+        let candidates = (0..=snapshot.len() - value_type.size()).collect();
+        RamSearch {
+            region,
+            value_type,
+            previous: snapshot.clone(),
+            snapshot,
+            candidates,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    // Keep the candidates matching `comparison` and take a new snapshot,
+    // returns how many are left
+    pub fn filter(&mut self, bus: &Bus, comparison: Comparison) -> usize {
+        let current = self.region.read(bus);
+        let value_type = self.value_type;
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|offset| {
+            comparison.matches(
+                value_type.get(snapshot, *offset),
+                value_type.get(&current, *offset),
+            )
+        });
+        self.previous = std::mem::replace(&mut self.snapshot, current);
+        self.candidates.len()
+    }
+
+    // Forget the changes since the last filter
+    pub fn snapshot(&mut self, bus: &Bus) {
+        self.snapshot = self.region.read(bus);
+    }
+
+    pub fn results(&self, bus: &Bus) -> Vec<SearchResult> {
+        let current = self.region.read(bus);
+        self.candidates
+            .iter()
+            .map(|offset| SearchResult {
+                addr: self.region.base() + *offset as u16,
+                previous: self.value_type.get(&self.previous, *offset),
+                current: self.value_type.get(&current, *offset),
+            })
+            .collect()
+    }
+}
+
+// An address to keep an eye on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watch {
+    pub region: Region,
+    pub addr: u16,
+    pub value_type: ValueType,
+}
+
+impl Watch {
+    pub fn new(region: Region, addr: u16, value_type: ValueType) -> Result<Self, String> {
+        let offset = region.offset(addr)?;
+        if offset + value_type.size() > region.len() {
+            return Err(format!("${:04X} is outside of {:?}", addr, region));
+        }
+        Ok(Watch {
+            region,
+            addr,
+            value_type,
+        })
+    }
+
+    pub fn value(&self, bus: &Bus) -> i32 {
+        let offset = (self.addr - self.region.base()) as usize;
+        self.value_type.get(&self.region.read(bus), offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    const BYTE: ValueType = ValueType {
+        word: false,
+        signed: false,
+    };
+
+    fn addrs(search: &RamSearch, bus: &Bus) -> Vec<u16> {
+        search.results(bus).iter().map(|r| r.addr).collect()
+    }
+
+    #[test]
+    fn test_narrow_down() {
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        bus.mem_write(0x10, 3);
+        bus.mem_write(0x20, 3);
+        let mut search = RamSearch::new(&bus, Region::Ram, BYTE);
+        assert_eq!(search.len(), 0x800);
+
+        // Lives go down, something else goes up
+        bus.mem_write(0x10, 2);
+        bus.mem_write(0x30, 1);
+        assert_eq!(search.filter(&bus, Comparison::Changed), 2);
+        assert_eq!(addrs(&search, &bus), [0x10, 0x30]);
+        assert_eq!(search.filter(&bus, Comparison::Equal), 2);
+        bus.mem_write(0x10, 1);
+        bus.mem_write(0x30, 2);
+        assert_eq!(search.filter(&bus, Comparison::Decreased), 1);
+        assert_eq!(addrs(&search, &bus), [0x10]);
+        assert_eq!(search.filter(&bus, Comparison::Value(0)), 0);
+    }
+
+    #[test]
+    fn test_value_types() {
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        let word: ValueType = "s16".parse().unwrap();
+        let mut search = RamSearch::new(&bus, Region::PrgRam, word);
+        assert_eq!(search.len(), 0x1FFF);
+
+        // 0 to -2 is a decrease when signed
+        bus.mem_write(0x6100, 0xFE);
+        bus.mem_write(0x6101, 0xFF);
+        search.filter(&bus, Comparison::Decreased);
+        let results = search.results(&bus);
+        assert_eq!(addrs(&search, &bus), [0x60FF, 0x6100]);
+        assert_eq!(results[1].current, -2);
+        assert_eq!(results[1].previous, 0);
+
+        let mut unsigned = RamSearch::new(&bus, Region::PrgRam, "16".parse().unwrap());
+        bus.mem_write(0x6101, 0x00);
+        unsigned.filter(&bus, Comparison::Value(0xFE));
+        assert_eq!(addrs(&unsigned, &bus), [0x6100]);
+
+        assert_eq!(ValueType::get(&"s8".parse().unwrap(), &[0x80], 0), -128);
+        assert!("32".parse::<ValueType>().is_err());
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        let watch = Watch::new(Region::Ram, 0x7FE, "16".parse().unwrap()).unwrap();
+        bus.mem_write(0x7FE, 0x34);
+        bus.mem_write(0x7FF, 0x12);
+        assert_eq!(watch.value(&bus), 0x1234);
+        assert!(Watch::new(Region::Ram, 0x7FF, "16".parse().unwrap()).is_err());
+        assert!(Watch::new(Region::Palette, 0x2000, BYTE).is_err());
+    }
+}
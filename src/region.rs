@@ -0,0 +1,86 @@
+use crate::frame_control::{NTSC_FRAME_RATE, PAL_FRAME_RATE};
+
+const DOTS_PER_SCANLINE: u32 = 341;
+
+// TV system the console was built for, which sets the frame timing. There's
+// no APU yet, so its region tables (frame counter steps, noise and DMC
+// periods) are still to come with it; only CPU and PPU timing follow the
+// region for now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    // Famiclone sold in Russia: PAL frame length with NTSC-like CPU timing
+    Dendy,
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("Unknown region: {} (ntsc, pal, dendy)", value)),
+        }
+    }
+}
+
+impl Region {
+    // NES 2.0 header byte 12. Multi-region games run as NTSC.
+    pub fn from_nes2(timing: u8) -> Self {
+        match timing & 0b11 {
+            1 => Region::Pal,
+            3 => Region::Dendy,
+            _ => Region::Ntsc,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "ntsc",
+            Region::Pal => "pal",
+            Region::Dendy => "dendy",
+        }
+    }
+
+    // Scanlines per frame, including vblank and the pre-render line
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Scanline where vblank starts and NMI fires
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            // Dendy keeps the 20 scanline vblank and pads before it instead
+            Region::Dendy => 291,
+        }
+    }
+
+    // CPU cycles in a frame, rounded up: 29781 NTSC, 33248 PAL, 35464 Dendy
+    pub fn cpu_cycles_per_frame(&self) -> usize {
+        let (dots, cycles) = self.ppu_dots_per_cpu_cycle();
+        let frame_dots = DOTS_PER_SCANLINE * self.scanlines() as u32 * cycles;
+        frame_dots.div_ceil(dots) as usize
+    }
+
+    // PPU dots per CPU cycle as a fraction: 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (u32, u32) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_FRAME_RATE,
+            Region::Pal | Region::Dendy => PAL_FRAME_RATE,
+        }
+    }
+}
@@ -1,5 +1,9 @@
+use std::path::Path;
+
 pub struct Frame {
     pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
 }
 
 impl Frame {
@@ -7,17 +11,43 @@ impl Frame {
     const HIGHT: usize = 240;
 
     pub fn new() -> Self {
+        Frame::with_size(Frame::WIDTH, Frame::HIGHT)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> Self {
         Frame {
-            data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            data: vec![0; width * height * 3],
+            width,
+            height,
         }
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = y * 3 * Frame::WIDTH + x * 3;
+        let base = y * 3 * self.width + x * 3;
         if base + 2 < self.data.len() {
             self.data[base] = rgb.0;
             self.data[base + 1] = rgb.1;
             self.data[base + 2] = rgb.2;
         }
     }
+
+    // Bytes per row, as SDL textures want it
+    pub fn pitch(&self) -> usize {
+        self.width * 3
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<(), String> {
+        let err = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let file = std::fs::File::create(path).map_err(|e| err(&e))?;
+
+        let mut encoder = png::Encoder::new(
+            std::io::BufWriter::new(file),
+            self.width as u32,
+            self.height as u32,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| err(&e))?;
+        writer.write_image_data(&self.data).map_err(|e| err(&e))
+    }
 }
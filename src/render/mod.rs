@@ -1,8 +1,11 @@
 use crate::ppu::NesPPU;
 use frame::Frame;
+use palette::Palette;
 
 pub mod frame;
+pub mod overlay;
 pub mod palette;
+pub mod viewer;
 
 // pub fn render(ppu: &NesPPU, frame: &mut Frame) {
 //     let bank = ppu.ctrl.bknd_pattern_addr();
@@ -149,6 +152,10 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
 }
 
 pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    render_with_palette(ppu, frame, &palette::SYSTEM_PALETE)
+}
+
+pub fn render_with_palette(ppu: &NesPPU, frame: &mut Frame, system_palette: &Palette) {
     let bank = ppu.ctrl.bknd_pattern_addr();
 
     for i in 0..0x3c0 {
@@ -167,10 +174,10 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
                 upper = upper >> 1;
                 lower = lower >> 1;
                 let rgb = match value {
-                    0 => palette::SYSTEM_PALETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALETE[palette[3] as usize],
+                    0 => system_palette[ppu.palette_table[0] as usize],
+                    1 => system_palette[palette[1] as usize],
+                    2 => system_palette[palette[2] as usize],
+                    3 => system_palette[palette[3] as usize],
                     _ => panic!("can't be"),
                 };
                 frame.set_pixel(tile_column * 8 + x, tile_row * 8 + y, rgb)
@@ -209,9 +216,9 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
                 lower = lower >> 1;
                 let rgb = match value {
                     0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALETE[sprite_palette[3] as usize],
+                    1 => system_palette[sprite_palette[1] as usize],
+                    2 => system_palette[sprite_palette[2] as usize],
+                    3 => system_palette[sprite_palette[3] as usize],
                     _ => panic!("can't be"),
                 };
                 match (flip_horizontal, flip_vertical) {
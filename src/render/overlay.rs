@@ -0,0 +1,68 @@
+use crate::render::frame::Frame;
+
+// 3x5 pixel glyphs, one row per byte with bit 2 the leftmost pixel
+const GLYPHS: [(char, [u8; 5]); 14] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b011, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    (' ', [0b000; 5]),
+];
+
+const TEXT: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const SHADOW: (u8, u8, u8) = (0, 0, 0);
+
+// Draw `text` with its top left corner at (x, y), white with a shadow so it
+// reads on any background. Characters without a glyph are skipped.
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str) {
+    let mut left = x;
+    for c in text.chars() {
+        let Some((_, rows)) = GLYPHS.iter().find(|(glyph, _)| *glyph == c) else {
+            continue;
+        };
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    let (px, py) = (left + column, y + row);
+                    if px + 1 < frame.width && py + 1 < frame.height {
+                        frame.set_pixel(px + 1, py + 1, SHADOW);
+                        frame.set_pixel(px, py, TEXT);
+                    }
+                }
+            }
+        }
+        left += 4;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> u8 {
+        frame.data[(y * frame.width + x) * 3]
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut frame = Frame::new();
+        draw_text(&mut frame, 2, 2, "1 %");
+        // The 1's top row is the middle pixel, with a shadow below right
+        assert_eq!(pixel(&frame, 2, 2), 0);
+        assert_eq!(pixel(&frame, 3, 2), 0xff);
+        // The % starts after the space, two glyphs on
+        assert_eq!(pixel(&frame, 10, 2), 0xff);
+        assert_eq!(pixel(&frame, 11, 2), 0);
+        let lit = frame.data.iter().filter(|b| **b == 0xff).count() / 3;
+        assert_eq!(lit, 8 + 7);
+    }
+}
@@ -1,5 +1,9 @@
+use std::path::Path;
+
+pub type Palette = [(u8, u8, u8); 64];
+
 #[rustfmt::skip]
-pub static SYSTEM_PALETE: [(u8,u8,u8); 64] = [
+pub static SYSTEM_PALETE: Palette = [
    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
    (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
    (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
@@ -14,3 +18,21 @@ pub static SYSTEM_PALETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// Load a .pal file: 64 RGB triples. Files with the 8 color emphasis
+// variants appended (1536 bytes) only use the first set.
+pub fn load(path: &Path) -> Result<Palette, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if bytes.len() != 64 * 3 && bytes.len() != 64 * 3 * 8 {
+        return Err(format!(
+            "{}: expected 192 or 1536 bytes, got {}",
+            path.display(),
+            bytes.len()
+        ));
+    }
+    let mut palette = [(0, 0, 0); 64];
+    for (color, rgb) in palette.iter_mut().zip(bytes.chunks(3)) {
+        *color = (rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(palette)
+}
@@ -0,0 +1,304 @@
+// Debug views of PPU state: pattern tables, nametables, OAM and palette RAM
+use crate::ppu::NesPPU;
+use crate::render::frame::Frame;
+use crate::render::palette::Palette;
+use std::path::Path;
+
+const TILE_SIZE: usize = 16;
+// Background palettes 0-3 followed by sprite palettes 4-7
+pub const PALETTE_COUNT: u8 = 8;
+
+const OAM_COLUMNS: usize = 8;
+const OAM_CELL_WIDTH: usize = 12;
+const OAM_CELL_HEIGHT: usize = 20;
+const SWATCH_SIZE: usize = 16;
+
+const VIEWPORT_COLOR: (u8, u8, u8) = (0xff, 0x00, 0xff);
+const BORDER_COLOR: (u8, u8, u8) = (0x40, 0x40, 0x40);
+
+fn rgb(system_palette: &Palette, color: u8) -> (u8, u8, u8) {
+    system_palette[(color & 0x3f) as usize]
+}
+
+// Palette `idx` (0-7) as four palette RAM entries
+fn palette_colors(ppu: &NesPPU, idx: u8) -> [u8; 4] {
+    let start = (idx as usize % PALETTE_COUNT as usize) * 4;
+    [
+        ppu.palette_table[0],
+        ppu.palette_table[start + 1],
+        ppu.palette_table[start + 2],
+        ppu.palette_table[start + 3],
+    ]
+}
+
+fn draw_tile(
+    frame: &mut Frame,
+    ppu: &NesPPU,
+    system_palette: &Palette,
+    addr: usize,
+    (left, top): (usize, usize),
+    colors: [u8; 4],
+    (flip_h, flip_v): (bool, bool),
+) {
+    let tile = match ppu.chr_rom.get(addr..addr + TILE_SIZE) {
+        Some(tile) => tile,
+        None => return,
+    };
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = 7 - x;
+            let value = (tile[y] >> bit & 1) | (tile[y + 8] >> bit & 1) << 1;
+            let px = if flip_h { 7 - x } else { x };
+            let py = if flip_v { 7 - y } else { y };
+            frame.set_pixel(
+                left + px,
+                top + py,
+                rgb(system_palette, colors[value as usize]),
+            );
+        }
+    }
+}
+
+// Both pattern tables side by side (256x128), drawn with palette `palette_idx`.
+// Views take the system palette the game window uses so the colors match.
+pub fn pattern_tables(ppu: &NesPPU, system_palette: &Palette, palette_idx: u8) -> Frame {
+    let mut frame = Frame::with_size(256, 128);
+    let colors = palette_colors(ppu, palette_idx);
+    for table in 0..2 {
+        for tile in 0..256 {
+            let addr = table * 0x1000 + tile * TILE_SIZE;
+            let pos = (table * 128 + tile % 16 * 8, tile / 16 * 8);
+            draw_tile(
+                &mut frame,
+                ppu,
+                system_palette,
+                addr,
+                pos,
+                colors,
+                (false, false),
+            );
+        }
+    }
+    frame
+}
+
+// All four nametables (512x480) as mirrored into VRAM, with the scroll
+// viewport outlined
+pub fn nametables(ppu: &NesPPU, system_palette: &Palette) -> Frame {
+    let mut frame = Frame::with_size(512, 480);
+    let bank = ppu.ctrl.bknd_pattern_addr() as usize;
+
+    for table in 0..4 {
+        let base = 0x2000 + table as u16 * 0x400;
+        let vram = |offset: u16| ppu.vram[ppu.mirror_vram_addr(base + offset) as usize];
+        let (left, top) = (table % 2 * 256, table / 2 * 240);
+
+        for i in 0..0x3c0u16 {
+            let (column, row) = (i % 32, i / 32);
+            let attr = vram(0x3c0 + row / 4 * 8 + column / 4);
+            let shift = (row % 4 / 2 * 2 + column % 4 / 2) * 2;
+            let colors = palette_colors(ppu, attr >> shift & 0b11);
+
+            let addr = bank + vram(i) as usize * TILE_SIZE;
+            let pos = (left + column as usize * 8, top + row as usize * 8);
+            draw_tile(
+                &mut frame,
+                ppu,
+                system_palette,
+                addr,
+                pos,
+                colors,
+                (false, false),
+            );
+        }
+    }
+
+    // Viewport, wrapping around the 512x480 plane
+    let nametable = (ppu.ctrl.nametable_addr() - 0x2000) / 0x400;
+    let x0 = (nametable as usize % 2) * 256 + ppu.scroll.scroll_x as usize;
+    let y0 = (nametable as usize / 2) * 240 + ppu.scroll.scroll_y as usize;
+    for i in 0..256 {
+        frame.set_pixel((x0 + i) % 512, y0 % 480, VIEWPORT_COLOR);
+        frame.set_pixel((x0 + i) % 512, (y0 + 239) % 480, VIEWPORT_COLOR);
+    }
+    for i in 0..240 {
+        frame.set_pixel(x0 % 512, (y0 + i) % 480, VIEWPORT_COLOR);
+        frame.set_pixel((x0 + 255) % 512, (y0 + i) % 480, VIEWPORT_COLOR);
+    }
+    frame
+}
+
+// The 64 OAM entries as a grid of sprite previews, 8 per row
+pub fn oam(ppu: &NesPPU, system_palette: &Palette) -> Frame {
+    let rows = 64 / OAM_COLUMNS;
+    let mut frame = Frame::with_size(OAM_COLUMNS * OAM_CELL_WIDTH, rows * OAM_CELL_HEIGHT);
+    for pixel in frame.data.chunks_mut(3) {
+        pixel.copy_from_slice(&[BORDER_COLOR.0, BORDER_COLOR.1, BORDER_COLOR.2]);
+    }
+
+    let tall = ppu.ctrl.sprite_size() == 16;
+    for (i, sprite) in ppu.oam.chunks(4).enumerate() {
+        let (tile, attr) = (sprite[1] as usize, sprite[2]);
+        let colors = palette_colors(ppu, 4 + (attr & 0b11));
+        let flip = (attr & 0x40 != 0, attr & 0x80 != 0);
+        let left = i % OAM_COLUMNS * OAM_CELL_WIDTH + 2;
+        let top = i / OAM_COLUMNS * OAM_CELL_HEIGHT + 2;
+
+        // 8x16 sprites take the bank from bit 0 of the tile number
+        let tiles = if tall {
+            let addr = (tile & 1) * 0x1000 + (tile & 0xfe) * TILE_SIZE;
+            let (first, second) = (addr, addr + TILE_SIZE);
+            if flip.1 {
+                [Some(second), Some(first)]
+            } else {
+                [Some(first), Some(second)]
+            }
+        } else {
+            let bank = ppu.ctrl.sprt_pattern_addr() as usize;
+            [Some(bank + tile * TILE_SIZE), None]
+        };
+        for (half, addr) in tiles.iter().enumerate() {
+            if let Some(addr) = addr {
+                draw_tile(
+                    &mut frame,
+                    ppu,
+                    system_palette,
+                    *addr,
+                    (left, top + half * 8),
+                    colors,
+                    flip,
+                );
+            }
+        }
+    }
+    frame
+}
+
+// One line per OAM entry, to go with the previews
+pub fn oam_list(ppu: &NesPPU) -> String {
+    let mut out = String::from(" #   X   Y  TILE ATTR  PAL FLIP\n");
+    for (i, sprite) in ppu.oam.chunks(4).enumerate() {
+        let attr = sprite[2];
+        let flip = match (attr & 0x40 != 0, attr & 0x80 != 0) {
+            (false, false) => "",
+            (true, false) => "H",
+            (false, true) => "V",
+            (true, true) => "HV",
+        };
+        out += &format!(
+            "{:2} {:3} {:3}   {:02X}   {:02X}  {:3} {}\n",
+            i,
+            sprite[3],
+            sprite[0],
+            sprite[1],
+            attr,
+            attr & 0b11,
+            flip
+        );
+    }
+    out
+}
+
+// The 32 palette RAM entries, background palettes on top and sprites below
+pub fn palette_ram(ppu: &NesPPU, system_palette: &Palette) -> Frame {
+    let mut frame = Frame::with_size(16 * SWATCH_SIZE, 2 * SWATCH_SIZE);
+    for (i, color) in ppu.palette_table.iter().enumerate() {
+        let (left, top) = (i % 16 * SWATCH_SIZE, i / 16 * SWATCH_SIZE);
+        for y in 0..SWATCH_SIZE {
+            for x in 0..SWATCH_SIZE {
+                frame.set_pixel(left + x, top + y, rgb(system_palette, *color));
+            }
+        }
+    }
+    frame
+}
+
+// Write every view to `dir` as PNG, plus the OAM table as text
+pub fn save_all(
+    ppu: &NesPPU,
+    system_palette: &Palette,
+    dir: &Path,
+    palette_idx: u8,
+) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    pattern_tables(ppu, system_palette, palette_idx).save_png(&dir.join("pattern_tables.png"))?;
+    nametables(ppu, system_palette).save_png(&dir.join("nametables.png"))?;
+    oam(ppu, system_palette).save_png(&dir.join("oam.png"))?;
+    palette_ram(ppu, system_palette).save_png(&dir.join("palette.png"))?;
+
+    let path = dir.join("oam.txt");
+    std::fs::write(&path, oam_list(ppu)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::render::palette::SYSTEM_PALETE;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * frame.width + x) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    fn test_ppu() -> NesPPU {
+        let mut chr = vec![0; 0x2000];
+        // tile 1: top row color 1, everything else color 0
+        chr[TILE_SIZE] = 0xff;
+        // tile $101: top left pixel color 3
+        chr[0x1000 + TILE_SIZE] = 0x80;
+        chr[0x1000 + TILE_SIZE + 8] = 0x80;
+
+        let mut ppu = NesPPU::new(chr, Mirroring::Vertical);
+        for (i, color) in ppu.palette_table.iter_mut().enumerate() {
+            *color = i as u8;
+        }
+        ppu
+    }
+
+    #[test]
+    fn test_pattern_tables() {
+        let ppu = test_ppu();
+        let frame = pattern_tables(&ppu, &SYSTEM_PALETE, 1);
+        assert_eq!((frame.width, frame.height), (256, 128));
+        assert_eq!(pixel(&frame, 8, 0), rgb(&SYSTEM_PALETE, 5));
+        assert_eq!(pixel(&frame, 8, 1), rgb(&SYSTEM_PALETE, 0));
+        assert_eq!(pixel(&frame, 128 + 8, 0), rgb(&SYSTEM_PALETE, 7));
+    }
+
+    #[test]
+    fn test_nametables_mirroring_and_viewport() {
+        let mut ppu = test_ppu();
+        ppu.vram[0] = 1;
+        ppu.scroll.scroll_x = 16;
+        let frame = nametables(&ppu, &SYSTEM_PALETE);
+
+        // vertical mirroring: the table at $2800 shows $2000
+        assert_eq!(pixel(&frame, 1, 0), rgb(&SYSTEM_PALETE, 1));
+        assert_eq!(pixel(&frame, 1, 240), rgb(&SYSTEM_PALETE, 1));
+        assert_eq!(pixel(&frame, 256 + 1, 1), rgb(&SYSTEM_PALETE, 0));
+        assert_eq!(pixel(&frame, 16, 100), VIEWPORT_COLOR);
+        assert_eq!(pixel(&frame, 16 + 255, 100), VIEWPORT_COLOR);
+    }
+
+    #[test]
+    fn test_oam_and_palette() {
+        let mut ppu = test_ppu();
+        ppu.ctrl.update(0b0000_1000);
+        ppu.oam[4..8].copy_from_slice(&[0x20, 0x01, 0x41, 0x30]);
+        let frame = oam(&ppu, &SYSTEM_PALETE);
+
+        // sprite 1, palette 5, flipped horizontally: pixel lands on the right
+        assert_eq!(
+            pixel(&frame, OAM_CELL_WIDTH + 2 + 7, 2),
+            rgb(&SYSTEM_PALETE, 0x17)
+        );
+        assert!(oam_list(&ppu).contains(" 1  48  32   01   41    1 H\n"));
+
+        let frame = palette_ram(&ppu, &SYSTEM_PALETE);
+        assert_eq!(
+            pixel(&frame, SWATCH_SIZE * 3, SWATCH_SIZE),
+            rgb(&SYSTEM_PALETE, 0x13)
+        );
+    }
+}
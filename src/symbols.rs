@@ -0,0 +1,310 @@
+use crate::disasm::Labels;
+use std::collections::HashMap;
+use std::path::Path;
+
+const BANK_SIZE: usize = 0x4000;
+const INES_HEADER_SIZE: usize = 16;
+
+// Where a symbol lives. Code and data in PRG ROM is keyed by ROM offset so the
+// name follows the bank wherever a mapper puts it, everything else by address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    Cpu(u16),
+    Prg(usize),
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: HashMap<Location, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    pub fn insert(&mut self, location: Location, name: &str) {
+        if !name.is_empty() {
+            self.symbols.insert(location, name.to_string());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn get(&self, location: Location) -> Option<&str> {
+        self.symbols.get(&location).map(String::as_str)
+    }
+
+    pub fn find(&self, name: &str) -> Option<Location> {
+        self.symbols
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(location, _)| *location)
+    }
+
+    // Load a symbol file, picking the format from the extension:
+    // ld65 `.dbg`, FCEUX `.nl`, Mesen `.mlb`, anything else as a VICE label file
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => self.parse_dbg(&text),
+            Some("nl") => {
+                // FCEUX names them game.nes.ram.nl and game.nes.<bank>.nl
+                let bank = file_name
+                    .trim_end_matches(".nl")
+                    .rsplit_once('.')
+                    .filter(|(rom, _)| rom.contains('.'))
+                    .and_then(|(_, bank)| usize::from_str_radix(bank, 16).ok());
+                self.parse_nl(&text, bank);
+                Ok(())
+            }
+            Some("mlb") => self.parse_mlb(&text),
+            _ => {
+                self.parse_vice(&text);
+                Ok(())
+            }
+        }
+    }
+
+    // ld65 debug info. Symbols are resolved to ROM offsets through the segment
+    // they are defined in, using the segment's offset in the output file.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), String> {
+        let mut segments: HashMap<String, (u32, Option<usize>)> = HashMap::new();
+        let mut syms = vec![];
+
+        for line in text.lines() {
+            let (kind, fields) = match line.split_once(|c: char| c.is_whitespace()) {
+                Some(split) => split,
+                None => continue,
+            };
+            let fields = parse_dbg_fields(fields);
+            match kind {
+                "seg" => {
+                    let id = fields.get("id").cloned().unwrap_or_default();
+                    let start = parse_number(fields.get("start").map(String::as_str))
+                        .ok_or_else(|| format!("Invalid segment: {}", line))?;
+                    let rom_offset = match (fields.get("type"), fields.get("ooffs")) {
+                        (Some(t), Some(ooffs)) if t == "ro" => parse_number(Some(ooffs))
+                            .and_then(|o| (o as usize).checked_sub(INES_HEADER_SIZE)),
+                        _ => None,
+                    };
+                    segments.insert(id, (start, rom_offset));
+                }
+                "sym" => syms.push(fields),
+                _ => {}
+            }
+        }
+
+        for fields in syms {
+            if fields.get("type").map(String::as_str) == Some("imp") {
+                continue;
+            }
+            let name = match fields.get("name") {
+                Some(name) => name,
+                None => continue,
+            };
+            let value = match parse_number(fields.get("val").map(String::as_str)) {
+                Some(value) if value <= 0xFFFF => value,
+                _ => continue,
+            };
+
+            let segment = fields.get("seg").and_then(|seg| segments.get(seg));
+            let location = match segment {
+                Some((start, Some(rom_offset))) if value >= 0x8000 && value >= *start => {
+                    Location::Prg(rom_offset + (value - start) as usize)
+                }
+                _ => Location::Cpu(value as u16),
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+
+    // FCEUX name list: `$C000#reset#comment`, one file per 16 KiB bank
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) {
+        for line in text.lines() {
+            let mut parts = line.splitn(3, '#');
+            let (addr, name) = match (parts.next(), parts.next()) {
+                (Some(addr), Some(name)) => (addr, name),
+                _ => continue,
+            };
+            // arrays are written as $0200/10
+            let addr = addr.trim().trim_start_matches('$').split('/').next();
+            let addr = match addr.and_then(|a| u16::from_str_radix(a, 16).ok()) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    Location::Prg(bank * BANK_SIZE + (addr as usize % BANK_SIZE))
+                }
+                _ => Location::Cpu(addr),
+            };
+            self.insert(location, name.trim());
+        }
+    }
+
+    // Mesen label file: `type:address[-end]:label[:comment]`, with the
+    // single letter types of Mesen and the long ones of Mesen 2
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines() {
+            let parts: Vec<&str> = line.trim().splitn(4, ':').collect();
+            if parts.len() < 3 {
+                continue;
+            }
+            let offset = parts[1].split('-').next().unwrap_or("");
+            let offset = usize::from_str_radix(offset, 16)
+                .map_err(|_| format!("Invalid address in label file: {}", line))?;
+
+            let location = match parts[0] {
+                "P" | "NesPrgRom" => Location::Prg(offset),
+                "R" | "G" | "NesInternalRam" | "NesMemory" => Location::Cpu(offset as u16),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => Location::Cpu(0x6000 + offset as u16),
+                _ => continue,
+            };
+            self.insert(location, parts[2]);
+        }
+        Ok(())
+    }
+
+    // VICE label file as written by `ld65 -Ln`: `al 00C000 .reset`
+    pub fn parse_vice(&mut self, text: &str) {
+        for line in text.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if let ["al", addr, name] = parts.as_slice() {
+                if let Ok(addr) = u32::from_str_radix(addr, 16) {
+                    self.insert(Location::Cpu(addr as u16), name.trim_start_matches('.'));
+                }
+            }
+        }
+    }
+
+    // Labels by CPU address for a PRG window of `size` bytes at ROM offset
+    // `prg_offset` mapped to `base`. CPU symbols always apply.
+    pub fn bank_labels(&self, prg_offset: usize, base: u16, size: usize) -> Labels {
+        let mut labels = Labels::new();
+        for (location, name) in &self.symbols {
+            match *location {
+                Location::Cpu(addr) => {
+                    labels.entry(addr).or_insert_with(|| name.clone());
+                }
+                Location::Prg(offset) if offset >= prg_offset && offset < prg_offset + size => {
+                    labels.insert(base + (offset - prg_offset) as u16, name.clone());
+                }
+                Location::Prg(_) => {}
+            }
+        }
+        labels
+    }
+
+    // Labels for the whole CPU address space as the bus maps PRG ROM:
+    // 32 KiB at $8000, or 16 KiB mirrored at $8000 and $C000
+    pub fn labels(&self, prg_len: usize) -> Labels {
+        if prg_len <= BANK_SIZE {
+            let mut labels = self.bank_labels(0, 0x8000, prg_len);
+            labels.extend(self.bank_labels(0, 0xC000, prg_len));
+            labels
+        } else {
+            self.bank_labels(0, 0x8000, prg_len.min(2 * BANK_SIZE))
+        }
+    }
+}
+
+// Split `id=0,name="reset",val=0xC000` into fields, honouring quotes
+fn parse_dbg_fields(text: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut in_quotes = false;
+    let mut field = String::new();
+    for c in text.trim().chars().chain([',']) {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                if let Some((key, value)) = field.split_once('=') {
+                    fields.insert(key.to_string(), value.to_string());
+                }
+                field.clear();
+            }
+            _ => field.push(c),
+        }
+    }
+    fields
+}
+
+fn parse_number(value: Option<&str>) -> Option<u32> {
+    let value = value?;
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_dbg() {
+        let dbg = r#"version	major=2,minor=0
+seg	id=0,name="ZEROPAGE",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+seg	id=1,name="CODE",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+sym	id=0,name="counter",addrsize=zeropage,size=1,scope=0,def=1,ref=4,val=0x10,seg=0,type=lab
+sym	id=1,name="reset",addrsize=absolute,scope=0,def=2,val=0xC004,seg=1,type=lab
+sym	id=2,name="PPUCTRL",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ
+sym	id=3,name="extern",addrsize=absolute,scope=0,def=3,type=imp
+"#;
+        let mut symbols = SymbolTable::new();
+        symbols.parse_dbg(dbg).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.get(Location::Cpu(0x10)), Some("counter"));
+        assert_eq!(symbols.get(Location::Prg(0x0004)), Some("reset"));
+        assert_eq!(symbols.get(Location::Cpu(0x2000)), Some("PPUCTRL"));
+        assert_eq!(symbols.find("reset"), Some(Location::Prg(4)));
+    }
+
+    #[test]
+    fn test_parse_nl_and_mlb() {
+        let mut symbols = SymbolTable::new();
+        symbols.parse_nl("$0200/10#oam#sprite buffer\n", None);
+        symbols.parse_nl("$C010#nmi#\n$8000#bank_start#\n", Some(2));
+        assert_eq!(symbols.get(Location::Cpu(0x0200)), Some("oam"));
+        assert_eq!(symbols.get(Location::Prg(0x8010)), Some("nmi"));
+        assert_eq!(symbols.get(Location::Prg(0x8000)), Some("bank_start"));
+
+        let mut symbols = SymbolTable::new();
+        symbols
+            .parse_mlb("P:0004:reset:entry point\nR:0010-0011:ptr\nG:2002:PPUSTATUS\nS:0000:save\nP:0010::just a comment\n")
+            .unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.get(Location::Prg(0x0004)), Some("reset"));
+        assert_eq!(symbols.get(Location::Cpu(0x0010)), Some("ptr"));
+        assert_eq!(symbols.get(Location::Cpu(0x6000)), Some("save"));
+        assert!(symbols.parse_mlb("P:zz:bad").is_err());
+    }
+
+    #[test]
+    fn test_labels_follow_prg_mapping() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Location::Prg(0x0004), "reset");
+        symbols.insert(Location::Prg(0x4004), "bank1");
+        symbols.insert(Location::Cpu(0x0010), "counter");
+
+        let labels = symbols.labels(0x4000);
+        assert_eq!(labels.get(&0x8004).map(String::as_str), Some("reset"));
+        assert_eq!(labels.get(&0xC004).map(String::as_str), Some("reset"));
+        assert_eq!(labels.get(&0x0010).map(String::as_str), Some("counter"));
+        assert!(!labels.values().any(|name| name == "bank1"));
+
+        let labels = symbols.bank_labels(0x4000, 0xC000, 0x4000);
+        assert_eq!(labels.get(&0xC004).map(String::as_str), Some("bank1"));
+    }
+}
@@ -0,0 +1,256 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use std::path::Path;
+
+// Test ROMs (blargg, kevtris) report through PRG RAM:
+// $6000      status: $80 running, $81 reset requested, $00-$7F final result code
+// $6001-6003 signature DE B0 61, written once the status byte is valid
+// $6004-     zero terminated ASCII message
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE_ADDR: u16 = 0x6004;
+const MESSAGE_MAX_LEN: u16 = 0x1000;
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+// Test ROMs ask for at least 100ms between the reset request and the reset
+const RESET_DELAY_FRAMES: usize = 6;
+
+pub const DEFAULT_TIMEOUT_FRAMES: usize = 60 * 60;
+
+#[derive(Debug, PartialEq)]
+pub enum TestStatus {
+    Passed,
+    Failed(u8),
+    Timeout,
+    Error(String),
+}
+
+pub struct TestResult {
+    pub name: String,
+    pub status: TestStatus,
+    pub message: String,
+}
+
+fn signature_valid(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.bus.peek(SIGNATURE_ADDR + i) == SIGNATURE[i as usize])
+}
+
+fn read_message(cpu: &CPU) -> String {
+    let mut message = String::new();
+    for offset in 0..MESSAGE_MAX_LEN {
+        let byte = cpu.bus.peek(MESSAGE_ADDR + offset);
+        if byte == 0 {
+            break;
+        }
+        message.push(byte as char);
+    }
+    message.trim().to_string()
+}
+
+pub fn run_test_rom(name: &str, raw: &Vec<u8>, timeout_frames: usize) -> TestResult {
+    // Disk images run from RAM under the BIOS, which isn't loaded here
+    let rom = match Rom::new(raw) {
+        Ok(rom) if rom.disk.is_some() => Err("FDS images aren't supported".to_string()),
+        rom => rom,
+    };
+    let rom = match rom {
+        Ok(rom) => rom,
+        Err(err) => {
+            return TestResult {
+                name: name.to_string(),
+                status: TestStatus::Error(err),
+                message: String::new(),
+            }
+        }
+    };
+
+    let cycles_per_frame = rom.region.cpu_cycles_per_frame();
+    let bus = Bus::new(rom, |_, _| {});
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+
+    let timeout_cycles = timeout_frames * cycles_per_frame;
+    let mut reset_at: Option<usize> = None;
+
+    let status = loop {
+        if !cpu.step() {
+            break TestStatus::Error(format!("BRK at {:04X}", cpu.program_counter - 1));
+        }
+
+        let cycles = cpu.bus.cycles();
+        if cycles >= timeout_cycles {
+            break TestStatus::Timeout;
+        }
+
+        if !signature_valid(&cpu) {
+            continue;
+        }
+
+        match cpu.bus.peek(STATUS_ADDR) {
+            STATUS_RUNNING => {}
+            STATUS_RESET => match reset_at {
+                None => reset_at = Some(cycles + RESET_DELAY_FRAMES * cycles_per_frame),
+                Some(at) if cycles >= at => {
+                    reset_at = None;
+                    cpu.reset();
+                }
+                Some(_) => {}
+            },
+            0 => break TestStatus::Passed,
+            code if code < STATUS_RUNNING => break TestStatus::Failed(code),
+            _ => {}
+        }
+    };
+
+    TestResult {
+        name: name.to_string(),
+        status,
+        message: read_message(&cpu),
+    }
+}
+
+pub fn run_directory(dir: &Path, timeout_frames: usize) -> Result<Vec<TestResult>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "nes"))
+        .collect();
+    paths.sort();
+
+    let mut results = vec![];
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let raw = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        results.push(run_test_rom(&name, &raw, timeout_frames));
+    }
+    Ok(results)
+}
+
+pub fn format_table(results: &[TestResult]) -> String {
+    let width = results
+        .iter()
+        .map(|r| r.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+    let mut table = format!(
+        "{:width$}  {:8}  {}\n",
+        "ROM",
+        "RESULT",
+        "MESSAGE",
+        width = width
+    );
+
+    for result in results {
+        let status = match &result.status {
+            TestStatus::Passed => "passed".to_string(),
+            TestStatus::Failed(code) => format!("failed #{}", code),
+            TestStatus::Timeout => "timeout".to_string(),
+            TestStatus::Error(err) => format!("error: {}", err),
+        };
+        let message = result.message.replace('\n', " ");
+        table += &format!(
+            "{:width$}  {:8}  {}\n",
+            result.name,
+            status,
+            message,
+            width = width
+        );
+    }
+
+    let passed = results
+        .iter()
+        .filter(|r| r.status == TestStatus::Passed)
+        .count();
+    table += &format!("{}/{} passed\n", passed, results.len());
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // NROM image with `program` at $8000 and the reset vector pointing to it
+    fn build_rom(program: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+        raw.extend([0; 8]);
+
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0x80;
+        raw.extend(prg);
+        raw.extend(vec![0; 0x2000]);
+        raw
+    }
+
+    // LDA #value; STA addr
+    fn store(program: &mut Vec<u8>, addr: u16, value: u8) {
+        let [lo, hi] = addr.to_le_bytes();
+        program.extend([0xA9, value, 0x8D, lo, hi]);
+    }
+
+    fn protocol_rom(result: u8, message: &str) -> Vec<u8> {
+        let mut program = vec![];
+        store(&mut program, STATUS_ADDR, STATUS_RUNNING);
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            store(&mut program, SIGNATURE_ADDR + i as u16, *byte);
+        }
+        for (i, byte) in message.bytes().chain([0]).enumerate() {
+            store(&mut program, MESSAGE_ADDR + i as u16, byte);
+        }
+        store(&mut program, STATUS_ADDR, result);
+
+        // JMP to self
+        let [lo, hi] = (0x8000 + program.len() as u16).to_le_bytes();
+        program.extend([0x4C, lo, hi]);
+        build_rom(&program)
+    }
+
+    #[test]
+    fn test_status_protocol_pass() {
+        let result = run_test_rom("pass.nes", &protocol_rom(0, "Passed\n"), 10);
+        assert_eq!(result.status, TestStatus::Passed);
+        assert_eq!(result.message, "Passed");
+    }
+
+    #[test]
+    fn test_status_protocol_fail() {
+        let result = run_test_rom("fail.nes", &protocol_rom(3, "Failed #3"), 10);
+        assert_eq!(result.status, TestStatus::Failed(3));
+        assert_eq!(result.message, "Failed #3");
+    }
+
+    #[test]
+    fn test_status_protocol_timeout() {
+        let result = run_test_rom("hang.nes", &build_rom(&[0x4C, 0x00, 0x80]), 2);
+        assert_eq!(result.status, TestStatus::Timeout);
+    }
+
+    // Runs every test ROM vendored under roms/tests
+    #[test]
+    fn test_fds_image_is_an_error() {
+        let result = run_test_rom("disk.fds", &crate::fds::test::image(), 10);
+        assert_eq!(
+            result.status,
+            TestStatus::Error("FDS images aren't supported".to_string())
+        );
+    }
+
+    #[test]
+    fn test_vendored_test_roms() {
+        let results = run_directory(Path::new("roms/tests"), DEFAULT_TIMEOUT_FRAMES).unwrap();
+        assert!(!results.is_empty(), "no test ROMs in roms/tests");
+        let table = format_table(&results);
+        assert!(
+            results.iter().all(|r| r.status == TestStatus::Passed),
+            "\n{}",
+            table
+        );
+    }
+}
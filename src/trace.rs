@@ -1,13 +1,147 @@
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
+use crate::cpu::CpuFlags;
 use crate::cpu::CPU;
+use crate::disasm::{Instruction, Labels};
 use crate::opcodes;
 use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Nestest,
+    Mesen,
+    Fceux,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nestest" => Ok(TraceFormat::Nestest),
+            "mesen" => Ok(TraceFormat::Mesen),
+            "fceux" => Ok(TraceFormat::Fceux),
+            _ => Err(format!("Unknown trace format: {}", s)),
+        }
+    }
+}
+
+// Instruction at the current PC, decoded for tracing
+struct Disassembly {
+    addr: u16,
+    hex: String,
+    asm: String,
+    // Address the operand names, for symbol lookup
+    target: Option<u16>,
+}
+
+impl Disassembly {
+    // Replace the operand address with its symbol name, e.g. `JMP $C5F5` -> `JMP main`
+    fn symbolize(&self, asm: &str, labels: &Labels) -> String {
+        let name = match self.target.and_then(|target| labels.get(&target)) {
+            Some(name) => name,
+            None => return asm.to_string(),
+        };
+        let start = match asm.find('$') {
+            Some(start) => start,
+            None => return asm.to_string(),
+        };
+        let digits = asm[start + 1..]
+            .chars()
+            .take_while(|c| c.is_ascii_hexdigit())
+            .count();
+        format!("{}{}{}", &asm[..start], name, &asm[start + 1 + digits..])
+    }
+}
 
 pub fn trace(cpu: &mut CPU) -> String {
+    trace_with_format(cpu, TraceFormat::Nestest)
+}
+
+pub fn trace_with_format(cpu: &mut CPU, format: TraceFormat) -> String {
+    trace_with_labels(cpu, format, &Labels::new())
+}
+
+pub fn trace_with_labels(cpu: &mut CPU, format: TraceFormat, labels: &Labels) -> String {
+    let dis = disassemble(cpu);
+    let scanline = cpu.bus.ppu().scanline();
+    let dot = cpu.bus.ppu().dot();
+    let cycles = cpu.bus.cycles();
+
+    match format {
+        TraceFormat::Nestest => {
+            let asm_str = format!(
+                "{:04X}  {:8} {}",
+                dis.addr,
+                dis.hex.to_ascii_uppercase(),
+                dis.symbolize(&dis.asm.to_ascii_uppercase(), labels)
+            )
+            .trim()
+            .to_string();
+
+            format!(
+                "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                asm_str,
+                cpu.register_a,
+                cpu.register_x,
+                cpu.register_y,
+                cpu.status,
+                cpu.stack_pointer,
+                scanline,
+                dot,
+                cycles,
+            )
+        }
+        TraceFormat::Mesen => format!(
+            "{:04X}  {:<31} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{} V:{:<3} H:{:<3} Cycle:{}",
+            dis.addr,
+            dis.symbolize(&dis.asm.trim().to_ascii_uppercase(), labels),
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.stack_pointer,
+            flags_str(&cpu.status),
+            scanline,
+            dot,
+            cycles,
+        ),
+        TraceFormat::Fceux => format!(
+            "c{:<11} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ${:04X}:{:<9} {}",
+            cycles,
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.stack_pointer,
+            flags_str(&cpu.status),
+            dis.addr,
+            dis.hex.to_ascii_uppercase(),
+            dis.symbolize(&dis.asm.trim().to_ascii_uppercase(), labels),
+        ),
+    }
+}
+
+// Status flags as letters, uppercase when set (e.g. nvUbdIzc)
+fn flags_str(status: &CpuFlags) -> String {
+    let names = ['c', 'z', 'i', 'd', 'b', 'u', 'v', 'n'];
+    (0..8)
+        .rev()
+        .map(|bit| {
+            if status.bits() & (1 << bit) != 0 {
+                names[bit].to_ascii_uppercase()
+            } else {
+                names[bit]
+            }
+        })
+        .collect()
+}
+
+// Reads go through `peek` so tracing doesn't fire watchpoints, log CDL data,
+// apply cheats or clock read-sensitive registers
+fn disassemble(cpu: &CPU) -> Disassembly {
     let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
 
-    let code = cpu.mem_read(cpu.program_counter);
+    let code = cpu.bus.peek(cpu.program_counter);
     let ops = opscodes.get(&code).unwrap();
 
     let begin = cpu.program_counter;
@@ -17,8 +151,8 @@ pub fn trace(cpu: &mut CPU) -> String {
     let (mem_addr, stored_value) = match ops.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
-            let (addr, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            let addr = cpu.peek_absolute_address(&ops.mode, begin + 1);
+            (addr, cpu.bus.peek(addr))
         }
     };
 
@@ -28,8 +162,8 @@ pub fn trace(cpu: &mut CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
+            let address: u8 = cpu.bus.peek(begin + 1);
+            // let value = cpu.bus.peek(address));
             hex_dump.push(address);
 
             match ops.mode {
@@ -71,23 +205,26 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.bus.peek(begin + 1);
+            let address_hi = cpu.bus.peek(begin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
+            let address = u16::from_le_bytes([address_lo, address_hi]);
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.bus.peek(address);
+                            let hi = cpu.bus.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            u16::from_le_bytes([
+                                cpu.bus.peek(address),
+                                cpu.bus.peek(address.wrapping_add(1)),
+                            ])
                         };
 
                         // let jmp_addr = cpu.mem_read_u16(address);
@@ -119,26 +256,90 @@ pub fn trace(cpu: &mut CPU) -> String {
         .map(|z| format!("{:02x}", z))
         .collect::<Vec<String>>()
         .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.name, tmp)
-        .trim()
-        .to_string();
-
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
-    )
-    .to_ascii_uppercase()
+    Disassembly {
+        addr: begin,
+        hex: hex_str,
+        asm: format!("{: >4} {}", ops.name, tmp),
+        target: Instruction::decode(&hex_dump, begin).and_then(|ins| ins.referenced_addr()),
+    }
+}
+
+pub struct TraceWriter<W: Write> {
+    out: W,
+    format: TraceFormat,
+    start: Option<u16>,
+    stop: Option<u16>,
+    active: bool,
+    labels: Labels,
+    // First write error, after which tracing stops
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        TraceWriter {
+            out,
+            format,
+            start: None,
+            stop: None,
+            active: true,
+            labels: Labels::new(),
+            error: None,
+        }
+    }
+
+    // Only log from the first time PC hits `start` until PC hits `stop`
+    pub fn with_range(mut self, start: Option<u16>, stop: Option<u16>) -> Self {
+        self.start = start;
+        self.stop = stop;
+        self.active = start.is_none();
+        self
+    }
+
+    // Show symbol names for operands, and PC's own name in a trailing
+    // `; name` column so lines still diff against other emulators' logs
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn log(&mut self, cpu: &mut CPU) {
+        let pc = cpu.program_counter;
+        if !self.active && Some(pc) == self.start {
+            self.active = true;
+        }
+        if !self.active || self.error.is_some() {
+            return;
+        }
+
+        let mut line = trace_with_labels(cpu, self.format, &self.labels);
+        if let Some(name) = self.labels.get(&pc) {
+            line += &format!("  ; {}", name);
+        }
+        let mut result = writeln!(self.out, "{}", line);
+        if Some(pc) == self.stop {
+            self.active = false;
+            result = result.and_then(|_| self.out.flush());
+        }
+        // A full disk or closed pipe ends the trace, not the emulator
+        if let Err(err) = result {
+            eprintln!("Trace stopped: {}", err);
+            self.error = Some(err);
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::bus::Bus;
+    use crate::bus::{Access, Bus};
     use crate::cartridge::test::test_rom;
+    use crate::cartridge::Rom;
+    use crate::cpu::Mem;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = Bus::new(test_rom(), |_, _| {});
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -155,22 +356,22 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  6 CYC:2",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 12 CYC:4",
             result[2]
         );
     }
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = Bus::new(test_rom(), |_, _| {});
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -190,8 +391,189 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            result[0]
+        );
+    }
+
+    #[test]
+    fn test_trace_has_no_side_effects() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        // LDA ($33),Y
+        bus.mem_write(100, 0xb1);
+        bus.mem_write(101, 0x33);
+        bus.mem_write(0x34, 0x04);
+        for addr in [100, 101, 0x33, 0x34, 0x400] {
+            bus.add_watchpoint(addr, Access::Read);
+        }
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        trace(&mut cpu);
+        assert_eq!(cpu.bus.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn test_format_mesen_and_fceux() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.mem_write(100, 0xa2);
+        bus.mem_write(101, 0x01);
+        bus.mem_write(102, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace_with_format(cpu, TraceFormat::Mesen));
+            result.push(trace_with_format(cpu, TraceFormat::Fceux));
+        });
+        assert_eq!(
+            "0064  LDX #$01                        A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:0   Cycle:0",
             result[0]
         );
+        assert_eq!(
+            "c0           A:00 X:00 Y:00 S:FD P:nvUbdIzc  $0064:A2 01     LDX #$01",
+            result[1]
+        );
+    }
+
+    #[test]
+    fn test_trace_writer_range() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.mem_write(100, 0xe8);
+        bus.mem_write(101, 0xe8);
+        bus.mem_write(102, 0xe8);
+        bus.mem_write(103, 0xe8);
+        bus.mem_write(104, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        let mut writer =
+            TraceWriter::new(Vec::new(), TraceFormat::Nestest).with_range(Some(0x65), Some(0x66));
+        cpu.run_with_callback(|cpu| writer.log(cpu));
+
+        let out = String::from_utf8(writer.out).unwrap();
+        let pcs: Vec<&str> = out.lines().map(|l| &l[..4]).collect();
+        assert_eq!(pcs, vec!["0065", "0066"]);
+    }
+
+    #[test]
+    fn test_trace_writer_labels() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        // JSR $0010; BRK; ... $0010: LDA $02
+        bus.mem_write(0x00, 0x20);
+        bus.mem_write(0x01, 0x10);
+        bus.mem_write(0x02, 0x00);
+        bus.mem_write(0x03, 0x00);
+        bus.mem_write(0x10, 0xa5);
+        bus.mem_write(0x11, 0x02);
+        bus.mem_write(0x12, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x00;
+        let labels = Labels::from([(0x10, "Init".to_string()), (0x02, "temp".to_string())]);
+        let mut writer = TraceWriter::new(Vec::new(), TraceFormat::Nestest).with_labels(labels);
+        cpu.run_with_callback(|cpu| writer.log(cpu));
+
+        let out = String::from_utf8(writer.out).unwrap();
+        let lines: Vec<(&str, Option<&str>)> = out
+            .lines()
+            .map(|l| {
+                (
+                    l.get(..47).unwrap_or(l).trim_end(),
+                    l.split_once("  ; ").map(|c| c.1),
+                )
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ("0000  20 10 00  JSR Init", None),
+                ("0010  A5 02     LDA temp = 00", Some("Init")),
+                ("0012  00        BRK", None),
+            ]
+        );
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("no space left"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_writer_keeps_write_errors() {
+        let mut cpu = CPU::new(Bus::new(test_rom(), |_, _| {}));
+        let mut writer = TraceWriter::new(FullDisk, TraceFormat::Nestest);
+        writer.log(&mut cpu);
+        writer.log(&mut cpu);
+        assert_eq!(writer.error.unwrap().to_string(), "no space left");
+    }
+
+    // The checked-in log predates PPU/CYC tracking, so timing columns are only
+    // compared against a reference that has them, such as Nintendulator's log.
+    fn strip_timing_columns<'a>(line: &'a str, reference: &str) -> &'a str {
+        if reference.contains(" PPU:") {
+            return line;
+        }
+        match line.find(" PPU:") {
+            Some(idx) => &line[..idx],
+            None => line,
+        }
+    }
+
+    fn is_trace_line(line: &str) -> bool {
+        line.len() > 6 && line[..4].chars().all(|c| c.is_ascii_hexdigit()) && &line[4..6] == "  "
+    }
+
+    #[test]
+    fn test_nestest_conformance() {
+        let bytes = std::fs::read("roms/nestest.nes").unwrap();
+        let reference = std::fs::read_to_string("logs/nestest.log").unwrap();
+        let expected: Vec<&str> = reference.lines().filter(|l| is_trace_line(l)).collect();
+
+        let bus = Bus::new(Rom::new(&bytes).unwrap(), |_, _| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0xC000;
+
+        // Stop where the log does, the program keeps running past its last
+        // line into code the log doesn't cover
+        let mut result: Vec<String> = vec![];
+        while result.len() < expected.len() {
+            result.push(trace(&mut cpu));
+            if !cpu.step() {
+                break;
+            }
+        }
+
+        for (idx, line) in expected.iter().enumerate() {
+            let actual = match result.get(idx) {
+                Some(actual) => strip_timing_columns(actual, line),
+                None => panic!(
+                    "trace ended after {} lines, expected {}",
+                    result.len(),
+                    expected.len()
+                ),
+            };
+
+            if actual != *line {
+                let context = expected[idx.saturating_sub(3)..idx].join("\n");
+                panic!(
+                    "nestest diverged at line {}:\n{}\nexpected: {}\n  actual: {}",
+                    idx + 1,
+                    context,
+                    line,
+                    actual
+                );
+            }
+        }
+        assert_eq!(result.len(), expected.len());
     }
 }
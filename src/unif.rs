@@ -0,0 +1,232 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::game_db;
+use crate::region::Region;
+
+// UNIF: a 32 byte header ("UNIF", revision, padding) followed by chunks of a
+// 4 character ID, a little endian length and the data. The board is named
+// instead of numbered, so it's mapped to the iNES mapper that emulates it.
+
+pub const UNIF_TAG: [u8; 4] = *b"UNIF";
+const HEADER_SIZE: usize = 32;
+
+// Board name (without its NES-/HVC-/UNL-/BMC- prefix) to iNES mapper. Only
+// boards the bus emulates are listed, others are rejected rather than run as
+// the wrong hardware. That's NROM for now, so no UNL- or BMC- multicart
+// board loads yet; add boards here as their mappers get implemented.
+const BOARDS: [(&str, u8); 5] = [
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("RROM", 0),
+    ("RROM-128", 0),
+];
+
+// Common boards whose iNES mapper isn't emulated yet, named in the error so
+// it's clear what's missing. Multicart and pirate boards mostly have no
+// single mapper equivalent and are just reported as unsupported.
+const UNEMULATED_BOARDS: [(&str, u8); 12] = [
+    ("SLROM", 1),
+    ("SNROM", 1),
+    ("SKROM", 1),
+    ("UNROM", 2),
+    ("UOROM", 2),
+    ("CNROM", 3),
+    ("TLROM", 4),
+    ("TKROM", 4),
+    ("TSROM", 4),
+    ("TFROM", 4),
+    ("ANROM", 7),
+    ("AOROM", 7),
+];
+
+const BOARD_PREFIXES: [&str; 5] = ["NES-", "HVC-", "UNL-", "BMC-", "BTL-"];
+
+fn board_mapper(board: &str) -> Result<u8, String> {
+    let name = BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    let find = |boards: &[(&str, u8)]| {
+        boards
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, mapper)| *mapper)
+    };
+    if let Some(mapper) = find(&BOARDS) {
+        return Ok(mapper);
+    }
+    Err(match find(&UNEMULATED_BOARDS) {
+        Some(mapper) => format!(
+            "Unsupported UNIF board: {} (needs mapper {}, which isn't emulated)",
+            board, mapper
+        ),
+        None => format!("Unsupported UNIF board: {}", board),
+    })
+}
+
+// Chunk IDs are PRG0..PRGF and CHR0..CHRF, loaded in that order
+fn rom_chunk(id: &[u8], kind: &[u8; 3]) -> Option<usize> {
+    if &id[..3] != kind {
+        return None;
+    }
+    (id[3] as char).to_digit(16).map(|n| n as usize)
+}
+
+pub fn parse(raw: &[u8]) -> Result<Rom, String> {
+    if raw.len() < HEADER_SIZE || raw[0..4] != UNIF_TAG {
+        return Err("File is not a valid UNIF format".to_string());
+    }
+
+    let mut board = None;
+    let mut prg: [Vec<u8>; 16] = Default::default();
+    let mut chr: [Vec<u8>; 16] = Default::default();
+    let mut mirroring = None;
+    let mut battery = false;
+    let mut region = Region::Ntsc;
+
+    let mut at = HEADER_SIZE;
+    while at < raw.len() {
+        let header = raw
+            .get(at..at + 8)
+            .ok_or("UNIF chunk header is truncated")?;
+        let id = &header[0..4];
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = raw
+            .get(at + 8..at + 8 + len)
+            .ok_or_else(|| format!("UNIF chunk {} is truncated", String::from_utf8_lossy(id)))?;
+        at += 8 + len;
+
+        if let Some(n) = rom_chunk(id, b"PRG") {
+            prg[n] = data.to_vec();
+        } else if let Some(n) = rom_chunk(id, b"CHR") {
+            chr[n] = data.to_vec();
+        } else {
+            match id {
+                // Board name, zero terminated
+                b"MAPR" => {
+                    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+                    board = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+                }
+                b"MIRR" => {
+                    mirroring = Some(match data.first() {
+                        Some(0) => Mirroring::Horizontal,
+                        Some(1) => Mirroring::Vertical,
+                        Some(4) => Mirroring::FourScreen,
+                        // Single screen and mapper controlled, the mapper
+                        // sets it at runtime
+                        _ => Mirroring::Horizontal,
+                    });
+                }
+                b"BATR" => battery = data.first().is_none_or(|b| *b != 0),
+                b"TVCI" if data.first() == Some(&1) => region = Region::Pal,
+                // Name, dumper, CRCs and the rest don't affect emulation
+                _ => {}
+            }
+        }
+    }
+
+    let board = board.ok_or("UNIF file has no MAPR chunk")?;
+    let mapper = board_mapper(&board)?;
+    let prg_rom = prg.concat();
+    let chr_rom = chr.concat();
+    if prg_rom.is_empty() {
+        return Err("UNIF file has no PRG ROM".to_string());
+    }
+    let rom_data = [prg_rom.as_slice(), chr_rom.as_slice()].concat();
+
+    Ok(Rom {
+        prg_rom,
+        chr_rom,
+        mapper,
+        mirroring: mirroring.unwrap_or(Mirroring::Horizontal),
+        expansion_device: 0,
+        region,
+        battery,
+        crc32: game_db::crc32(&rom_data),
+        sha1: game_db::sha1(&rom_data),
+        corrections: vec![],
+        disk: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unif(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut raw = UNIF_TAG.to_vec();
+        raw.extend(7u32.to_le_bytes());
+        raw.resize(HEADER_SIZE, 0);
+        for (id, data) in chunks {
+            raw.extend(*id);
+            raw.extend((data.len() as u32).to_le_bytes());
+            raw.extend(*data);
+        }
+        raw
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = unif(&[
+            (b"MAPR", b"NES-NROM-256\0"),
+            (b"NAME", b"Game\0"),
+            (b"PRG1", &[2; 4]),
+            (b"PRG0", &[1; 4]),
+            (b"CHR0", &[3; 2]),
+            (b"MIRR", &[1]),
+            (b"BATR", &[1]),
+            (b"TVCI", &[1]),
+        ]);
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.prg_rom, [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(rom.chr_rom, [3, 3]);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.region, Region::Pal);
+        assert_eq!(rom.crc32, game_db::crc32(&[1, 1, 1, 1, 2, 2, 2, 2, 3, 3]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let prg: (&[u8; 4], &[u8]) = (b"PRG0", &[0; 4]);
+        assert_eq!(
+            parse(&unif(&[prg])).err().unwrap(),
+            "UNIF file has no MAPR chunk"
+        );
+        assert_eq!(
+            parse(&unif(&[(b"MAPR", b"UNL-Mystery\0"), prg]))
+                .err()
+                .unwrap(),
+            "Unsupported UNIF board: UNL-Mystery"
+        );
+        let mut truncated = unif(&[(b"MAPR", b"NROM\0"), prg]);
+        truncated.pop();
+        assert_eq!(
+            parse(&truncated).err().unwrap(),
+            "UNIF chunk PRG0 is truncated"
+        );
+        assert_eq!(board_mapper("HVC-nrom"), Ok(0));
+        // Known boards whose mappers aren't emulated
+        assert_eq!(
+            board_mapper("NES-TLROM"),
+            Err(
+                "Unsupported UNIF board: NES-TLROM (needs mapper 4, which isn't emulated)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            board_mapper("HVC-snrom"),
+            Err(
+                "Unsupported UNIF board: HVC-snrom (needs mapper 1, which isn't emulated)"
+                    .to_string()
+            )
+        );
+        for board in ["UNL-Sachen-8259A", "BMC-Super24in1SC03"] {
+            assert_eq!(
+                board_mapper(board),
+                Err(format!("Unsupported UNIF board: {}", board))
+            );
+        }
+    }
+}